@@ -0,0 +1,102 @@
+//! Embed the `web/` tree into the binary. For every file the raw bytes are
+//! included together with gzip and brotli variants compressed at build time,
+//! and an ETag derived from the content.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+
+const WEB_DIR: &str = "web";
+
+fn collect_files(dir: &Path, out: &mut Vec<PathBuf>) {
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("read {}: {e}", dir.display()))
+        .map(|e| e.expect("dir entry").path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_files(&path, out);
+        } else {
+            out.push(path);
+        }
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds and toolchains.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn gzip(bytes: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(bytes).expect("gzip");
+    encoder.finish().expect("gzip")
+}
+
+fn brotli(bytes: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+        encoder.write_all(bytes).expect("brotli");
+    }
+    out
+}
+
+/// Write a compressed variant next to the generated code if it is actually smaller.
+fn compressed_variant(out_dir: &Path, name: &str, raw_len: usize, bytes: Vec<u8>) -> String {
+    if bytes.len() >= raw_len {
+        return "None".to_string();
+    }
+    let path = out_dir.join(name);
+    std::fs::write(&path, bytes).expect("write compressed asset");
+    format!("Some(include_bytes!({:?}))", path.display().to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={WEB_DIR}");
+
+    let manifest_dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let web_dir = manifest_dir.join(WEB_DIR);
+
+    let mut files = Vec::new();
+    collect_files(&web_dir, &mut files);
+
+    let mut code = String::from("pub static WEB_ASSETS: &[WebAsset] = &[\n");
+    for (index, path) in files.iter().enumerate() {
+        println!("cargo:rerun-if-changed={}", path.display());
+        let raw = std::fs::read(path).expect("read asset");
+        let rel = path
+            .strip_prefix(&web_dir)
+            .unwrap()
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let gz = compressed_variant(
+            &out_dir,
+            &format!("asset-{index}.gz"),
+            raw.len(),
+            gzip(&raw),
+        );
+        let br = compressed_variant(
+            &out_dir,
+            &format!("asset-{index}.br"),
+            raw.len(),
+            brotli(&raw),
+        );
+        writeln!(
+            code,
+            "    WebAsset {{ path: {rel:?}, etag: \"\\\"{:016x}\\\"\", raw: include_bytes!({:?}), gzip: {gz}, br: {br} }},",
+            fnv1a(&raw),
+            path.display().to_string(),
+        )
+        .unwrap();
+    }
+    code.push_str("];\n");
+
+    std::fs::write(out_dir.join("web_assets.rs"), code).expect("write web_assets.rs");
+}
@@ -0,0 +1,18 @@
+-- Named check-in tracks. Plain /打卡 goes to the default habit.
+CREATE TABLE `bot_habit` (
+    `id` INTEGER NOT NULL PRIMARY KEY,
+    `created_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    `name` TEXT NOT NULL,
+    `is_default` INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE UNIQUE INDEX idx_bot_habit_name ON bot_habit (name);
+CREATE UNIQUE INDEX idx_bot_habit_default ON bot_habit (is_default) WHERE is_default = 1;
+
+-- existing check-ins become the default habit
+INSERT INTO `bot_habit` (`id`, `name`, `is_default`) VALUES (1, '打卡', 1);
+
+ALTER TABLE `bot_daka`
+    ADD COLUMN `habit_id` INTEGER NOT NULL DEFAULT 1;
+
+CREATE INDEX idx_bot_daka_habit ON bot_daka (habit_id, created_at);
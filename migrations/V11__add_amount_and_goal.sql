@@ -0,0 +1,19 @@
+-- Amount of a quantitative check-in such as /打卡 30分钟. Plain check-ins
+-- leave both NULL.
+ALTER TABLE `bot_daka`
+    ADD COLUMN `amount` REAL;
+ALTER TABLE `bot_daka`
+    ADD COLUMN `unit` TEXT;
+
+-- Daily goal of a habit, for the whole group (member_id NULL) or for one
+-- member, who then does not follow the group goal.
+CREATE TABLE `bot_goal` (
+    `id` INTEGER NOT NULL PRIMARY KEY,
+    `created_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    `habit_id` INTEGER NOT NULL,
+    `member_id` INTEGER,
+    `amount` REAL NOT NULL,
+    `unit` TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX idx_bot_goal_member ON bot_goal (habit_id, IFNULL(member_id, 0));
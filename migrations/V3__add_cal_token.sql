@@ -0,0 +1,5 @@
+ALTER TABLE `bot_group_member`
+    ADD COLUMN `cal_token` TEXT NOT NULL DEFAULT '';
+
+CREATE UNIQUE INDEX idx_bot_group_member_cal_token ON bot_group_member (cal_token)
+    WHERE cal_token != '';
@@ -0,0 +1,15 @@
+CREATE TABLE `audit_log` (
+    `id` INTEGER NOT NULL PRIMARY KEY,
+    `created_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    -- `bot_group_member`.`id` of whoever made the change, NULL for the CLI
+    `actor_id` INTEGER,
+    -- bot / web / cli
+    `channel` TEXT NOT NULL,
+    `action` TEXT NOT NULL,
+    `target_id` INTEGER,
+    -- JSON snapshots of the affected values, NULL when not applicable
+    `before` TEXT,
+    `after` TEXT
+);
+
+CREATE INDEX idx_audit_log_created_at ON audit_log (created_at);
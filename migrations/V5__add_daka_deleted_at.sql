@@ -0,0 +1,3 @@
+-- Cancelled check-ins are kept with the time they were cancelled so they can be restored.
+ALTER TABLE `bot_daka`
+    ADD COLUMN `deleted_at` TEXT;
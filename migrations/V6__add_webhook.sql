@@ -0,0 +1,27 @@
+CREATE TABLE `webhook` (
+    `id` INTEGER NOT NULL PRIMARY KEY,
+    `created_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    `url` TEXT NOT NULL,
+    -- key for the HMAC-SHA256 signature of every payload
+    `secret` TEXT NOT NULL,
+    -- comma-separated event types, or `*` for all
+    `events` TEXT NOT NULL DEFAULT '*',
+    `enabled` INTEGER NOT NULL DEFAULT 1
+);
+
+CREATE TABLE `webhook_delivery` (
+    `id` INTEGER NOT NULL PRIMARY KEY,
+    `created_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    `webhook_id` INTEGER NOT NULL REFERENCES `webhook`(`id`) ON DELETE CASCADE,
+    `event` TEXT NOT NULL,
+    `payload` TEXT NOT NULL,
+    -- pending / delivered / failed
+    `status` TEXT NOT NULL DEFAULT 'pending',
+    `attempts` INTEGER NOT NULL DEFAULT 0,
+    `next_attempt_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    `last_status` INTEGER,
+    `last_error` TEXT,
+    `delivered_at` TEXT
+);
+
+CREATE INDEX idx_webhook_delivery_status ON webhook_delivery (status, next_attempt_at);
@@ -0,0 +1,8 @@
+-- Per-group settings. Groups without a row, and NULL columns, use the defaults.
+CREATE TABLE `bot_group_config` (
+    `group_uin` INTEGER NOT NULL PRIMARY KEY,
+    -- JSON array of /咕 tiers: [{"days": 10, "label": "10天没打卡", "emoji": "💢"}, ...]
+    `gu_tiers` TEXT,
+    -- members who joined fewer than this many days ago are left out of /咕
+    `gu_grace_days` INTEGER
+);
@@ -0,0 +1,16 @@
+-- Leave periods of members, by bot day (UTC+8, starting at 04:00).
+CREATE TABLE `bot_leave` (
+    `id` INTEGER NOT NULL PRIMARY KEY,
+    `created_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    `member_id` INTEGER NOT NULL REFERENCES `bot_group_member`(`id`),
+    -- first and last day of the leave, `YYYY-MM-DD`, both included
+    `start_date` TEXT NOT NULL,
+    `end_date` TEXT NOT NULL,
+    `reason` TEXT NOT NULL DEFAULT '',
+    -- pending / approved / rejected / cancelled; only approved leave counts
+    `status` TEXT NOT NULL DEFAULT 'approved',
+    `reviewed_by` INTEGER REFERENCES `bot_group_member`(`id`),
+    `reviewed_at` TEXT
+);
+
+CREATE INDEX idx_bot_leave_member ON bot_leave (member_id, end_date);
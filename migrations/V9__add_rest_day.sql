@@ -0,0 +1,13 @@
+-- Days nobody is expected to check in: a range of bot days, or a weekday
+-- every week.
+CREATE TABLE `bot_rest_day` (
+    `id` INTEGER NOT NULL PRIMARY KEY,
+    `created_at` TEXT NOT NULL DEFAULT (strftime('%Y-%m-%d %H:%M:%f', 'now')),
+    -- first and last day, `YYYY-MM-DD`, both included
+    `start_date` TEXT,
+    `end_date` TEXT,
+    -- 1 (Monday) to 7 (Sunday)
+    `weekday` INTEGER,
+    `reason` TEXT NOT NULL DEFAULT '',
+    CHECK ((`start_date` IS NOT NULL AND `end_date` IS NOT NULL) != (`weekday` IS NOT NULL))
+);
@@ -1,2 +1,9 @@
 pub mod api;
+pub mod assets;
+pub mod bot;
+pub mod cli;
+pub mod console;
+pub mod onebot;
+pub mod outbox;
 pub mod qbot;
+pub mod scenario;
@@ -3,19 +3,34 @@ use axum::{
     extract::{Json, State},
     http::StatusCode,
     response::IntoResponse,
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
 };
 use serde::{Deserialize, Serialize};
 // async_trait not required anymore
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use super::assets;
+use crate::metrics;
 use crate::service::Service;
+use crate::service::goal::Amount;
+use crate::service::group_config::{GuConfig, GuTier};
+use crate::service::habit::Habit;
+use crate::service::leaderboard::Period;
+use crate::service::leave::{CreateLeaveError, LeaveStatus};
+use crate::service::models::Channel;
+use crate::service::rest_day::RestDays;
+use crate::shutdown::ShutdownSignal;
 
 use argon2::password_hash::SaltString;
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use axum::Extension;
 use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures::StreamExt;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, TokenData, Validation, decode, encode};
 use rand::rngs::OsRng;
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
 
 #[derive(Deserialize)]
 pub struct LoginRequest {
@@ -29,6 +44,54 @@ pub struct ResetPasswordRequest {
     pub new_password: String,
 }
 
+#[derive(Deserialize)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    pub secret: Option<String>,
+    /// Comma-separated event kinds, `*` (the default) for all
+    pub events: Option<String>,
+}
+
+/// Dates are bot days, `YYYY-MM-DD`; `start` defaults to today.
+#[derive(Deserialize)]
+pub struct LeaveRequest {
+    pub start: Option<String>,
+    pub end: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// Either `start` (and `end`, the same day by default), `YYYY-MM-DD`, or
+/// `weekday`, 1 (Monday) to 7 (Sunday).
+#[derive(Deserialize)]
+pub struct RestDayRequest {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub weekday: Option<u8>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct HabitRequest {
+    pub name: String,
+}
+
+/// Daily goal of `habit`, the default habit if omitted.
+#[derive(Deserialize)]
+pub struct GoalRequest {
+    pub habit: Option<String>,
+    pub amount: f64,
+    pub unit: String,
+}
+
+/// Omitted fields go back to the defaults.
+#[derive(Deserialize)]
+pub struct GuConfigRequest {
+    pub tiers: Option<Vec<GuTier>>,
+    pub grace_days: Option<i64>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct Claims {
     sub: i64,
@@ -64,22 +127,107 @@ fn verify_jwt(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Er
     )
 }
 
-pub fn routes(svc: Service) -> Router {
-    Router::new()
+pub fn routes(svc: Service, shutdown: ShutdownSignal) -> Router {
+    let app = Router::new()
         .route("/login", post(login_handler))
         .route("/logout", post(logout_handler))
         .route("/reset_password", post(reset_password_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
         .route("/", get(index_handler))
         .route("/static/{*file}", get(static_handler))
         .route("/daka/records", get(daka_records_handler))
         .route("/daka/gu", get(daka_gu_handler))
+        .route("/daka/leaderboard", get(daka_leaderboard_handler))
+        .route("/daka/stats", get(daka_stats_handler))
         .route("/daka/daka", post(daka_create_handler))
         .route("/daka/daka", delete(daka_delete_handler))
-        .with_state(svc)
+        .route("/daka/undo", post(daka_undo_handler))
+        .route("/daka/events", get(daka_events_handler))
+        .route("/daka/habits", get(daka_habits_handler))
+        .route("/daka/goal", get(daka_goal_handler))
+        .route("/daka/goal", put(daka_goal_update_handler))
+        .route("/daka/goal", delete(daka_goal_delete_handler))
+        .route("/daka/rest_days", get(daka_rest_days_handler))
+        .route("/daka/leave", get(daka_leave_list_handler))
+        .route("/daka/leave", post(daka_leave_create_handler))
+        .route("/daka/leave/{id}", delete(daka_leave_end_handler))
+        .route("/cal/token", get(cal_token_handler))
+        .route("/cal/token", post(cal_token_regenerate_handler))
+        .route("/cal/group/{file}", get(cal_group_feed_handler))
+        .route("/cal/{file}", get(cal_member_feed_handler))
+        .route("/admin/import", post(admin_import_handler))
+        .route("/admin/backup", post(admin_backup_handler))
+        .route("/admin/audit", get(admin_audit_handler))
+        .route("/admin/bot", get(admin_bot_page_handler))
+        .route("/admin/bot/login", get(admin_bot_login_handler))
+        .route("/admin/bot/qrcode.png", get(admin_bot_qrcode_handler))
+        .route("/admin/webhooks", get(admin_webhooks_handler))
+        .route("/admin/webhooks", post(admin_webhook_create_handler))
+        .route(
+            "/admin/webhooks/deliveries",
+            get(admin_webhook_deliveries_handler),
+        )
+        .route("/admin/webhooks/{id}", delete(admin_webhook_delete_handler))
+        .route("/admin/habits", post(admin_habit_create_handler))
+        .route(
+            "/admin/habits/{id}/default",
+            post(admin_habit_default_handler),
+        )
+        .route("/admin/goal", put(admin_goal_update_handler))
+        .route("/admin/goal", delete(admin_goal_delete_handler))
+        .route("/admin/rest_days", post(admin_rest_day_create_handler))
+        .route(
+            "/admin/rest_days/{id}",
+            delete(admin_rest_day_delete_handler),
+        )
+        .route("/admin/leave", get(admin_leave_list_handler))
+        .route(
+            "/admin/leave/{id}/approve",
+            post(admin_leave_approve_handler),
+        )
+        .route("/admin/leave/{id}/reject", post(admin_leave_reject_handler))
+        .route("/admin/groups/{group_uin}/gu", get(admin_gu_config_handler))
+        .route(
+            "/admin/groups/{group_uin}/gu",
+            put(admin_gu_config_update_handler),
+        )
+        .fallback(spa_fallback_handler)
+        .layer(Extension(shutdown))
+        .with_state(svc);
+    if metrics_addr().is_some() {
+        app
+    } else {
+        app.merge(metrics_routes())
+    }
+}
+
+/// Address of a separate listener for `/metrics`. When unset, `/metrics` is
+/// served together with the public API.
+pub fn metrics_addr() -> Option<String> {
+    std::env::var("METRICS_ADDR").ok().filter(|v| !v.is_empty())
+}
+
+pub fn metrics_routes() -> Router {
+    Router::new().route("/metrics", get(metrics_handler))
+}
+
+async fn metrics_handler() -> impl IntoResponse {
+    (
+        [("Content-Type", "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
 }
 
+/// `habit` is the name of the habit, the default one if omitted. `amount`
+/// is a number and a unit such as `30分钟`, only for check-ins.
 #[derive(Deserialize)]
-struct DakaPayload {}
+struct DakaPayload {
+    #[serde(default)]
+    habit: Option<String>,
+    #[serde(default)]
+    amount: Option<String>,
+}
 
 // AuthUser unused (cookie-based auth)
 
@@ -101,6 +249,38 @@ fn extract_token_from_cookies(headers: &HeaderMap) -> Result<String, (StatusCode
 use axum::extract::Query;
 use std::collections::HashMap;
 
+/// Verify the auth cookie and require the member to be listed in `ADMIN_UINS`.
+/// Returns the member id of the admin.
+fn require_admin(svc: &Service, headers: &HeaderMap) -> Result<i64, (StatusCode, &'static str)> {
+    let token = extract_token_from_cookies(headers)?;
+    let Ok(jwt) = verify_jwt(&token) else {
+        return Err((StatusCode::UNAUTHORIZED, "invalid token"));
+    };
+    if !svc.is_admin_member(jwt.claims.sub) {
+        return Err((StatusCode::FORBIDDEN, "admin only"));
+    }
+    Ok(jwt.claims.sub)
+}
+
+/// The habit named by the `habit` query parameter, the default one if it is
+/// not given.
+fn habit_param(
+    svc: &Service,
+    q: &HashMap<String, String>,
+) -> Result<Habit, (StatusCode, Json<serde_json::Value>)> {
+    match svc.find_habit(q.get("habit").map(|s| s.as_str())) {
+        Ok(Some(habit)) => Ok(habit),
+        Ok(None) => Err((
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": "unknown habit"})),
+        )),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )),
+    }
+}
+
 async fn daka_records_handler(
     State(svc): State<Service>,
     headers: HeaderMap,
@@ -116,24 +296,80 @@ async fn daka_records_handler(
     };
     let _member_id = data.claims.sub;
     let date = q.get("date").map(|s| s.as_str());
-    match svc.query_records_for_date(date) {
-        Ok(rows) => {
-            // return array of { name, time } where time is null or "HH:MM"
-            let arr: Vec<_> = rows
+    let habit = match habit_param(&svc, &q) {
+        Ok(habit) => habit,
+        Err(e) => return e.into_response(),
+    };
+    match svc.query_records_for_date(date, habit.id) {
+        Ok(rows) => (StatusCode::OK, Json(serde_json::json!({"records": rows}))).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e, "message": "failed to get records"})),
+        )
+            .into_response(),
+    }
+}
+
+// Members who have gone without a check-in, by the `/咕` tiers of `group`
+// (the defaults if it is not given)
+async fn daka_gu_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    if verify_jwt(&token).is_err() {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    }
+    let group_uin = match q.get("group").map(|g| g.parse::<u32>()) {
+        None => None,
+        Some(Ok(group_uin)) => Some(group_uin),
+        Some(Err(_)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "invalid group"})),
+            )
+                .into_response();
+        }
+    };
+
+    let habit = match habit_param(&svc, &q) {
+        Ok(habit) => habit,
+        Err(e) => return e.into_response(),
+    };
+
+    match svc.gu_report(group_uin, habit.id) {
+        Ok(report) => {
+            let tiers: Vec<_> = report
                 .into_iter()
-                .map(|(n, time)| serde_json::json!({"name": n, "time": time}))
+                .map(|(tier, members)| {
+                    serde_json::json!({
+                        "days": tier.days,
+                        "label": tier.label,
+                        "emoji": tier.emoji,
+                        "members": members,
+                    })
+                })
                 .collect();
-            (StatusCode::OK, Json(serde_json::json!({"records": arr}))).into_response()
+            (StatusCode::OK, Json(serde_json::json!({"tiers": tiers}))).into_response()
         }
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
-            Json(serde_json::json!({"error": e, "message": "failed to get records"})),
+            Json(serde_json::json!({"error": e})),
         )
             .into_response(),
     }
 }
 
-async fn daka_gu_handler(State(svc): State<Service>, headers: HeaderMap) -> impl IntoResponse {
+// Ranking by check-in days; `period` is `week`, `month` (default) or `all`
+async fn daka_leaderboard_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
     let token = match extract_token_from_cookies(&headers) {
         Ok(t) => t,
         Err(e) => return e.into_response(),
@@ -141,11 +377,33 @@ async fn daka_gu_handler(State(svc): State<Service>, headers: HeaderMap) -> impl
     if verify_jwt(&token).is_err() {
         return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
     }
+    let period = match q.get("period").map(|p| Period::parse(p)) {
+        None => Period::Month,
+        Some(Some(period)) => period,
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "period must be week, month or all"})),
+            )
+                .into_response();
+        }
+    };
+
+    let habit = match habit_param(&svc, &q) {
+        Ok(habit) => habit,
+        Err(e) => return e.into_response(),
+    };
 
-    match svc.query_missed_and_warning() {
-        Ok((missed, warn)) => (
+    match svc.leaderboard(period, &habit) {
+        Ok(board) => (
             StatusCode::OK,
-            Json(serde_json::json!({"missed_10": missed, "warning_7": warn})),
+            Json(serde_json::json!({
+                "period": period.as_str(),
+                "habit": habit.name,
+                "start": board.start.map(|d| d.to_string()),
+                "end": board.end.to_string(),
+                "entries": board.entries,
+            })),
         )
             .into_response(),
         Err(e) => (
@@ -156,53 +414,144 @@ async fn daka_gu_handler(State(svc): State<Service>, headers: HeaderMap) -> impl
     }
 }
 
-// Serve SPA index.html
-async fn index_handler() -> impl IntoResponse {
-    match tokio::fs::read_to_string("web/index.html").await {
-        Ok(s) => (
-            StatusCode::OK,
-            [("Content-Type", "text/html; charset=utf-8")],
-            s,
+// Aggregate statistics over `from`..=`to` (`YYYY-MM-DD` bot days), the last
+// 7 days by default
+async fn daka_stats_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    if verify_jwt(&token).is_err() {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    }
+    let parse = |key: &str| {
+        q.get(key)
+            .map(|s| {
+                chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                    .map_err(|_| format!("{key} must be YYYY-MM-DD"))
+            })
+            .transpose()
+    };
+    let range = parse("from")
+        .and_then(|from| Ok((from, parse("to")?)))
+        .and_then(|(from, to)| svc.stats_range(from, to));
+    let (from, to) = match range {
+        Ok(range) => range,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": e})),
+            )
+                .into_response();
+        }
+    };
+
+    let habit = match habit_param(&svc, &q) {
+        Ok(habit) => habit,
+        Err(e) => return e.into_response(),
+    };
+
+    match svc.group_stats(from, to, habit.id) {
+        Ok(stats) => (StatusCode::OK, Json(stats)).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
         )
             .into_response(),
-        Err(_) => (StatusCode::NOT_FOUND, "not found").into_response(),
     }
 }
 
+// Server-Sent Events stream of check-in changes from every channel
+async fn daka_events_handler(
+    State(svc): State<Service>,
+    Extension(shutdown): Extension<ShutdownSignal>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    if verify_jwt(&token).is_err() {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    }
+
+    let stream = futures::stream::unfold(svc.subscribe(), |mut rx| async move {
+        let event = match rx.recv().await {
+            Ok(ev) => Event::default()
+                .event(ev.kind())
+                .json_data(&ev)
+                .unwrap_or_else(|_| Event::default().event(ev.kind())),
+            // tell the client to re-fetch instead of silently missing updates
+            Err(RecvError::Lagged(n)) => Event::default().event("lagged").data(n.to_string()),
+            Err(RecvError::Closed) => return None,
+        };
+        Some((Ok::<_, Infallible>(event), rx))
+    })
+    // end the stream on shutdown, it would otherwise hold the server open
+    .take_until(shutdown.recv_owned());
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+// Liveness: 503 once the database stops answering or the bot task has died,
+// so the orchestrator restarts the process
+async fn healthz_handler(State(svc): State<Service>) -> impl IntoResponse {
+    let report = svc.health();
+    let status = if report.healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report)).into_response()
+}
+
+// Readiness: additionally 503 until the bot is online
+async fn readyz_handler(State(svc): State<Service>) -> impl IntoResponse {
+    let report = svc.health();
+    let status = if report.ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report)).into_response()
+}
+
+// Serve SPA index.html
+async fn index_handler(headers: HeaderMap) -> impl IntoResponse {
+    assets::serve("index.html", &headers).await
+}
+
 // Serve static assets from web/static
 async fn static_handler(
     axum::extract::Path(file): axum::extract::Path<String>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    // prevent path traversal
-    if file.contains("..") {
-        return (StatusCode::FORBIDDEN, "forbidden").into_response();
-    }
-    let path = format!("web/static/{}", file);
-    match tokio::fs::read(&path).await {
-        Ok(bytes) => {
-            let content_type = match std::path::Path::new(&path)
-                .extension()
-                .and_then(|s| s.to_str())
-            {
-                Some("js") => "application/javascript",
-                Some("css") => "text/css",
-                Some("png") => "image/png",
-                Some("jpg") | Some("jpeg") => "image/jpeg",
-                Some("svg") => "image/svg+xml",
-                Some("html") => "text/html; charset=utf-8",
-                _ => "application/octet-stream",
-            };
-            let body = bytes;
-            (StatusCode::OK, [("Content-Type", content_type)], body).into_response()
-        }
-        Err(_) => (StatusCode::NOT_FOUND, "not found").into_response(),
+    assets::serve(&format!("static/{}", file), &headers).await
+}
+
+// Client-side routes: browsers navigating to an unknown path get the SPA,
+// anything else gets a plain 404.
+async fn spa_fallback_handler(method: axum::http::Method, headers: HeaderMap) -> impl IntoResponse {
+    let wants_html = headers
+        .get("accept")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"));
+    if (method == axum::http::Method::GET || method == axum::http::Method::HEAD) && wants_html {
+        assets::serve("index.html", &headers).await
+    } else {
+        (StatusCode::NOT_FOUND, "not found").into_response()
     }
 }
 
 async fn daka_create_handler(
     State(svc): State<Service>,
     headers: HeaderMap,
-    Json(_payload): Json<DakaPayload>,
+    Json(payload): Json<DakaPayload>,
 ) -> impl IntoResponse {
     let token = match extract_token_from_cookies(&headers) {
         Ok(t) => t,
@@ -212,7 +561,47 @@ async fn daka_create_handler(
         return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
     };
     let member_id = jwt.claims.sub as i64;
-    let resp = svc.handle_打卡(member_id, "");
+    let habit = match svc.find_habit(
+        payload
+            .habit
+            .as_deref()
+            .map(str::trim)
+            .filter(|h| !h.is_empty()),
+    ) {
+        Ok(Some(habit)) => habit,
+        Ok(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "unknown habit"})),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"error": e})),
+            )
+                .into_response();
+        }
+    };
+    let amount = match payload
+        .amount
+        .as_deref()
+        .map(str::trim)
+        .filter(|a| !a.is_empty())
+        .map(Amount::parse)
+    {
+        None => None,
+        Some(Some(amount)) => Some(amount),
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "invalid amount"})),
+            )
+                .into_response();
+        }
+    };
+    let resp = svc.daka(member_id, &habit, amount, "", Channel::Web);
     (
         StatusCode::OK,
         Json(serde_json::json!({"ok": resp.ok, "message": resp.message})),
@@ -220,10 +609,29 @@ async fn daka_create_handler(
         .into_response()
 }
 
-async fn daka_delete_handler(
+async fn daka_habits_handler(State(svc): State<Service>, headers: HeaderMap) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    if verify_jwt(&token).is_err() {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    }
+    match svc.list_habits() {
+        Ok(habits) => (StatusCode::OK, Json(serde_json::json!({"habits": habits}))).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+// Daily goals of `habit` for the group and for the member, each null if unset
+async fn daka_goal_handler(
     State(svc): State<Service>,
     headers: HeaderMap,
-    Json(_payload): Json<DakaPayload>,
+    Query(q): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
     let token = match extract_token_from_cookies(&headers) {
         Ok(t) => t,
@@ -232,63 +640,897 @@ async fn daka_delete_handler(
     let Ok(jwt) = verify_jwt(&token) else {
         return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
     };
-    let member_id = jwt.claims.sub as i64;
-    let resp = svc.handle_我没打卡(member_id, "");
-    (
-        StatusCode::OK,
-        Json(serde_json::json!({"ok": resp.ok, "message": resp.message})),
-    )
-        .into_response()
+    let habit = match habit_param(&svc, &q) {
+        Ok(habit) => habit,
+        Err(e) => return e.into_response(),
+    };
+    let res = svc
+        .get_goal(habit.id, None)
+        .and_then(|group| Ok((group, svc.get_goal(habit.id, Some(jwt.claims.sub))?)));
+    match res {
+        Ok((group, member)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"group": group, "member": member})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
 }
 
-async fn login_handler(
+/// Set the goal of the group (`member_id` `None`) or of a member.
+fn update_goal(
+    svc: &Service,
+    req: GoalRequest,
+    member_id: Option<i64>,
+    actor_id: i64,
+) -> axum::response::Response {
+    let habit = match svc.find_habit(req.habit.as_deref()) {
+        Ok(Some(habit)) => habit,
+        Ok(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "unknown habit"})),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"error": e})),
+            )
+                .into_response();
+        }
+    };
+    let target = match Amount::new(req.amount, &req.unit) {
+        Ok(target) => target,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": e})),
+            )
+                .into_response();
+        }
+    };
+    match svc.goal_unit_conflict(habit.id, member_id, &target.unit) {
+        Ok(None) => {}
+        Ok(Some(unit)) => {
+            return (
+                StatusCode::CONFLICT,
+                Json(serde_json::json!({
+                    "error": format!("already checked in with unit {} today", unit)
+                })),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({"error": e})),
+            )
+                .into_response();
+        }
+    }
+    match svc.set_goal(habit.id, member_id, target, Some(actor_id), Channel::Web) {
+        Ok(goal) => (StatusCode::OK, Json(serde_json::json!({"goal": goal}))).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+/// Clear the goal of the group (`member_id` `None`) or of a member.
+fn delete_goal(
+    svc: &Service,
+    q: &HashMap<String, String>,
+    member_id: Option<i64>,
+    actor_id: i64,
+) -> axum::response::Response {
+    let habit = match habit_param(svc, q) {
+        Ok(habit) => habit,
+        Err(e) => return e.into_response(),
+    };
+    match svc.clear_goal(habit.id, member_id, Some(actor_id), Channel::Web) {
+        Ok(Some(_)) => StatusCode::NO_CONTENT.into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn daka_goal_update_handler(
     State(svc): State<Service>,
-    Json(payload): Json<LoginRequest>,
+    headers: HeaderMap,
+    Json(req): Json<GoalRequest>,
 ) -> impl IntoResponse {
-    // find member by uin
-    match svc.find_member_by_uin(payload.uin) {
-        Some((member_id, pw_hash)) => {
-            // if stored password is empty, instruct frontend to redirect to reset-password
-            if pw_hash.trim().is_empty() {
-                return (StatusCode::OK, Json(serde_json::json!({"ok": false, "need_reset": true, "message": "password not set"}))).into_response();
-            }
-            // verify password
-            let parsed = PasswordHash::new(&pw_hash);
-            match parsed {
-                Ok(ph) => {
-                    let verifier = Argon2::default();
-                    if verifier
-                        .verify_password(payload.password.as_bytes(), &ph)
-                        .is_ok()
-                    {
-                        match issue_jwt(member_id) {
-                            Ok(token) => {
-                                // set HttpOnly cookie with Max-Age matching JWT expiry (180 days)
-                                let cookie = format!(
-                                    "auth_token={}; HttpOnly; Path=/; Max-Age=15552000; SameSite=Lax",
-                                    token
-                                );
-                                let body = Json(serde_json::json!({"ok": true}));
-                                return (StatusCode::OK, [("Set-Cookie", cookie)], body)
-                                    .into_response();
-                            }
-                            Err(e) => {
-                                return (
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    format!("token error: {:?}", e),
-                                )
-                                    .into_response();
-                            }
-                        }
-                    }
-                }
-                Err(_) => {
-                    // invalid stored hash
-                }
-            }
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    update_goal(&svc, req, Some(jwt.claims.sub), jwt.claims.sub)
+}
+
+async fn daka_goal_delete_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    delete_goal(&svc, &q, Some(jwt.claims.sub), jwt.claims.sub)
+}
+
+async fn daka_rest_days_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    if verify_jwt(&token).is_err() {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    }
+    match svc.list_rest_days() {
+        Ok(rest_days) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"rest_days": rest_days})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn daka_leave_list_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    match svc.list_leaves(Some(jwt.claims.sub), None) {
+        Ok(leaves) => (StatusCode::OK, Json(serde_json::json!({"leaves": leaves}))).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+// Leave is pending until an admin approves it if LEAVE_REQUIRES_APPROVAL is set
+async fn daka_leave_create_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Json(req): Json<LeaveRequest>,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    let parse = |s: &str| chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d");
+    let start = match req.start.as_deref().map(parse).transpose() {
+        Ok(start) => start,
+        Err(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": "start must be YYYY-MM-DD"})),
+            )
+                .into_response();
+        }
+    };
+    let Ok(end) = parse(&req.end) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": "end must be YYYY-MM-DD"})),
+        )
+            .into_response();
+    };
+    match svc.create_leave(jwt.claims.sub, start, end, req.reason.trim(), Channel::Web) {
+        Ok(leave) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({"leave": leave})),
+        )
+            .into_response(),
+        Err(e @ CreateLeaveError::Db(_)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e.to_string()})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+// Cancels leave that has not started yet and ends leave in progress yesterday
+async fn daka_leave_end_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    match svc.end_leave(jwt.claims.sub, Some(id), Channel::Web) {
+        Ok(Some(leave)) => {
+            (StatusCode::OK, Json(serde_json::json!({"leave": leave}))).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn daka_delete_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Json(payload): Json<DakaPayload>,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    let member_id = jwt.claims.sub as i64;
+    let habit = payload.habit.unwrap_or_default();
+    let resp = svc.handle_我没打卡(member_id, habit.trim(), Channel::Web);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"ok": resp.ok, "message": resp.message})),
+    )
+        .into_response()
+}
+
+async fn cal_token_handler(State(svc): State<Service>, headers: HeaderMap) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    match svc.get_or_create_cal_token(jwt.claims.sub, Channel::Web) {
+        Ok(cal_token) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"token": cal_token, "url": format!("/cal/{}.ics", cal_token)})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn cal_token_regenerate_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    match svc.regenerate_cal_token(jwt.claims.sub, Channel::Web) {
+        Ok(cal_token) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"token": cal_token, "url": format!("/cal/{}.ics", cal_token)})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+fn ics_response(res: Result<String, String>) -> axum::response::Response {
+    match res {
+        Ok(body) => (
+            StatusCode::OK,
+            [
+                ("Content-Type", "text/calendar; charset=utf-8"),
+                ("Cache-Control", "no-cache"),
+            ],
+            body,
+        )
+            .into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e).into_response(),
+    }
+}
+
+// Calendar feeds are authenticated by the secret token in the URL, since
+// calendar apps cannot send our cookie.
+async fn cal_member_feed_handler(
+    State(svc): State<Service>,
+    axum::extract::Path(file): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    let Some(cal_token) = file.strip_suffix(".ics") else {
+        return (StatusCode::NOT_FOUND, "not found").into_response();
+    };
+    match svc.find_member_by_cal_token(cal_token) {
+        Some((member_id, nickname)) => ics_response(svc.build_member_ics(member_id, &nickname)),
+        None => (StatusCode::NOT_FOUND, "not found").into_response(),
+    }
+}
+
+// The group feed is only enabled when GROUP_CAL_TOKEN is set.
+async fn cal_group_feed_handler(
+    State(svc): State<Service>,
+    axum::extract::Path(file): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    let expected = std::env::var("GROUP_CAL_TOKEN").unwrap_or_default();
+    match file.strip_suffix(".ics") {
+        Some(cal_token) if !expected.is_empty() && cal_token == expected => {
+            ics_response(svc.build_group_ics())
+        }
+        _ => (StatusCode::NOT_FOUND, "not found").into_response(),
+    }
+}
+
+// Upload a CSV body of `qq_uin,date,time,note` rows. `?dry_run=1` reports
+// what would be imported without writing anything.
+async fn admin_import_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+    body: String,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let dry_run = q.get("dry_run").is_some_and(|v| v == "1" || v == "true");
+    match svc.import_daka_csv(&body, dry_run, Some(admin_id), Channel::Web) {
+        Ok(report) => (StatusCode::OK, Json(serde_json::json!(report))).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_backup_handler(State(svc): State<Service>, headers: HeaderMap) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    let config = crate::service::backup::BackupConfig::from_env();
+    match tokio::task::spawn_blocking(move || svc.backup_and_prune(&config)).await {
+        Ok(Ok(path)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"ok": true, "path": path.display().to_string()})),
+        )
+            .into_response(),
+        Ok(Err(e)) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"ok": false, "error": e})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"ok": false, "error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+// Query the audit log. Supports `?limit=`, `?action=` (prefix) and `?actor_id=`.
+async fn admin_audit_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    let limit = q
+        .get("limit")
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(100)
+        .min(1000);
+    let action = q.get("action").map(|s| s.as_str());
+    let actor_id = q.get("actor_id").and_then(|v| v.parse::<i64>().ok());
+    match svc.query_audit_log(limit, action, actor_id) {
+        Ok(records) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"records": records})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+// Bot QR login page; the data it polls is admin-only
+async fn admin_bot_page_handler(headers: HeaderMap) -> impl IntoResponse {
+    assets::serve("bot.html", &headers).await
+}
+
+async fn admin_bot_login_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    (StatusCode::OK, Json(svc.qr_login_report())).into_response()
+}
+
+async fn admin_bot_qrcode_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    match svc.pending_qr_png() {
+        Some(png) => (
+            StatusCode::OK,
+            [("Content-Type", "image/png"), ("Cache-Control", "no-store")],
+            png,
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "no pending QR code").into_response(),
+    }
+}
+
+async fn admin_webhooks_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    match svc.list_webhooks() {
+        Ok(webhooks) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"webhooks": webhooks})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+// The secret is only returned here, when the webhook is created
+async fn admin_webhook_create_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Json(req): Json<CreateWebhookRequest>,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    match svc.create_webhook(&req.url, req.secret.as_deref(), req.events.as_deref()) {
+        Ok(webhook) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({"webhook": webhook, "secret": webhook.secret})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_webhook_delete_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    match svc.delete_webhook(id) {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_habit_create_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Json(req): Json<HabitRequest>,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    match svc.create_habit(req.name.trim(), Some(admin_id), Channel::Web) {
+        Ok(habit) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({"habit": habit})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_habit_default_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    match svc.set_default_habit(id, Some(admin_id), Channel::Web) {
+        Ok(Some(habit)) => {
+            (StatusCode::OK, Json(serde_json::json!({"habit": habit}))).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_goal_update_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Json(req): Json<GoalRequest>,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    update_goal(&svc, req, None, admin_id)
+}
+
+async fn admin_goal_delete_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    delete_goal(&svc, &q, None, admin_id)
+}
+
+async fn admin_rest_day_create_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Json(req): Json<RestDayRequest>,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let parse = |s: &str| chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d").ok();
+    let days = match (&req.start, &req.weekday) {
+        (Some(start), None) => parse(start).and_then(|start| {
+            let end = req.end.as_deref().map_or(Some(start), parse)?;
+            Some(RestDays::Dates(start, end))
+        }),
+        (None, Some(weekday)) => weekday
+            .checked_sub(1)
+            .and_then(|w| chrono::Weekday::try_from(w).ok())
+            .map(RestDays::Weekly),
+        _ => None,
+    };
+    let Some(days) = days else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "error": "give start and end as YYYY-MM-DD, or weekday from 1 to 7"
+            })),
+        )
+            .into_response();
+    };
+    match svc.add_rest_day(days, req.reason.trim(), Some(admin_id), Channel::Web) {
+        Ok(rest_day) => (
+            StatusCode::CREATED,
+            Json(serde_json::json!({"rest_day": rest_day})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_rest_day_delete_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    match svc.delete_rest_day(id, Some(admin_id), Channel::Web) {
+        Ok(Some(_)) => StatusCode::NO_CONTENT.into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_leave_list_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    let status = match q.get("status").map(|s| LeaveStatus::parse(s)) {
+        None => None,
+        Some(Some(status)) => Some(status),
+        Some(None) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "error": "status must be pending, approved, rejected or cancelled"
+                })),
+            )
+                .into_response();
+        }
+    };
+    match svc.list_leaves(None, status) {
+        Ok(leaves) => (StatusCode::OK, Json(serde_json::json!({"leaves": leaves}))).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_leave_approve_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> impl IntoResponse {
+    review_leave(&svc, &headers, id, true)
+}
+
+async fn admin_leave_reject_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(id): axum::extract::Path<i64>,
+) -> impl IntoResponse {
+    review_leave(&svc, &headers, id, false)
+}
+
+fn review_leave(
+    svc: &Service,
+    headers: &HeaderMap,
+    id: i64,
+    approve: bool,
+) -> axum::response::Response {
+    let admin_id = match require_admin(svc, headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    match svc.review_leave(id, approve, admin_id, Channel::Web) {
+        Ok(Some(leave)) => {
+            (StatusCode::OK, Json(serde_json::json!({"leave": leave}))).into_response()
+        }
+        Ok(None) => (StatusCode::NOT_FOUND, "not found").into_response(),
+        Err(e) => (StatusCode::CONFLICT, Json(serde_json::json!({"error": e}))).into_response(),
+    }
+}
+
+async fn admin_gu_config_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(group_uin): axum::extract::Path<u32>,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"gu": svc.gu_config(Some(group_uin))})),
+    )
+        .into_response()
+}
+
+async fn admin_gu_config_update_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    axum::extract::Path(group_uin): axum::extract::Path<u32>,
+    Json(req): Json<GuConfigRequest>,
+) -> impl IntoResponse {
+    let admin_id = match require_admin(&svc, &headers) {
+        Ok(id) => id,
+        Err(e) => return e.into_response(),
+    };
+    let config = match GuConfig::new(req.tiers.clone(), req.grace_days) {
+        Ok(config) => config,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({"error": e})),
+            )
+                .into_response();
+        }
+    };
+    match svc.set_gu_config(
+        group_uin,
+        req.tiers.as_ref().map(|_| config.tiers.as_slice()),
+        req.grace_days,
+        Some(admin_id),
+        Channel::Web,
+    ) {
+        Ok(()) => (StatusCode::OK, Json(serde_json::json!({"gu": config}))).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn admin_webhook_deliveries_handler(
+    State(svc): State<Service>,
+    headers: HeaderMap,
+    Query(q): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    if let Err(e) = require_admin(&svc, &headers) {
+        return e.into_response();
+    }
+    let limit = q
+        .get("limit")
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(100)
+        .min(1000);
+    let webhook_id = q.get("webhook_id").and_then(|v| v.parse::<i64>().ok());
+    match svc.query_webhook_deliveries(webhook_id, limit) {
+        Ok(deliveries) => (
+            StatusCode::OK,
+            Json(serde_json::json!({"deliveries": deliveries})),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({"error": e})),
+        )
+            .into_response(),
+    }
+}
+
+async fn daka_undo_handler(State(svc): State<Service>, headers: HeaderMap) -> impl IntoResponse {
+    let token = match extract_token_from_cookies(&headers) {
+        Ok(t) => t,
+        Err(e) => return e.into_response(),
+    };
+    let Ok(jwt) = verify_jwt(&token) else {
+        return (StatusCode::UNAUTHORIZED, "invalid token").into_response();
+    };
+    let resp = svc.handle_撤销(jwt.claims.sub, "", Channel::Web);
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"ok": resp.ok, "message": resp.message})),
+    )
+        .into_response()
+}
+
+async fn login_handler(
+    State(svc): State<Service>,
+    Json(payload): Json<LoginRequest>,
+) -> impl IntoResponse {
+    // find member by uin
+    match svc.find_member_by_uin(payload.uin) {
+        Some((member_id, pw_hash)) => {
+            // if stored password is empty, instruct frontend to redirect to reset-password
+            if pw_hash.trim().is_empty() {
+                return (StatusCode::OK, Json(serde_json::json!({"ok": false, "need_reset": true, "message": "password not set"}))).into_response();
+            }
+            // verify password
+            let parsed = PasswordHash::new(&pw_hash);
+            match parsed {
+                Ok(ph) => {
+                    let verifier = Argon2::default();
+                    if verifier
+                        .verify_password(payload.password.as_bytes(), &ph)
+                        .is_ok()
+                    {
+                        match issue_jwt(member_id) {
+                            Ok(token) => {
+                                // set HttpOnly cookie with Max-Age matching JWT expiry (180 days)
+                                let cookie = format!(
+                                    "auth_token={}; HttpOnly; Path=/; Max-Age=15552000; SameSite=Lax",
+                                    token
+                                );
+                                let body = Json(serde_json::json!({"ok": true}));
+                                metrics::LOGINS.with_label_values(&["success"]).inc();
+                                return (StatusCode::OK, [("Set-Cookie", cookie)], body)
+                                    .into_response();
+                            }
+                            Err(e) => {
+                                return (
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    format!("token error: {:?}", e),
+                                )
+                                    .into_response();
+                            }
+                        }
+                    }
+                }
+                Err(_) => {
+                    // invalid stored hash
+                }
+            }
+            metrics::LOGINS.with_label_values(&["failure"]).inc();
+            (StatusCode::UNAUTHORIZED, "invalid credentials").into_response()
+        }
+        None => {
+            metrics::LOGINS.with_label_values(&["failure"]).inc();
             (StatusCode::UNAUTHORIZED, "invalid credentials").into_response()
         }
-        None => (StatusCode::UNAUTHORIZED, "invalid credentials").into_response(),
     }
 }
 
@@ -324,7 +1566,7 @@ async fn reset_password_handler(
             match hasher.hash_password(req.new_password.as_bytes(), &salt) {
                 Ok(ph) => {
                     let encoded = ph.to_string();
-                    match svc.set_password_for_member_id(member_id, &encoded) {
+                    match svc.set_password_for_member_id(member_id, &encoded, Channel::Web) {
                         Ok(_) => {
                             (StatusCode::OK, Json(serde_json::json!({"ok": true}))).into_response()
                         }
@@ -0,0 +1,153 @@
+use std::path::{Component, Path, PathBuf};
+
+use axum::http::{HeaderMap, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+
+/// A file from `web/`, embedded by `build.rs`. Compressed variants are only
+/// present when they are smaller than the raw bytes.
+pub struct WebAsset {
+    pub path: &'static str,
+    pub etag: &'static str,
+    pub raw: &'static [u8],
+    pub gzip: Option<&'static [u8]>,
+    pub br: Option<&'static [u8]>,
+}
+
+include!(concat!(env!("OUT_DIR"), "/web_assets.rs"));
+
+fn content_type_for(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|s| s.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("webmanifest") => "application/manifest+json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("svg") => "image/svg+xml",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("woff") => "font/woff",
+        _ => "application/octet-stream",
+    }
+}
+
+// The page itself must be revalidated on every load so a deploy is picked up
+// right away; other assets are not fingerprinted, so keep their lifetime short.
+fn cache_control_for(path: &str) -> &'static str {
+    if path.ends_with(".html") {
+        "no-cache"
+    } else {
+        "public, max-age=3600"
+    }
+}
+
+/// When `WEB_DEV_DIR` is set, assets are read from that directory on every
+/// request instead of the copy embedded at build time.
+fn dev_dir() -> Option<PathBuf> {
+    std::env::var_os("WEB_DEV_DIR")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+}
+
+fn accepts_encoding(headers: &HeaderMap, encoding: &str) -> bool {
+    let Some(accept) = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return false;
+    };
+    accept.split(',').any(|item| {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let rejected = parts.any(|p| {
+            p.trim()
+                .strip_prefix("q=")
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        name.eq_ignore_ascii_case(encoding) && !rejected
+    })
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| {
+            v.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+            })
+        })
+}
+
+pub fn find_asset(path: &str) -> Option<&'static WebAsset> {
+    WEB_ASSETS.iter().find(|a| a.path == path)
+}
+
+fn serve_embedded(asset: &'static WebAsset, headers: &HeaderMap) -> Response {
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(header::ETAG, HeaderValue::from_static(asset.etag));
+    resp_headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control_for(asset.path)),
+    );
+    resp_headers.insert(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    if etag_matches(headers, asset.etag) {
+        return (StatusCode::NOT_MODIFIED, resp_headers).into_response();
+    }
+
+    resp_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type_for(asset.path)),
+    );
+    let body = match (asset.br, asset.gzip) {
+        (Some(br), _) if accepts_encoding(headers, "br") => {
+            resp_headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("br"));
+            br
+        }
+        (_, Some(gzip)) if accepts_encoding(headers, "gzip") => {
+            resp_headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+            gzip
+        }
+        _ => asset.raw,
+    };
+    (StatusCode::OK, resp_headers, body).into_response()
+}
+
+async fn serve_from_dir(dir: &Path, path: &str) -> Response {
+    // only plain relative paths may be joined onto the asset directory
+    if !Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return (StatusCode::FORBIDDEN, "forbidden").into_response();
+    }
+    match tokio::fs::read(dir.join(path)).await {
+        Ok(bytes) => (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, content_type_for(path)),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            bytes,
+        )
+            .into_response(),
+        Err(_) => (StatusCode::NOT_FOUND, "not found").into_response(),
+    }
+}
+
+/// Serve a file of the `web/` tree by its path relative to `web/`.
+pub async fn serve(path: &str, headers: &HeaderMap) -> Response {
+    if let Some(dir) = dev_dir() {
+        return serve_from_dir(&dir, path).await;
+    }
+    match find_asset(path) {
+        Some(asset) => serve_embedded(asset, headers),
+        None => (StatusCode::NOT_FOUND, "not found").into_response(),
+    }
+}
@@ -0,0 +1,431 @@
+//! Chat-platform independent part of the bot: command handling and the
+//! message pipeline between a platform adapter and the service.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::debug;
+
+use super::outbox::{MentionTarget, MessageSink, Outbox, OutgoingMessage};
+use crate::metrics;
+use crate::service::Service;
+use crate::service::leaderboard::next_month_start;
+use crate::service::models::{Channel, GroupMember};
+use crate::shutdown::ShutdownSignal;
+
+/// Number of recent messages remembered to drop duplicates.
+const DEDUP_WINDOW: usize = 1024;
+/// A member's worker exits after this long without messages.
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A group message, as delivered by a platform adapter.
+#[derive(Debug, Clone)]
+pub struct IncomingMessage {
+    /// Platform message id, unique within the group
+    pub message_id: i64,
+    pub group_uin: u32,
+    pub sender: GroupMember,
+    /// Set by platforms that do not expose uids: `sender.uid` is a placeholder
+    /// that gives way to the stored member record of the uin, if there is one
+    pub placeholder_uid: bool,
+    /// Text segments of the message, in order
+    pub texts: Vec<String>,
+    /// Uins of the members mentioned in the message
+    pub mentions: Vec<u32>,
+}
+
+impl IncomingMessage {
+    /// First non-empty text segment, trimmed; this is where commands are read from.
+    fn command_text(&self) -> &str {
+        self.texts
+            .iter()
+            .map(|t| t.trim())
+            .find(|t| !t.is_empty())
+            .unwrap_or_default()
+    }
+}
+
+/// A chat platform the bot can run on. Sending goes through [`MessageSink`].
+pub trait ChatAdapter: MessageSink {
+    /// Receive group messages and pass every one of them to `incoming`. On
+    /// shutdown `incoming` is dropped, but sending must keep working until
+    /// [`close`](Self::close) is called.
+    fn run(
+        self: Arc<Self>,
+        incoming: mpsc::UnboundedSender<IncomingMessage>,
+        shutdown: ShutdownSignal,
+    ) -> impl Future<Output = ()> + Send;
+
+    /// Called once the last reply has been sent after shutdown.
+    fn close(&self) {}
+}
+
+/// Run the bot on `adapter` until shutdown, then finish handling the
+/// messages already received and send their replies.
+pub async fn run_adapter<A: ChatAdapter>(svc: Service, adapter: Arc<A>, shutdown: ShutdownSignal) {
+    let (outbox, outbox_task) = Outbox::start(adapter.clone());
+    let leaderboard = tokio::spawn(post_monthly_leaderboard(
+        svc.clone(),
+        outbox.clone(),
+        shutdown.clone(),
+    ));
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let receiver = tokio::spawn(adapter.clone().run(tx, shutdown));
+    let mut dispatcher = Dispatcher::new(svc, outbox);
+    // ends when the adapter stops and drops its sender
+    while let Some(msg) = rx.recv().await {
+        dispatcher.dispatch(msg);
+    }
+    // workers finish the messages they already have, replies included
+    dispatcher.finish().await;
+    let _ = leaderboard.await;
+    // the dispatcher held the last outbox handle, so the queue drains and stops
+    if let Err(e) = outbox_task.await {
+        tracing::error!("Outbox failed: {:?}", e);
+    }
+    adapter.close();
+    if let Err(e) = receiver.await {
+        tracing::error!("Bot adapter failed: {:?}", e);
+    }
+}
+
+/// Groups that get last month's leaderboard when a new month starts, from
+/// the comma-separated `LEADERBOARD_GROUPS`. Unset disables the post.
+fn leaderboard_groups() -> Vec<u32> {
+    std::env::var("LEADERBOARD_GROUPS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|s| s.trim().parse().ok())
+        .collect()
+}
+
+async fn post_monthly_leaderboard(svc: Service, outbox: Outbox, mut shutdown: ShutdownSignal) {
+    let groups = leaderboard_groups();
+    if groups.is_empty() {
+        return;
+    }
+    loop {
+        let now = svc.now();
+        let wait = (next_month_start(now) - now).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shutdown.recv() => return,
+        }
+        let svc = svc.clone();
+        let text = match tokio::task::spawn_blocking(move || svc.last_month_leaderboard()).await {
+            Ok(Ok(board)) => board.to_text(),
+            Ok(Err(e)) => {
+                tracing::error!("Failed to build monthly leaderboard: {}", e);
+                continue;
+            }
+            Err(e) => {
+                tracing::error!("Monthly leaderboard task panicked: {:?}", e);
+                continue;
+            }
+        };
+        for group_uin in &groups {
+            outbox.send(plain(*group_uin, text.clone()));
+        }
+    }
+}
+
+fn plain(group_uin: u32, text: String) -> OutgoingMessage {
+    OutgoingMessage {
+        group_uin,
+        mention: None,
+        reply_to: None,
+        text,
+    }
+}
+
+/// Reply to a message, mentioning its sender.
+fn reply_to(msg: &IncomingMessage, text: String) -> OutgoingMessage {
+    OutgoingMessage {
+        group_uin: msg.group_uin,
+        mention: Some(MentionTarget {
+            uid: msg.sender.uid.clone(),
+            uin: msg.sender.uin,
+            name: msg.sender.group_nickname().to_string(),
+        }),
+        reply_to: Some(msg.message_id),
+        text,
+    }
+}
+
+pub fn handle_message(svc: &Service, msg: &IncomingMessage) -> Option<OutgoingMessage> {
+    let first_text = msg.command_text();
+    let (command, args) = first_text.split_once(' ').unwrap_or((first_text, ""));
+    let mut gm = msg.sender.clone();
+    if msg.placeholder_uid
+        && let Some(uid) = svc.find_member_uid_by_uin(gm.uin)
+    {
+        gm.uid = uid;
+    }
+    let reply = match command {
+        "/打卡" => {
+            debug!("Handling 我没打卡 command for user {}", gm.uin);
+            match svc.upsert_member(&gm, Channel::Bot) {
+                Ok(user_id) => {
+                    let res = svc.handle_打卡(user_id, args, Channel::Bot);
+                    tracing::debug!("Service handle_打卡 ok={} message={}", res.ok, res.message);
+                    Some(reply_to(msg, res.message))
+                }
+                Err(e) => {
+                    tracing::error!("Failed to upsert member: {:?}", e);
+                    Some(plain(msg.group_uin, e.message))
+                }
+            }
+        }
+        "/我没打卡" => {
+            debug!("Handling 我没打卡 command for user {}", gm.uin);
+            match svc.upsert_member(&gm, Channel::Bot) {
+                Ok(user_id) => {
+                    let res = svc.handle_我没打卡(user_id, args, Channel::Bot);
+                    tracing::debug!(
+                        "Service handle_我没打卡 ok={} message={}",
+                        res.ok,
+                        res.message
+                    );
+                    Some(reply_to(msg, res.message))
+                }
+                Err(e) => {
+                    tracing::error!("Failed to upsert member: {:?}", e);
+                    Some(plain(msg.group_uin, e.message))
+                }
+            }
+        }
+        "/撤销" => {
+            debug!("Handling 撤销 command for user {}", gm.uin);
+            match svc.upsert_member(&gm, Channel::Bot) {
+                Ok(user_id) => {
+                    let res = svc.handle_撤销(user_id, args, Channel::Bot);
+                    tracing::debug!("Service handle_撤销 ok={} message={}", res.ok, res.message);
+                    Some(reply_to(msg, res.message))
+                }
+                Err(e) => {
+                    tracing::error!("Failed to upsert member: {:?}", e);
+                    Some(plain(msg.group_uin, e.message))
+                }
+            }
+        }
+        "/请假" => match svc.upsert_member(&gm, Channel::Bot) {
+            Ok(user_id) => {
+                let res = svc.handle_请假(user_id, args, Channel::Bot);
+                tracing::debug!("Service handle_请假 ok={} message={}", res.ok, res.message);
+                Some(reply_to(msg, res.message))
+            }
+            Err(e) => {
+                tracing::error!("Failed to upsert member: {:?}", e);
+                Some(plain(msg.group_uin, e.message))
+            }
+        },
+        "/销假" => match svc.upsert_member(&gm, Channel::Bot) {
+            Ok(user_id) => {
+                let res = svc.handle_销假(user_id, Channel::Bot);
+                tracing::debug!("Service handle_销假 ok={} message={}", res.ok, res.message);
+                Some(reply_to(msg, res.message))
+            }
+            Err(e) => {
+                tracing::error!("Failed to upsert member: {:?}", e);
+                Some(plain(msg.group_uin, e.message))
+            }
+        },
+        "/目标" => match svc.upsert_member(&gm, Channel::Bot) {
+            Ok(user_id) => {
+                let res = svc.handle_目标(user_id, args, svc.is_admin_uin(gm.uin));
+                tracing::debug!("Service handle_目标 ok={} message={}", res.ok, res.message);
+                Some(reply_to(msg, res.message))
+            }
+            Err(e) => {
+                tracing::error!("Failed to upsert member: {:?}", e);
+                Some(plain(msg.group_uin, e.message))
+            }
+        },
+        "/习惯" => {
+            let actor_id = svc.find_member_by_uin(gm.uin).map(|(id, _)| id);
+            let res = svc.handle_习惯(args, actor_id, svc.is_admin_uin(gm.uin));
+            Some(plain(msg.group_uin, res.message))
+        }
+        "/休息日" => {
+            let actor_id = svc.find_member_by_uin(gm.uin).map(|(id, _)| id);
+            let res = svc.handle_休息日(args, actor_id, svc.is_admin_uin(gm.uin));
+            Some(plain(msg.group_uin, res.message))
+        }
+        "/今日" => {
+            let res = svc.handle_今日(args);
+            Some(plain(msg.group_uin, res.message))
+        }
+        "/排行" => {
+            let res = svc.handle_排行(args);
+            Some(plain(msg.group_uin, res.message))
+        }
+        "/统计" => {
+            let res = svc.handle_统计(args);
+            Some(plain(msg.group_uin, res.message))
+        }
+        "/咕" => {
+            // `/咕 @someone` shows the status of one member
+            let res = match msg.mentions.first() {
+                Some(uin) => svc.handle_咕_member(msg.group_uin, *uin, args),
+                None => svc.handle_咕(msg.group_uin, args),
+            };
+            tracing::debug!("Service handle_咕 ok={} message={}", res.ok, res.message);
+            Some(plain(msg.group_uin, res.message))
+        }
+        "/备份" => {
+            let message = if svc.is_admin_uin(gm.uin) {
+                svc.handle_备份().message
+            } else {
+                "仅管理员可用".to_string()
+            };
+            Some(plain(msg.group_uin, message))
+        }
+        "/日志" => {
+            let message = if svc.is_admin_uin(gm.uin) {
+                // `/日志 @someone` shows only the changes made by that member
+                let actor_id = msg
+                    .mentions
+                    .first()
+                    .and_then(|uin| svc.find_member_by_uin(*uin))
+                    .map(|(id, _)| id);
+                svc.handle_日志(args, actor_id).message
+            } else {
+                "仅管理员可用".to_string()
+            };
+            Some(plain(msg.group_uin, message))
+        }
+        _ => None,
+    };
+    // unknown commands get no reply, so only count the ones that were handled
+    if reply.is_some() {
+        metrics::COMMANDS.with_label_values(&[command]).inc();
+    }
+    reply
+}
+
+/// Remembers the last few messages by group and message id, to drop events
+/// delivered more than once.
+struct SeenMessages {
+    order: VecDeque<(u32, i64)>,
+    set: HashSet<(u32, i64)>,
+}
+
+impl SeenMessages {
+    fn new() -> Self {
+        Self {
+            order: VecDeque::with_capacity(DEDUP_WINDOW),
+            set: HashSet::with_capacity(DEDUP_WINDOW),
+        }
+    }
+
+    /// Returns false if the message was seen before.
+    fn insert(&mut self, key: (u32, i64)) -> bool {
+        if !self.set.insert(key) {
+            return false;
+        }
+        self.order.push_back(key);
+        if self.order.len() > DEDUP_WINDOW
+            && let Some(old) = self.order.pop_front()
+        {
+            self.set.remove(&old);
+        }
+        true
+    }
+}
+
+/// Routes group messages to one worker per sender, so the commands of a member
+/// are handled in order while different members are served concurrently.
+struct Dispatcher {
+    svc: Service,
+    outbox: Outbox,
+    workers: HashMap<u32, mpsc::UnboundedSender<IncomingMessage>>,
+    tasks: JoinSet<()>,
+    seen: SeenMessages,
+}
+
+impl Dispatcher {
+    fn new(svc: Service, outbox: Outbox) -> Self {
+        Self {
+            svc,
+            outbox,
+            workers: HashMap::new(),
+            tasks: JoinSet::new(),
+            seen: SeenMessages::new(),
+        }
+    }
+
+    fn dispatch(&mut self, msg: IncomingMessage) {
+        if !self.seen.insert((msg.group_uin, msg.message_id)) {
+            debug!(
+                "Dropping duplicate message {} in {}",
+                msg.message_id, msg.group_uin
+            );
+            return;
+        }
+        let uin = msg.sender.uin;
+        // a worker that went idle has closed its queue and gives the message back
+        let msg = match self.workers.get(&uin) {
+            Some(tx) => match tx.send(msg) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(msg)) => msg,
+            },
+            None => msg,
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(msg);
+        self.workers.insert(uin, tx);
+        self.tasks
+            .spawn(member_worker(self.svc.clone(), self.outbox.clone(), rx));
+        while self.tasks.try_join_next().is_some() {}
+    }
+
+    /// Wait for the workers to handle every message they were given.
+    async fn finish(mut self) {
+        self.workers.clear();
+        while let Some(res) = self.tasks.join_next().await {
+            if let Err(e) = res {
+                tracing::error!("Message worker failed: {:?}", e);
+            }
+        }
+    }
+}
+
+async fn member_worker(
+    svc: Service,
+    outbox: Outbox,
+    mut rx: mpsc::UnboundedReceiver<IncomingMessage>,
+) {
+    loop {
+        match tokio::time::timeout(WORKER_IDLE_TIMEOUT, rx.recv()).await {
+            Ok(Some(msg)) => handle_and_reply(&svc, &outbox, msg).await,
+            Ok(None) => return,
+            Err(_) => {
+                // refuse new messages, but handle what slipped in before closing
+                rx.close();
+                while let Ok(msg) = rx.try_recv() {
+                    handle_and_reply(&svc, &outbox, msg).await;
+                }
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_and_reply(svc: &Service, outbox: &Outbox, msg: IncomingMessage) {
+    let handler_svc = svc.clone();
+    // command handlers block on the database
+    let reply = match tokio::task::spawn_blocking(move || handle_message(&handler_svc, &msg)).await
+    {
+        Ok(reply) => reply,
+        Err(e) => {
+            tracing::error!("Command handler failed: {:?}", e);
+            None
+        }
+    };
+    if let Some(reply) = reply {
+        outbox.send(reply);
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+
+use crate::service::backup::{self, BackupConfig};
+use crate::service::models::Channel;
+use crate::service::{self, Service};
+
+const USAGE: &str = "usage:
+    call-cal-bot import <file.csv> [--dry-run]
+    call-cal-bot backup
+    call-cal-bot restore <backup.db>
+    call-cal-bot scenario [<file or dir>...]";
+
+/// Run a command-line subcommand and return the process exit code.
+pub fn run(args: &[String]) -> i32 {
+    match args[0].as_str() {
+        "import" => import(&service::init_service(), &args[1..]),
+        "backup" => backup(&service::init_service()),
+        // restore must not open (and migrate) the database it replaces
+        "restore" => restore(&args[1..]),
+        "scenario" => scenario(&args[1..]),
+        _ => {
+            eprintln!("{USAGE}");
+            2
+        }
+    }
+}
+
+fn import(svc: &Service, args: &[String]) -> i32 {
+    let dry_run = args.iter().any(|a| a == "--dry-run");
+    let Some(path) = args.iter().find(|a| !a.starts_with("--")) else {
+        eprintln!("{USAGE}");
+        return 2;
+    };
+    let text = match std::fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Failed to read {path}: {e}");
+            return 1;
+        }
+    };
+    match svc.import_daka_csv(&text, dry_run, None, Channel::Cli) {
+        Ok(report) => {
+            println!("{}", report.summary());
+            0
+        }
+        Err(e) => {
+            eprintln!("Import failed: {e}");
+            1
+        }
+    }
+}
+
+fn backup(svc: &Service) -> i32 {
+    match svc.backup_and_prune(&BackupConfig::from_env()) {
+        Ok(path) => {
+            println!("Backup written to {}", path.display());
+            0
+        }
+        Err(e) => {
+            eprintln!("Backup failed: {e}");
+            1
+        }
+    }
+}
+
+fn restore(args: &[String]) -> i32 {
+    let Some(path) = args.first() else {
+        eprintln!("{USAGE}");
+        return 2;
+    };
+    match backup::restore_backup(Path::new(path), Path::new(service::DB_PATH)) {
+        Ok(msg) => {
+            println!("{msg}");
+            0
+        }
+        Err(e) => {
+            eprintln!("Restore failed: {e}");
+            1
+        }
+    }
+}
+
+fn scenario(args: &[String]) -> i32 {
+    let paths: Vec<PathBuf> = if args.is_empty() {
+        vec![PathBuf::from("scenarios")]
+    } else {
+        args.iter().map(PathBuf::from).collect()
+    };
+    if super::scenario::run(&paths) { 0 } else { 1 }
+}
@@ -0,0 +1,224 @@
+//! Bot adapter that reads group messages from stdin and prints the replies,
+//! for trying out commands without a QQ account (`RUN_MODE=console`).
+//!
+//! Every line is a message from the current member to the current group, and
+//! `@<uin>` in a line mentions a member. Lines starting with `:` are console
+//! commands, see [`HELP`]. `CONSOLE_UIN`, `CONSOLE_NAME` and `CONSOLE_GROUP`
+//! set who is talking at startup.
+//!
+//! Commands run against a fresh in-memory database, or the one at `CONSOLE_DB`
+//! to keep data between runs; never the bot's own database.
+
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use super::bot::{self, ChatAdapter, IncomingMessage};
+use super::outbox::{MessageSink, OutgoingMessage};
+use crate::service::clock::Clock;
+use crate::service::models::GroupMember;
+use crate::service::status::BotState;
+use crate::service::user::admin_uins;
+use crate::service::{self, Service};
+use crate::shutdown::ShutdownSignal;
+
+const HELP: &str = "\
+:as <uin> [名字]  以该成员的身份发言
+:group <群号>     切换到该群
+:who              显示当前成员和群
+:quit             退出
+发言中的 @<uin> 表示提及该成员";
+
+/// Who is talking, and where.
+struct Session {
+    uin: u32,
+    group_uin: u32,
+    /// Names given with `:as`, so switching back to a member keeps its name
+    names: HashMap<u32, String>,
+}
+
+impl Session {
+    fn from_env() -> Self {
+        let env_num = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        let uin = env_num("CONSOLE_UIN", 10000);
+        let name = std::env::var("CONSOLE_NAME")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| format!("用户{}", uin));
+        Self {
+            uin,
+            group_uin: env_num("CONSOLE_GROUP", 1),
+            names: HashMap::from([(uin, name)]),
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.names
+            .get(&self.uin)
+            .map(String::as_str)
+            .unwrap_or_default()
+    }
+
+    /// Run a `:` command, returning false on `:quit`.
+    fn command(&mut self, line: &str) -> bool {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(":as"), Some(uin)) => match uin.parse() {
+                Ok(uin) => {
+                    self.uin = uin;
+                    let name: Vec<&str> = parts.collect();
+                    if !name.is_empty() {
+                        self.names.insert(uin, name.join(" "));
+                    }
+                    self.names
+                        .entry(uin)
+                        .or_insert_with(|| format!("用户{}", uin));
+                    self.print_who();
+                }
+                Err(_) => println!("无效的 uin: {}", uin),
+            },
+            (Some(":group"), Some(group)) => match group.parse() {
+                Ok(group) => {
+                    self.group_uin = group;
+                    self.print_who();
+                }
+                Err(_) => println!("无效的群号: {}", group),
+            },
+            (Some(":who"), None) => self.print_who(),
+            (Some(":quit"), None) => return false,
+            _ => println!("{}", HELP),
+        }
+        true
+    }
+
+    fn print_who(&self) {
+        println!(
+            "当前: {} ({}) @ 群 {}",
+            self.name(),
+            self.uin,
+            self.group_uin
+        );
+    }
+
+    fn message(&self, message_id: i64, line: &str) -> IncomingMessage {
+        let mut texts = Vec::new();
+        let mut mentions = Vec::new();
+        for word in line.split_whitespace() {
+            match word.strip_prefix('@').and_then(|uin| uin.parse().ok()) {
+                Some(uin) => mentions.push(uin),
+                None => texts.push(word),
+            }
+        }
+        IncomingMessage {
+            message_id,
+            group_uin: self.group_uin,
+            sender: GroupMember {
+                uid: format!("console:{}", self.uin),
+                uin: self.uin,
+                member_name: Some(self.name().to_string()),
+                member_card: None,
+            },
+            // reuse the member record of this uin, e.g. one from a copy of the
+            // bot's database in CONSOLE_DB
+            placeholder_uid: true,
+            texts: vec![texts.join(" ")],
+            mentions,
+        }
+    }
+}
+
+pub struct ConsoleAdapter {
+    svc: Service,
+}
+
+impl MessageSink for ConsoleAdapter {
+    async fn send(&self, msg: &OutgoingMessage) -> Result<(), String> {
+        let mention = msg
+            .mention
+            .as_ref()
+            .map(|m| format!("@{} ", m.name))
+            .unwrap_or_default();
+        println!("[群 {}] 机器人: {}{}", msg.group_uin, mention, msg.text);
+        Ok(())
+    }
+}
+
+impl ChatAdapter for ConsoleAdapter {
+    async fn run(
+        self: Arc<Self>,
+        incoming: mpsc::UnboundedSender<IncomingMessage>,
+        mut shutdown: ShutdownSignal,
+    ) {
+        // stdin is read on a plain thread: a blocking read would otherwise
+        // keep the runtime from shutting down
+        let (line_tx, mut lines) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+                if line_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        self.svc.set_bot_state(BotState::Online, None);
+        let mut session = Session::from_env();
+        session.print_who();
+        let mut message_id = 0;
+        loop {
+            let line = tokio::select! {
+                line = lines.recv() => line,
+                _ = shutdown.recv() => break,
+            };
+            let Some(line) = line else { break };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with(':') {
+                if !session.command(line) {
+                    break;
+                }
+                continue;
+            }
+            message_id += 1;
+            self.svc.mark_bot_event();
+            let _ = incoming.send(session.message(message_id, line));
+        }
+        self.svc.set_bot_state(BotState::Offline, None);
+    }
+}
+
+/// The service the console runs on, see the module docs.
+pub fn init_service() -> Service {
+    let Some(path) = std::env::var("CONSOLE_DB").ok().filter(|v| !v.is_empty()) else {
+        return service::init_in_memory(Clock::default(), admin_uins());
+    };
+    let same_file = match (
+        std::fs::canonicalize(&path),
+        std::fs::canonicalize(service::DB_PATH),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => Path::new(&path) == Path::new(service::DB_PATH),
+    };
+    if same_file {
+        panic!(
+            "CONSOLE_DB must not be the bot's database {}",
+            service::DB_PATH
+        );
+    }
+    service::open_service(&path)
+}
+
+/// Run the bot on the console until stdin is closed or shutdown.
+pub async fn run(svc: Service, shutdown: ShutdownSignal) {
+    let adapter = Arc::new(ConsoleAdapter { svc: svc.clone() });
+    bot::run_adapter(svc, adapter, shutdown).await;
+}
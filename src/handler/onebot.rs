@@ -0,0 +1,524 @@
+//! Bot adapter for OneBot v11 implementations such as NapCat or
+//! Lagrange.OneBot, connected through their forward WebSocket server.
+//!
+//! `ONEBOT_WS_URL` is the WebSocket address (`ws://127.0.0.1:3001` by
+//! default) and `ONEBOT_ACCESS_TOKEN` the optional access token.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use regex::Regex;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use super::bot::{self, ChatAdapter, IncomingMessage};
+use super::outbox::{MessageSink, OutgoingMessage};
+use crate::service::Service;
+use crate::service::models::GroupMember;
+use crate::service::status::BotState;
+use crate::shutdown::ShutdownSignal;
+
+/// How long to wait for the response to an action.
+const ACTION_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_BASE: Duration = Duration::from_secs(2);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+static CQ_CODE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\[CQ:([^,\]]+)((?:,[^\]]*)?)\]").unwrap());
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct OneBotAdapter {
+    svc: Service,
+    url: String,
+    access_token: Option<String>,
+    /// Outgoing frames of the current connection, `None` while disconnected
+    writer: Mutex<Option<mpsc::UnboundedSender<Message>>>,
+    /// Actions waiting for their response, by `echo`
+    pending: Mutex<HashMap<String, oneshot::Sender<Value>>>,
+    next_echo: AtomicU64,
+    closed: Notify,
+}
+
+impl OneBotAdapter {
+    pub fn from_env(svc: Service) -> Self {
+        let url = std::env::var("ONEBOT_WS_URL")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "ws://127.0.0.1:3001".to_string());
+        let access_token = std::env::var("ONEBOT_ACCESS_TOKEN")
+            .ok()
+            .filter(|v| !v.is_empty());
+        Self::new(svc, url, access_token)
+    }
+
+    fn new(svc: Service, url: String, access_token: Option<String>) -> Self {
+        Self {
+            svc,
+            url,
+            access_token,
+            writer: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+            next_echo: AtomicU64::new(1),
+            closed: Notify::new(),
+        }
+    }
+
+    async fn connect(&self) -> Result<WsStream, String> {
+        let mut request = self
+            .url
+            .as_str()
+            .into_client_request()
+            .map_err(|e| e.to_string())?;
+        if let Some(token) = &self.access_token {
+            let value = format!("Bearer {}", token)
+                .parse()
+                .map_err(|e| format!("invalid access token: {e}"))?;
+            request.headers_mut().insert("Authorization", value);
+        }
+        let (ws, _) = tokio_tungstenite::connect_async(request)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(ws)
+    }
+
+    /// Serve one connection until it drops or the adapter is closed. Returns
+    /// true when closed.
+    async fn serve(
+        &self,
+        ws: WsStream,
+        incoming: &mut Option<mpsc::UnboundedSender<IncomingMessage>>,
+        shutdown: &mut ShutdownSignal,
+    ) -> bool {
+        let (mut write, mut read) = ws.split();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+        *self.writer.lock().unwrap() = Some(tx);
+        let write_task = tokio::spawn(async move {
+            while let Some(frame) = rx.recv().await {
+                if let Err(e) = write.send(frame).await {
+                    tracing::warn!("OneBot write failed: {}", e);
+                    break;
+                }
+            }
+            let _ = write.close().await;
+        });
+
+        let closed = loop {
+            tokio::select! {
+                // stop forwarding messages, but keep the connection for the last replies
+                _ = shutdown.recv(), if incoming.is_some() => *incoming = None,
+                _ = self.closed.notified() => break true,
+                frame = read.next() => match frame {
+                    Some(Ok(Message::Text(text))) => self.handle_frame(&text, incoming.as_ref()),
+                    Some(Ok(Message::Close(_))) | None => break false,
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => {
+                        tracing::warn!("OneBot connection error: {}", e);
+                        break false;
+                    }
+                },
+            }
+        };
+
+        // dropping the sender ends the write task, and pending actions fail
+        *self.writer.lock().unwrap() = None;
+        self.pending.lock().unwrap().clear();
+        let _ = write_task.await;
+        closed
+    }
+
+    fn handle_frame(&self, text: &str, incoming: Option<&mpsc::UnboundedSender<IncomingMessage>>) {
+        let frame: Value = match serde_json::from_str(text) {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::warn!("Invalid OneBot frame: {}", e);
+                return;
+            }
+        };
+        // responses to our actions carry the echo we sent
+        if let Some(echo) = frame.get("echo").filter(|e| !e.is_null()) {
+            let echo = echo
+                .as_str()
+                .map(str::to_string)
+                .unwrap_or_else(|| echo.to_string());
+            if let Some(tx) = self.pending.lock().unwrap().remove(&echo) {
+                let _ = tx.send(frame);
+            }
+            return;
+        }
+        self.svc.mark_bot_event();
+        match frame["post_type"].as_str() {
+            Some("message") if frame["message_type"] == "group" => {
+                tracing::debug!("[OneBot] {}", text);
+                match (to_incoming(&frame), incoming) {
+                    (Some(msg), Some(incoming)) => {
+                        let _ = incoming.send(msg);
+                    }
+                    (Some(_), None) => tracing::debug!("Shutting down, ignoring message"),
+                    (None, _) => tracing::warn!("Unrecognized OneBot group message: {}", text),
+                }
+            }
+            Some("meta_event") => {}
+            _ => tracing::debug!("[OneBot] {}", text),
+        }
+    }
+}
+
+fn to_incoming(frame: &Value) -> Option<IncomingMessage> {
+    let group_uin = u32::try_from(frame["group_id"].as_u64()?).ok()?;
+    let uin = u32::try_from(frame["user_id"].as_u64()?).ok()?;
+    let sender = &frame["sender"];
+    let non_empty = |v: &Value| v.as_str().filter(|s| !s.is_empty()).map(str::to_string);
+    let (texts, mentions) = match &frame["message"] {
+        Value::Array(segments) => parse_segments(segments),
+        Value::String(s) => parse_cq_string(s),
+        _ => return None,
+    };
+    Some(IncomingMessage {
+        message_id: frame["message_id"].as_i64()?,
+        group_uin,
+        sender: GroupMember {
+            // members are stored by uid, which OneBot does not expose
+            uid: format!("onebot:{}", uin),
+            uin,
+            member_name: non_empty(&sender["nickname"]),
+            member_card: non_empty(&sender["card"]),
+        },
+        placeholder_uid: true,
+        texts,
+        mentions,
+    })
+}
+
+fn parse_segments(segments: &[Value]) -> (Vec<String>, Vec<u32>) {
+    let mut texts = Vec::new();
+    let mut mentions = Vec::new();
+    for segment in segments {
+        let data = &segment["data"];
+        match segment["type"].as_str() {
+            Some("text") => texts.extend(data["text"].as_str().map(str::to_string)),
+            Some("at") => mentions.extend(
+                data["qq"]
+                    .as_str()
+                    .and_then(|q| q.parse().ok())
+                    .or_else(|| data["qq"].as_u64().and_then(|q| u32::try_from(q).ok())),
+            ),
+            _ => {}
+        }
+    }
+    (texts, mentions)
+}
+
+fn unescape_cq(text: &str) -> String {
+    text.replace("&#91;", "[")
+        .replace("&#93;", "]")
+        .replace("&#44;", ",")
+        .replace("&amp;", "&")
+}
+
+/// Messages in the string format: plain text with `[CQ:type,key=value]` codes.
+fn parse_cq_string(message: &str) -> (Vec<String>, Vec<u32>) {
+    let mut texts = Vec::new();
+    let mut mentions = Vec::new();
+    let mut last = 0;
+    for caps in CQ_CODE.captures_iter(message) {
+        let whole = caps.get(0).unwrap();
+        if whole.start() > last {
+            texts.push(unescape_cq(&message[last..whole.start()]));
+        }
+        last = whole.end();
+        if &caps[1] == "at"
+            && let Some(qq) = caps[2]
+                .split(',')
+                .find_map(|kv| kv.strip_prefix("qq="))
+                .and_then(|q| q.parse().ok())
+        {
+            mentions.push(qq);
+        }
+    }
+    if last < message.len() {
+        texts.push(unescape_cq(&message[last..]));
+    }
+    (texts, mentions)
+}
+
+fn message_segments(msg: &OutgoingMessage) -> Value {
+    let mut segments = Vec::new();
+    if let Some(id) = msg.reply_to {
+        segments.push(serde_json::json!({"type": "reply", "data": {"id": id.to_string()}}));
+    }
+    let text = match &msg.mention {
+        Some(target) => {
+            segments
+                .push(serde_json::json!({"type": "at", "data": {"qq": target.uin.to_string()}}));
+            format!(" {}", msg.text)
+        }
+        None => msg.text.clone(),
+    };
+    segments.push(serde_json::json!({"type": "text", "data": {"text": text}}));
+    Value::Array(segments)
+}
+
+impl MessageSink for OneBotAdapter {
+    async fn send(&self, msg: &OutgoingMessage) -> Result<(), String> {
+        let echo = self.next_echo.fetch_add(1, Ordering::Relaxed).to_string();
+        let frame = serde_json::json!({
+            "action": "send_group_msg",
+            "params": {"group_id": msg.group_uin, "message": message_segments(msg)},
+            "echo": echo,
+        });
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(echo.clone(), tx);
+        let sent = self
+            .writer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|w| w.send(Message::text(frame.to_string())).is_ok());
+        if !sent {
+            self.pending.lock().unwrap().remove(&echo);
+            return Err("not connected".to_string());
+        }
+        match tokio::time::timeout(ACTION_TIMEOUT, rx).await {
+            Ok(Ok(resp)) if resp["retcode"].as_i64() == Some(0) => Ok(()),
+            Ok(Ok(resp)) => Err(format!(
+                "retcode {}: {}",
+                resp["retcode"],
+                resp["wording"]
+                    .as_str()
+                    .or(resp["message"].as_str())
+                    .unwrap_or_default()
+            )),
+            Ok(Err(_)) => Err("connection closed".to_string()),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&echo);
+                Err("timed out".to_string())
+            }
+        }
+    }
+}
+
+impl ChatAdapter for OneBotAdapter {
+    async fn run(
+        self: Arc<Self>,
+        incoming: mpsc::UnboundedSender<IncomingMessage>,
+        mut shutdown: ShutdownSignal,
+    ) {
+        let mut incoming = Some(incoming);
+        let mut failures = 0u32;
+        // after shutdown there is no point in reconnecting
+        while incoming.is_some() {
+            let connected = tokio::select! {
+                res = self.connect() => res,
+                _ = shutdown.recv() => return,
+            };
+            let delay = match connected {
+                Ok(ws) => {
+                    tracing::info!("Connected to OneBot at {}", self.url);
+                    failures = 0;
+                    self.svc.set_bot_state(BotState::Online, None);
+                    if self.serve(ws, &mut incoming, &mut shutdown).await {
+                        return;
+                    }
+                    tracing::warn!("OneBot connection closed");
+                    self.svc
+                        .set_bot_state(BotState::Offline, Some("connection closed".to_string()));
+                    RETRY_BASE
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to connect to OneBot at {}: {}", self.url, e);
+                    failures += 1;
+                    self.svc
+                        .set_bot_state(BotState::Offline, Some(format!("connect: {e}")));
+                    (RETRY_BASE * 2u32.pow(failures.saturating_sub(1).min(10))).min(RETRY_MAX)
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.recv() => return,
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.closed.notify_one();
+    }
+}
+
+pub async fn run(svc: Service, shutdown: ShutdownSignal) {
+    let adapter = Arc::new(OneBotAdapter::from_env(svc.clone()));
+    bot::run_adapter(svc, adapter, shutdown).await;
+    tracing::info!("Bot stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::service::clock::Clock;
+    use crate::service::init_in_memory;
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    fn adapter(url: String) -> Arc<OneBotAdapter> {
+        let svc = init_in_memory(Clock::default(), HashSet::new());
+        Arc::new(OneBotAdapter::new(svc, url, None))
+    }
+
+    fn group_message(message_id: i64, message: Value) -> String {
+        serde_json::json!({
+            "post_type": "message",
+            "message_type": "group",
+            "message_id": message_id,
+            "group_id": 1,
+            "user_id": 10001,
+            "sender": {"nickname": "Alice", "card": ""},
+            "message": message,
+        })
+        .to_string()
+    }
+
+    async fn accept(listener: &TcpListener) -> WebSocketStream<TcpStream> {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio_tungstenite::accept_async(stream).await.unwrap()
+    }
+
+    #[test]
+    fn cq_string_is_unescaped_and_mentions_read() {
+        let (texts, mentions) = parse_cq_string("[CQ:at,qq=10002] /打卡 &#91;早&#93;&#44;&amp;");
+        assert_eq!(texts, vec![" /打卡 [早],&"]);
+        assert_eq!(mentions, vec![10002]);
+    }
+
+    #[test]
+    fn cq_string_skips_other_codes_and_at_all() {
+        let (texts, mentions) =
+            parse_cq_string("[CQ:reply,id=5][CQ:at,qq=all] 起床[CQ:face,id=1]了");
+        assert_eq!(texts, vec![" 起床", "了"]);
+        assert!(mentions.is_empty());
+    }
+
+    #[test]
+    fn segments_read_text_and_mentions() {
+        let segments = serde_json::json!([
+            {"type": "at", "data": {"qq": "10002"}},
+            {"type": "text", "data": {"text": " /咕"}},
+            {"type": "at", "data": {"qq": 10003}},
+            {"type": "at", "data": {"qq": "all"}},
+            {"type": "image", "data": {"file": "a.png"}},
+        ]);
+        let (texts, mentions) = parse_segments(segments.as_array().unwrap());
+        assert_eq!(texts, vec![" /咕"]);
+        assert_eq!(mentions, vec![10002, 10003]);
+    }
+
+    #[test]
+    fn group_message_gets_a_placeholder_uid() {
+        let frame: Value = serde_json::from_str(&group_message(3, "/打卡".into())).unwrap();
+        let msg = to_incoming(&frame).unwrap();
+        assert_eq!(msg.message_id, 3);
+        assert_eq!(msg.sender.uid, "onebot:10001");
+        assert!(msg.placeholder_uid);
+        assert_eq!(msg.sender.member_name.as_deref(), Some("Alice"));
+        assert_eq!(msg.sender.member_card, None);
+    }
+
+    #[test]
+    fn responses_are_matched_by_echo() {
+        let adapter = adapter(String::new());
+        let (tx_text, mut rx_text) = oneshot::channel();
+        let (tx_number, mut rx_number) = oneshot::channel();
+        adapter
+            .pending
+            .lock()
+            .unwrap()
+            .insert("7".to_string(), tx_text);
+        adapter
+            .pending
+            .lock()
+            .unwrap()
+            .insert("8".to_string(), tx_number);
+
+        adapter.handle_frame(r#"{"retcode": 0, "echo": "8"}"#, None);
+        adapter.handle_frame(r#"{"retcode": 1, "echo": 9}"#, None);
+        assert_eq!(rx_number.try_recv().unwrap()["retcode"], 0);
+        assert!(rx_text.try_recv().is_err());
+        assert_eq!(adapter.pending.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn adapter_talks_to_a_onebot_server_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let adapter = adapter(format!("ws://{}", listener.local_addr().unwrap()));
+        let (shutdown_tx, shutdown) = crate::shutdown::channel();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let run = tokio::spawn(adapter.clone().run(tx, shutdown));
+
+        // events are passed on
+        let mut server = accept(&listener).await;
+        let message = serde_json::json!([
+            {"type": "text", "data": {"text": "/打卡"}},
+        ]);
+        server
+            .send(Message::text(group_message(1, message)))
+            .await
+            .unwrap();
+        let msg = tokio::time::timeout(WAIT, rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.texts, vec!["/打卡"]);
+
+        // an action is answered through its echo
+        let reply = OutgoingMessage {
+            group_uin: 1,
+            mention: None,
+            reply_to: Some(1),
+            text: "3/1".to_string(),
+        };
+        let (sent, ()) = tokio::join!(adapter.send(&reply), async {
+            let Some(Ok(Message::Text(frame))) = server.next().await else {
+                panic!("expected an action");
+            };
+            let action: Value = serde_json::from_str(&frame).unwrap();
+            assert_eq!(action["action"], "send_group_msg");
+            assert_eq!(action["params"]["message"][0]["type"], "reply");
+            let response =
+                serde_json::json!({"status": "ok", "retcode": 0, "echo": action["echo"]});
+            server
+                .send(Message::text(response.to_string()))
+                .await
+                .unwrap();
+        });
+        assert_eq!(sent, Ok(()));
+
+        // a dropped connection is made again
+        drop(server);
+        let mut server = tokio::time::timeout(WAIT, accept(&listener)).await.unwrap();
+        server
+            .send(Message::text(group_message(2, "/今日".into())))
+            .await
+            .unwrap();
+        let msg = tokio::time::timeout(WAIT, rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(msg.message_id, 2);
+
+        // shutdown stops passing on events, close ends the adapter
+        let _ = shutdown_tx.send(true);
+        adapter.close();
+        tokio::time::timeout(WAIT, run).await.unwrap().unwrap();
+        assert!(rx.recv().await.is_none());
+    }
+}
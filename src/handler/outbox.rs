@@ -0,0 +1,182 @@
+//! Outbound message queue of the bot. Replies are queued per group, sent no
+//! faster than the group rate limit allows, retried with backoff on failure,
+//! and split into several messages when the text is too long.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+
+use crate::metrics;
+
+/// Longest text sent in a single message, in characters.
+const MAX_MESSAGE_CHARS: usize = 1500;
+const MAX_SEND_ATTEMPTS: u32 = 5;
+const SEND_RETRY_BASE: Duration = Duration::from_secs(1);
+
+/// Member to mention at the start of a reply.
+#[derive(Debug, Clone)]
+pub struct MentionTarget {
+    pub uid: String,
+    pub uin: u32,
+    pub name: String,
+}
+
+/// A reply of the bot, independent of the chat platform.
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    pub group_uin: u32,
+    pub mention: Option<MentionTarget>,
+    /// Platform id of the message to quote, where the platform supports it
+    pub reply_to: Option<i64>,
+    pub text: String,
+}
+
+/// Something that can deliver a single message to a group.
+pub trait MessageSink: Send + Sync + 'static {
+    fn send(&self, msg: &OutgoingMessage) -> impl Future<Output = Result<(), String>> + Send;
+}
+
+/// Minimum time between two messages to the same group.
+/// `BOT_SEND_INTERVAL_MS`, 1000 by default.
+fn group_send_interval() -> Duration {
+    let ms = std::env::var("BOT_SEND_INTERVAL_MS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(1000);
+    Duration::from_millis(ms)
+}
+
+/// Split `text` into chunks of at most `max_chars` characters, preferring
+/// line breaks as split points.
+fn split_text(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    let mut current_len = 0;
+    for line in text.split('\n') {
+        let line_len = line.chars().count();
+        // +1 for the line break that joins it to the current chunk
+        if current_len > 0 && current_len + 1 + line_len > max_chars {
+            chunks.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        if line_len > max_chars {
+            let chars: Vec<char> = line.chars().collect();
+            for piece in chars.chunks(max_chars) {
+                if current_len > 0 {
+                    chunks.push(std::mem::take(&mut current));
+                }
+                current = piece.iter().collect();
+                current_len = piece.len();
+            }
+            continue;
+        }
+        if current_len > 0 {
+            current.push('\n');
+            current_len += 1;
+        }
+        current.push_str(line);
+        current_len += line_len;
+    }
+    if current_len > 0 || chunks.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Handle for queueing messages. The queue shuts down, after sending what it
+/// holds, once every handle has been dropped.
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::UnboundedSender<OutgoingMessage>,
+}
+
+impl Outbox {
+    pub fn start<S: MessageSink>(sink: Arc<S>) -> (Outbox, JoinHandle<()>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = tokio::spawn(run(sink, rx));
+        (Outbox { tx }, handle)
+    }
+
+    pub fn send(&self, msg: OutgoingMessage) {
+        if self.tx.send(msg).is_err() {
+            tracing::error!("Outbox is closed, dropping message");
+        }
+    }
+}
+
+async fn run<S: MessageSink>(sink: Arc<S>, mut rx: mpsc::UnboundedReceiver<OutgoingMessage>) {
+    let interval = group_send_interval();
+    let mut groups: HashMap<u32, mpsc::UnboundedSender<OutgoingMessage>> = HashMap::new();
+    let mut tasks = JoinSet::new();
+    while let Some(msg) = rx.recv().await {
+        let tx = groups.entry(msg.group_uin).or_insert_with(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tasks.spawn(run_group(sink.clone(), rx, interval));
+            tx
+        });
+        let _ = tx.send(msg);
+    }
+    drop(groups);
+    while let Some(res) = tasks.join_next().await {
+        if let Err(e) = res {
+            tracing::error!("Outbox group worker failed: {:?}", e);
+        }
+    }
+}
+
+/// Send the messages of one group in order, spaced by `interval`.
+async fn run_group<S: MessageSink>(
+    sink: Arc<S>,
+    mut rx: mpsc::UnboundedReceiver<OutgoingMessage>,
+    interval: Duration,
+) {
+    let mut next_send = Instant::now();
+    while let Some(msg) = rx.recv().await {
+        let parts = split_text(&msg.text, MAX_MESSAGE_CHARS);
+        for (index, text) in parts.into_iter().enumerate() {
+            let part = OutgoingMessage {
+                group_uin: msg.group_uin,
+                // only the first part mentions the member
+                mention: msg.mention.clone().filter(|_| index == 0),
+                reply_to: msg.reply_to.filter(|_| index == 0),
+                text,
+            };
+            tokio::time::sleep_until(next_send).await;
+            send_with_retry(sink.as_ref(), &part).await;
+            next_send = Instant::now() + interval;
+        }
+    }
+}
+
+async fn send_with_retry<S: MessageSink>(sink: &S, msg: &OutgoingMessage) {
+    for attempt in 1..=MAX_SEND_ATTEMPTS {
+        match sink.send(msg).await {
+            Ok(()) => {
+                metrics::MESSAGES_SENT.inc();
+                return;
+            }
+            Err(e) => {
+                metrics::MESSAGE_SEND_FAILURES.inc();
+                tracing::warn!(
+                    "Failed to send message to group {} (attempt {}/{}): {}",
+                    msg.group_uin,
+                    attempt,
+                    MAX_SEND_ATTEMPTS,
+                    e
+                );
+            }
+        }
+        if attempt < MAX_SEND_ATTEMPTS {
+            tokio::time::sleep(SEND_RETRY_BASE * 2u32.pow(attempt - 1)).await;
+        }
+    }
+    tracing::error!(
+        "Giving up on message to group {}: {:?}",
+        msg.group_uin,
+        msg.text
+    );
+}
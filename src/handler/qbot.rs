@@ -1,16 +1,44 @@
+//! Bot adapter for mania, which speaks the QQ protocol itself. It also owns
+//! the session: login, reconnects and the key store.
+//!
+//! mania hands group events over a `watch` channel, which holds only the
+//! latest one. Messages are queued and deduplicated from the moment this
+//! adapter takes them out, see [`bot::run_adapter`], but one that mania
+//! replaces before that is lost, and there is no way to tell from here. Use
+//! the OneBot adapter (`BOT_PLATFORM=onebot`) where every message counts.
+
 use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
 
 use mania::entity::bot_group_member::BotGroupMember;
 use mania::event::group::GroupEvent;
 use mania::event::group::group_message::GroupMessageEvent;
+use mania::event::system::SystemEvent;
 use mania::message::builder::MessageChainBuilder;
-use mania::message::chain::{GroupMessageUniqueElem, MessageChain, MessageType};
+use mania::message::chain::{GroupMessageUniqueElem, MessageType};
 use mania::message::entity::{Entity, Mention};
-use mania::{Client, ClientConfig, DeviceInfo, KeyStore};
-use tracing::debug;
+use mania::{Client, ClientConfig, DeviceInfo, KeyStore, Operator};
+use tokio::sync::{Notify, mpsc};
 
+use super::bot::{self, ChatAdapter, IncomingMessage};
+use super::outbox::{MessageSink, OutgoingMessage};
 use crate::service::Service;
 use crate::service::models::GroupMember;
+use crate::service::status::{BotState, QrState};
+use crate::shutdown::ShutdownSignal;
+
+const KEYSTORE_PATH: &str = "keystore.json";
+/// How long a session lasts after it was established; past this, mania
+/// reports the key store as expired.
+const SESSION_LIFETIME: chrono::Duration = chrono::Duration::days(15);
+/// How often the key store is checked for changes while online.
+const KEYSTORE_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// A QR code that is not confirmed within this time is replaced.
+const QR_LIFETIME: Duration = Duration::from_secs(120);
+const QR_MAX_REFRESHES: u32 = 10;
+const RETRY_BASE: Duration = Duration::from_secs(5);
+const RETRY_MAX: Duration = Duration::from_secs(5 * 60);
 
 fn bot_member_to_group_member(b: &BotGroupMember) -> GroupMember {
     GroupMember {
@@ -21,7 +49,7 @@ fn bot_member_to_group_member(b: &BotGroupMember) -> GroupMember {
     }
 }
 
-fn handle_group_msg(svc: &Service, ev: &GroupMessageEvent) -> Option<MessageChain> {
+fn to_incoming(ev: &GroupMessageEvent) -> Option<IncomingMessage> {
     let MessageType::Group(GroupMessageUniqueElem {
         group_uin,
         group_member_info: Some(group_member_info),
@@ -29,106 +57,108 @@ fn handle_group_msg(svc: &Service, ev: &GroupMessageEvent) -> Option<MessageChai
     else {
         return None;
     };
-    debug!("group_member_info: {:?}", group_member_info);
-
-    let text_entities = ev.chain.entities.iter().filter_map(|e| {
-        if let Entity::Text(te) = e {
-            Some(te.text.trim())
-        } else {
-            None
+    let mut texts = Vec::new();
+    let mut mentions = Vec::new();
+    for entity in &ev.chain.entities {
+        match entity {
+            Entity::Text(te) => texts.push(te.text.clone()),
+            Entity::Mention(m) => mentions.push(m.uin),
+            _ => {}
         }
-    });
-    let first_text = text_entities
-        .filter(|te| !te.is_empty())
-        .next()
-        .unwrap_or_default();
-    let (command, args) = first_text.split_once(' ').unwrap_or((first_text, ""));
-
-    let gm = bot_member_to_group_member(group_member_info);
-    match command {
-        "/打卡" => {
-            debug!("Handling 我没打卡 command for user {}", gm.uin);
-            match svc.upsert_member(&gm) {
-                Ok(user_id) => {
-                    let res = svc.handle_打卡(user_id, args);
-                    tracing::debug!("Service handle_打卡 ok={} message={}", res.ok, res.message);
-                    let mut chain = MessageChainBuilder::group(*group_uin)
-                        .text(" ")
-                        .text(&res.message)
-                        .build();
-                    chain.entities.insert(
-                        0,
-                        Entity::Mention(Mention {
-                            uid: gm.uid.clone().into(),
-                            name: Some(format!("@{}", gm.group_nickname())),
-                            uin: gm.uin,
-                        }),
-                    );
-                    Some(chain)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to upsert member: {:?}", e);
-                    Some(
-                        MessageChainBuilder::group(*group_uin)
-                            .text(&e.message)
-                            .build(),
-                    )
-                }
-            }
+    }
+    Some(IncomingMessage {
+        message_id: i64::from(ev.chain.sequence),
+        group_uin: *group_uin,
+        sender: bot_member_to_group_member(group_member_info),
+        placeholder_uid: false,
+        texts,
+        mentions,
+    })
+}
+
+struct ManiaAdapter {
+    svc: Service,
+    op: Arc<Operator>,
+    /// Signalled when mania reports the bot went offline
+    offline: Notify,
+}
+
+/// Mentions become a mention entity followed by the text. Quoting is not
+/// supported through this adapter.
+impl MessageSink for ManiaAdapter {
+    async fn send(&self, msg: &OutgoingMessage) -> Result<(), String> {
+        let mut chain = match &msg.mention {
+            Some(_) => MessageChainBuilder::group(msg.group_uin)
+                .text(" ")
+                .text(&msg.text)
+                .build(),
+            None => MessageChainBuilder::group(msg.group_uin)
+                .text(&msg.text)
+                .build(),
+        };
+        if let Some(target) = &msg.mention {
+            chain.entities.insert(
+                0,
+                Entity::Mention(Mention {
+                    uid: target.uid.clone().into(),
+                    name: Some(format!("@{}", target.name)),
+                    uin: target.uin,
+                }),
+            );
         }
-        "/我没打卡" => {
-            debug!("Handling 我没打卡 command for user {}", gm.uin);
-            match svc.upsert_member(&gm) {
-                Ok(user_id) => {
-                    let res = svc.handle_我没打卡(user_id, args);
-                    tracing::debug!(
-                        "Service handle_我没打卡 ok={} message={}",
-                        res.ok,
-                        res.message
-                    );
-                    let mut chain = MessageChainBuilder::group(*group_uin)
-                        .text(" ")
-                        .text(&res.message)
-                        .build();
-                    chain.entities.insert(
-                        0,
-                        Entity::Mention(Mention {
-                            uid: gm.uid.clone().into(),
-                            name: Some(format!("@{}", gm.group_nickname())),
-                            uin: gm.uin,
-                        }),
-                    );
-                    Some(chain)
+        tracing::debug!("Sending message chain: {:?}", chain);
+        self.op
+            .send_message(chain)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl ChatAdapter for ManiaAdapter {
+    async fn run(
+        self: Arc<Self>,
+        incoming: mpsc::UnboundedSender<IncomingMessage>,
+        mut shutdown: ShutdownSignal,
+    ) {
+        let mut group_receiver = self.op.event_listener.group.clone();
+        let mut system_receiver = self.op.event_listener.system.clone();
+        loop {
+            tokio::select! {
+                // stop taking new messages
+                _ = shutdown.recv() => break,
+                _ = system_receiver.changed() => {
+                    self.svc.mark_bot_event();
+                    if let Some(ref se) = *system_receiver.borrow() {
+                        tracing::info!("[SystemEvent] {:?}", se);
+                        if let SystemEvent::BotOffline(ev) = se {
+                            tracing::warn!("Bot offline: {} {}", ev.tag, ev.message);
+                            // only a session that is waiting is ended; none is
+                            // while reconnecting
+                            self.offline.notify_waiters();
+                        }
+                    }
                 }
-                Err(e) => {
-                    tracing::error!("Failed to upsert member: {:?}", e);
-                    Some(
-                        MessageChainBuilder::group(*group_uin)
-                            .text(&e.message)
-                            .build(),
-                    )
+                _ = group_receiver.changed() => {
+                    self.svc.mark_bot_event();
+                    // take the event out right away: one that arrives before
+                    // this replaces it, see the module docs
+                    let event = group_receiver.borrow_and_update().clone();
+                    if let Some(ge) = event {
+                        tracing::debug!("[GroupEvent] {:?}", ge);
+                        if let GroupEvent::GroupMessage(gme) = ge
+                            && let Some(msg) = to_incoming(&gme)
+                        {
+                            let _ = incoming.send(msg);
+                        }
+                    }
                 }
             }
         }
-        "/今日" => {
-            let report = svc.build_daily_report();
-            Some(MessageChainBuilder::group(*group_uin).text(&report).build())
-        }
-        "/咕" => {
-            let res = svc.handle_咕(*group_uin, &gm, args);
-            tracing::debug!("Service handle_咕 ok={} message={}", res.ok, res.message);
-            Some(
-                MessageChainBuilder::group(*group_uin)
-                    .text(" ")
-                    .text(&res.message)
-                    .build(),
-            )
-        }
-        _ => None,
     }
 }
 
-pub async fn run(svc: Service) {
+pub async fn run(svc: Service, shutdown: ShutdownSignal) {
     let config = ClientConfig::default();
     let device = DeviceInfo::load("device.json").unwrap_or_else(|_| {
         tracing::warn!("Failed to load device info, generating a new one...");
@@ -136,52 +166,34 @@ pub async fn run(svc: Service) {
         device.save("device.json").unwrap();
         device
     });
-    let key_store = KeyStore::load("keystore.json").unwrap_or_else(|_| {
+    let key_store = KeyStore::load(KEYSTORE_PATH).unwrap_or_else(|_| {
         tracing::warn!("Failed to load keystore, generating a new one...");
         let key_store = KeyStore::default();
-        key_store.save("keystore.json").unwrap();
+        key_store.save(KEYSTORE_PATH).unwrap();
         key_store
     });
     let need_login = key_store.is_expired();
-    let mut client = Client::new(config, device, key_store).await.unwrap();
-
-    let op = client.handle().operator().clone();
-    let send_op = client.handle().operator().clone();
-    let mut group_receiver = op.event_listener.group.clone();
-    let mut system_receiver = op.event_listener.system.clone();
-
-    tokio::spawn(async move {
-        loop {
-            let mut reply = None;
-            tokio::select! {
-                _ = system_receiver.changed() => {
-                    if let Some(ref se) = *system_receiver.borrow() {
-                        tracing::info!("[SystemEvent] {:?}", se);
-                    }
-                }
-                _ = group_receiver.changed() => {
-                    let guard = group_receiver.borrow();
-                    if let Some(ref ge) = *guard {
-                        tracing::debug!("[GroupEvent] {:?}", ge);
-                        match ge {
-                            GroupEvent::GroupMessage(gme) => {
-                                if let mania::message::chain::MessageType::Group(_gmeu) = &gme.chain.typ {
-                                    reply = handle_group_msg(&svc, gme);
-                                }
-                            }
-                            _ => {},
-                        }
-                    }
-                }
-            }
-            if let Some(chain) = reply {
-                tracing::debug!("Replying with message chain: {:?}", chain);
-                if let Err(e) = send_op.send_message(chain).await {
-                    tracing::error!("Failed to send message: {:?}", e);
-                }
-            }
+    svc.set_bot_state(BotState::Starting, None);
+    svc.set_keystore_status(need_login, keystore_expires_at(), false);
+    let mut client = match Client::new(config, device, key_store).await {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Failed to create client: {e:?}");
+            svc.set_bot_state(BotState::Failed, Some(format!("client: {e}")));
+            return;
         }
+    };
+
+    let adapter = Arc::new(ManiaAdapter {
+        svc: svc.clone(),
+        op: client.handle().operator().clone(),
+        offline: Notify::new(),
     });
+    let pipeline = tokio::spawn(bot::run_adapter(
+        svc.clone(),
+        adapter.clone(),
+        shutdown.clone(),
+    ));
 
     tokio::spawn(async move {
         client.spawn().await;
@@ -189,41 +201,162 @@ pub async fn run(svc: Service) {
 
     if need_login {
         tracing::warn!("Session is invalid, need to login again!");
-        let login_res: Result<(), String> = async {
-            let (url, bytes) = op.fetch_qrcode().await.map_err(|e| e.to_string())?;
-            let qr_code_name = format!("qrcode.png");
-            fs::write(&qr_code_name, &bytes).map_err(|e| e.to_string())?;
-            tracing::info!(
-                "QR code fetched successfully! url: {}, saved to {}",
-                url,
-                qr_code_name
-            );
-            let login_res = op.login_by_qrcode().await.map_err(|e| e.to_string());
-            match fs::remove_file(&qr_code_name).map_err(|e| e.to_string()) {
-                Ok(_) => tracing::info!("QR code file {} deleted successfully", qr_code_name),
-                Err(e) => tracing::error!("Failed to delete QR code file {}: {}", qr_code_name, e),
+    } else {
+        tracing::info!("Session is still valid, trying to online...");
+    }
+    let mut shutdown = shutdown;
+    tokio::select! {
+        _ = supervise(&svc, &adapter.op, &adapter.offline, need_login) => {}
+        _ = shutdown.recv() => {}
+    }
+    if let Err(e) = pipeline.await {
+        tracing::error!("Bot message pipeline failed: {:?}", e);
+    }
+    persist_key_store(&svc, &adapter.op);
+    tracing::info!("Bot stopped");
+}
+
+/// Keep the bot online: reconnect with backoff when it drops, fall back to QR
+/// login once the session has expired, and persist key store changes.
+async fn supervise(svc: &Service, op: &Operator, offline: &Notify, mut need_login: bool) {
+    let mut failures = 0u32;
+    // admins are told once per expiry, not on every failed QR attempt
+    let mut login_notified = false;
+    loop {
+        if need_login {
+            svc.set_bot_state(BotState::LoggingIn, None);
+            if !login_notified {
+                svc.notify_login_required("session expired, scan the QR code to login");
+                login_notified = true;
+            }
+            if let Err(e) = qr_login(svc, op).await {
+                tracing::error!("Failed to login: {e:?}");
+                failures += 1;
+                svc.set_bot_state(BotState::Offline, Some(format!("login: {e}")));
+                tokio::time::sleep(retry_delay(failures)).await;
+                continue;
             }
-            login_res
+            need_login = false;
+            login_notified = false;
         }
-        .await;
-        if let Err(e) = login_res {
-            tracing::error!("Failed to login: {e:?}");
+
+        match op.online().await {
+            Ok(online_handle) => {
+                // listen before anything can await, so an offline event of this
+                // session cannot slip through
+                let session_offline = offline.notified();
+                tokio::pin!(session_offline);
+                session_offline.as_mut().enable();
+                failures = 0;
+                svc.set_bot_state(BotState::Online, None);
+                tracing::info!("Bot online");
+                let mut refresh = tokio::time::interval(KEYSTORE_REFRESH_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = refresh.tick() => persist_key_store(svc, op),
+                        _ = &mut session_offline => break,
+                    }
+                }
+                // the session is gone; stop its heartbeat before starting a new one
+                drop(online_handle);
+                persist_key_store(svc, op);
+                svc.set_bot_state(BotState::Offline, Some("bot went offline".to_string()));
+                tokio::time::sleep(retry_delay(1)).await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to set online status: {e:?}");
+                failures += 1;
+                svc.set_bot_state(BotState::Offline, Some(format!("online: {e}")));
+                if op.update_key_store().is_expired() {
+                    need_login = true;
+                    continue;
+                }
+                tokio::time::sleep(retry_delay(failures)).await;
+            }
         }
-    } else {
-        tracing::info!("Session is still valid, trying to online...");
     }
+}
 
-    let online_handle = match op.online().await {
-        Ok(tx) => tx,
-        Err(e) => {
-            tracing::error!("Failed to set online status: {e:?}");
-            return;
+fn retry_delay(failures: u32) -> Duration {
+    (RETRY_BASE * 2u32.pow(failures.saturating_sub(1).min(10))).min(RETRY_MAX)
+}
+
+/// Login by QR code. The code is published on the admin web page and printed
+/// to the terminal, and replaced by a new one whenever it expires.
+async fn qr_login(svc: &Service, op: &Operator) -> Result<(), String> {
+    for _ in 0..QR_MAX_REFRESHES {
+        let (url, bytes) = op.fetch_qrcode().await.map_err(|e| e.to_string())?;
+        svc.set_qr_code(url.clone(), bytes.to_vec());
+        tracing::info!(
+            "QR code fetched, scan it on /admin/bot or below. url: {}\n{}",
+            url,
+            render_qr_terminal(&url)
+        );
+        match tokio::time::timeout(QR_LIFETIME, op.login_by_qrcode()).await {
+            Ok(Ok(())) => {
+                svc.set_qr_state(QrState::Confirmed);
+                return Ok(());
+            }
+            Ok(Err(e)) => tracing::warn!("QR code login failed: {e:?}, fetching a new code"),
+            Err(_) => tracing::warn!("QR code expired, fetching a new code"),
         }
+        svc.set_qr_state(QrState::Expired);
+    }
+    svc.set_qr_state(QrState::Failed);
+    Err(format!(
+        "no QR code confirmed after {} tries",
+        QR_MAX_REFRESHES
+    ))
+}
+
+fn render_qr_terminal(url: &str) -> String {
+    match qrcode::QrCode::new(url.as_bytes()) {
+        Ok(code) => code
+            .render::<qrcode::render::unicode::Dense1x2>()
+            .quiet_zone(true)
+            .build(),
+        Err(e) => format!("(failed to render QR code: {e})"),
+    }
+}
+
+/// When the session in the saved key store expires, from the date it was
+/// established. `None` if the file has no session date.
+fn keystore_expires_at() -> Option<chrono::DateTime<chrono::Utc>> {
+    let key_store: serde_json::Value =
+        serde_json::from_slice(&fs::read(KEYSTORE_PATH).ok()?).ok()?;
+    let established = match key_store.pointer("/session/session_date")? {
+        serde_json::Value::String(s) => chrono::DateTime::parse_from_rfc3339(s)
+            .ok()?
+            .with_timezone(&chrono::Utc),
+        value => chrono::DateTime::from_timestamp(value.as_i64()?, 0)?,
     };
-    std::mem::forget(online_handle);
-    tracing::info!("Bot online");
+    Some(established + SESSION_LIFETIME)
+}
 
-    op.update_key_store()
-        .save("keystore.json")
-        .unwrap_or_else(|e| tracing::error!("Failed to save key store: {:?}", e));
+/// Save the current key store, replacing `keystore.json` only when it changed.
+fn persist_key_store(svc: &Service, op: &Operator) {
+    let key_store = op.update_key_store();
+    let tmp_path = format!("{}.tmp", KEYSTORE_PATH);
+    if let Err(e) = key_store.save(&tmp_path) {
+        tracing::error!("Failed to save key store: {:?}", e);
+        return;
+    }
+    let changed = match (fs::read(&tmp_path), fs::read(KEYSTORE_PATH)) {
+        (Ok(new), Ok(old)) => new != old,
+        _ => true,
+    };
+    let res = if changed {
+        fs::rename(&tmp_path, KEYSTORE_PATH)
+    } else {
+        fs::remove_file(&tmp_path)
+    };
+    match res {
+        Ok(()) => {
+            if changed {
+                tracing::info!("Key store updated");
+            }
+            svc.set_keystore_status(key_store.is_expired(), keystore_expires_at(), changed);
+        }
+        Err(e) => tracing::error!("Failed to save key store: {:?}", e),
+    }
 }
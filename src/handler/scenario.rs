@@ -0,0 +1,353 @@
+//! Scripted conversations with the bot, run against an in-memory database
+//! and a manual clock (`call-cal-bot scenario`).
+//!
+//! A scenario file is a list of lines:
+//!
+//! ```text
+//! # comment
+//! member Alice 10001        a sender, by name and uin
+//! admin Alice               let a member use admin commands
+//! group 12345               group of the following messages (1 by default)
+//! at 2024-03-01 08:30       set the clock, UTC+8
+//! wait 1d 2h 30m            move the clock forward
+//! Alice: /打卡               a message; `@Bob` in it mentions Bob
+//! > @Alice 3/1              the reply, one `>` line per line of text
+//! ~ ✅                      the reply contains this text
+//! ```
+//!
+//! A message without `>` or `~` lines after it may get any reply. A reply
+//! that mentions its sender starts with `@name `.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use chrono::prelude::*;
+
+use super::bot::{self, IncomingMessage};
+use super::outbox::OutgoingMessage;
+use crate::service::clock::Clock;
+use crate::service::daka::BOT_TZ;
+use crate::service::models::GroupMember;
+use crate::service::{self, Service};
+
+/// Where the clock starts if a scenario does not set it, UTC+8.
+const DEFAULT_START: &str = "2024-01-01 12:00";
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+#[derive(Debug)]
+enum Expect {
+    Exact(String),
+    Contains(String),
+}
+
+#[derive(Debug)]
+enum Step {
+    Member {
+        name: String,
+        uin: u32,
+    },
+    Admin(String),
+    Group(u32),
+    At(DateTime<Utc>),
+    Wait(chrono::Duration),
+    Message {
+        sender: String,
+        text: String,
+        expect: Vec<Expect>,
+    },
+}
+
+/// A step with the line it starts on, for error messages.
+struct Line {
+    number: usize,
+    step: Step,
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    let local = NaiveDateTime::parse_from_str(s, TIME_FORMAT)
+        .map_err(|e| format!("invalid time {:?}, expected YYYY-MM-DD HH:MM: {}", s, e))?;
+    Ok(BOT_TZ
+        .from_local_datetime(&local)
+        .single()
+        .expect("fixed offset")
+        .with_timezone(&Utc))
+}
+
+fn parse_duration(s: &str) -> Result<chrono::Duration, String> {
+    let mut total = chrono::Duration::zero();
+    for part in s.split_whitespace() {
+        let unit_at = part.char_indices().last().map_or(0, |(i, _)| i);
+        let (n, unit) = part.split_at(unit_at);
+        let n: i64 = n
+            .parse()
+            .map_err(|_| format!("invalid duration {:?}", part))?;
+        total += match unit {
+            "d" => chrono::Duration::days(n),
+            "h" => chrono::Duration::hours(n),
+            "m" => chrono::Duration::minutes(n),
+            _ => {
+                return Err(format!(
+                    "invalid duration unit in {:?}, use d, h or m",
+                    part
+                ));
+            }
+        };
+    }
+    Ok(total)
+}
+
+fn parse(text: &str) -> Result<Vec<Line>, String> {
+    let mut lines: Vec<Line> = Vec::new();
+    for (index, raw) in text.lines().enumerate() {
+        let number = index + 1;
+        let err = |e: String| format!("line {}: {}", number, e);
+        let line = raw.trim_end();
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let expect = if let Some(rest) = line.strip_prefix('>') {
+            Some(Expect::Exact(
+                rest.strip_prefix(' ').unwrap_or(rest).to_string(),
+            ))
+        } else {
+            line.strip_prefix("~ ")
+                .map(|rest| Expect::Contains(rest.to_string()))
+        };
+        if let Some(expect) = expect {
+            match lines.last_mut() {
+                Some(Line {
+                    step: Step::Message { expect: list, .. },
+                    ..
+                }) => list.push(expect),
+                _ => return Err(err("expected reply without a message".to_string())),
+            }
+            continue;
+        }
+
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let step = match keyword {
+            "member" => {
+                let mut parts = rest.split_whitespace();
+                match (parts.next(), parts.next().map(str::parse)) {
+                    (Some(name), Some(Ok(uin))) => Step::Member {
+                        name: name.to_string(),
+                        uin,
+                    },
+                    _ => return Err(err("usage: member <name> <uin>".to_string())),
+                }
+            }
+            "admin" => match rest.trim() {
+                "" => return Err(err("usage: admin <name>".to_string())),
+                name => Step::Admin(name.to_string()),
+            },
+            "group" => Step::Group(
+                rest.trim()
+                    .parse()
+                    .map_err(|_| err("usage: group <uin>".to_string()))?,
+            ),
+            "at" => Step::At(parse_time(rest.trim()).map_err(err)?),
+            "wait" => Step::Wait(parse_duration(rest).map_err(err)?),
+            _ => match line.split_once(": ") {
+                Some((sender, text)) if !sender.contains(' ') => Step::Message {
+                    sender: sender.to_string(),
+                    text: text.to_string(),
+                    expect: Vec::new(),
+                },
+                _ => return Err(err(format!("unrecognized line {:?}", line))),
+            },
+        };
+        lines.push(Line { number, step });
+    }
+    Ok(lines)
+}
+
+/// The reply as it would read in the group.
+fn reply_text(reply: &OutgoingMessage) -> String {
+    match &reply.mention {
+        Some(target) => format!("@{} {}", target.name, reply.text),
+        None => reply.text.clone(),
+    }
+}
+
+fn check(expect: &[Expect], reply: Option<&str>) -> Result<(), String> {
+    if expect.is_empty() {
+        return Ok(());
+    }
+    let Some(reply) = reply else {
+        return Err("expected a reply, got none".to_string());
+    };
+    let exact: Vec<&str> = expect
+        .iter()
+        .filter_map(|e| match e {
+            Expect::Exact(line) => Some(line.as_str()),
+            Expect::Contains(_) => None,
+        })
+        .collect();
+    if !exact.is_empty() && exact.join("\n") != reply {
+        return Err(format!(
+            "reply does not match\n--- expected\n{}\n--- got\n{}",
+            exact.join("\n"),
+            reply
+        ));
+    }
+    for e in expect {
+        if let Expect::Contains(part) = e
+            && !reply.contains(part.as_str())
+        {
+            return Err(format!(
+                "reply does not contain {:?}\n--- got\n{}",
+                part, reply
+            ));
+        }
+    }
+    Ok(())
+}
+
+struct Runner {
+    svc: Service,
+    clock: Clock,
+    members: Vec<(String, u32)>,
+    group_uin: u32,
+    message_id: i64,
+}
+
+impl Runner {
+    fn new() -> Self {
+        let clock = Clock::manual(parse_time(DEFAULT_START).expect("valid default start"));
+        Self {
+            // admins are only the ones the scenario names, not ADMIN_UINS
+            svc: service::init_in_memory(clock.clone(), HashSet::new()),
+            clock,
+            members: Vec::new(),
+            group_uin: 1,
+            message_id: 0,
+        }
+    }
+
+    fn uin_of(&self, name: &str) -> Option<u32> {
+        self.members
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, uin)| *uin)
+    }
+
+    fn step(&mut self, step: &Step) -> Result<(), String> {
+        match step {
+            Step::Member { name, uin } => self.members.push((name.clone(), *uin)),
+            Step::Admin(name) => {
+                let uin = self
+                    .uin_of(name)
+                    .ok_or_else(|| format!("unknown member {:?}", name))?;
+                self.svc.add_admin_uin(uin);
+            }
+            Step::Group(group_uin) => self.group_uin = *group_uin,
+            Step::At(time) => self.clock.set(*time),
+            Step::Wait(duration) => self.clock.set(self.clock.now() + *duration),
+            Step::Message {
+                sender,
+                text,
+                expect,
+            } => {
+                let uin = self
+                    .uin_of(sender)
+                    .ok_or_else(|| format!("unknown member {:?}", sender))?;
+                let mut mentions = Vec::new();
+                let mut words = Vec::new();
+                for word in text.split(' ') {
+                    match word.strip_prefix('@').and_then(|name| self.uin_of(name)) {
+                        Some(uin) => mentions.push(uin),
+                        None => words.push(word),
+                    }
+                }
+                self.message_id += 1;
+                let msg = IncomingMessage {
+                    message_id: self.message_id,
+                    group_uin: self.group_uin,
+                    sender: GroupMember {
+                        uid: format!("u_{}", uin),
+                        uin,
+                        member_name: Some(sender.clone()),
+                        member_card: None,
+                    },
+                    placeholder_uid: false,
+                    texts: vec![words.join(" ")],
+                    mentions,
+                };
+                let reply = bot::handle_message(&self.svc, &msg).map(|r| reply_text(&r));
+                check(expect, reply.as_deref())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run one scenario file, returning the number of messages checked.
+fn run_file(path: &Path) -> Result<usize, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let lines = parse(&text)?;
+    let mut runner = Runner::new();
+    for line in &lines {
+        runner
+            .step(&line.step)
+            .map_err(|e| format!("line {}: {}", line.number, e))?;
+    }
+    Ok(lines
+        .iter()
+        .filter(|l| matches!(&l.step, Step::Message { expect, .. } if !expect.is_empty()))
+        .count())
+}
+
+/// Scenario files under `path`, or `path` itself if it is a file.
+fn collect_files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let mut files = std::fs::read_dir(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "txt"))
+        .collect::<Vec<_>>();
+    files.sort();
+    Ok(files)
+}
+
+/// Run the scenarios in `paths` (files or directories) and report each one.
+/// Returns true if all of them passed.
+pub fn run(paths: &[PathBuf]) -> bool {
+    let mut passed = 0;
+    let mut failed = 0;
+    for path in paths {
+        let files = match collect_files(path) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("{e}");
+                failed += 1;
+                continue;
+            }
+        };
+        for file in files {
+            match run_file(&file) {
+                Ok(checks) => {
+                    passed += 1;
+                    println!("ok      {} ({} checks)", file.display(), checks);
+                }
+                Err(e) => {
+                    failed += 1;
+                    println!("FAILED  {}: {}", file.display(), e);
+                }
+            }
+        }
+    }
+    println!("{passed} passed, {failed} failed");
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scenarios_pass() {
+        assert!(run(&[PathBuf::from("scenarios")]));
+    }
+}
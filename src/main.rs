@@ -1,7 +1,9 @@
 use std::env;
 
 mod handler;
+mod metrics;
 mod service;
+mod shutdown;
 
 refinery::embed_migrations!("migrations");
 
@@ -9,22 +11,112 @@ refinery::embed_migrations!("migrations");
 async fn main() {
     tracing_subscriber::fmt::init();
 
-    let ctx = service::init_service();
+    // subcommands such as `import` run once and exit
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(handler::cli::run(&args));
+    }
+
+    metrics::init();
     let run_mode = env::var("RUN_MODE").ok();
+    let console = run_mode.as_deref() == Some("console");
+    let ctx = if console {
+        handler::console::init_service()
+    } else {
+        service::init_service()
+    };
+
+    let (shutdown_tx, shutdown) = shutdown::channel();
+    // tasks that are given time to finish their work on shutdown
+    let mut tasks = Vec::new();
 
-    if run_mode.as_deref() != Some("bot") {
+    if !matches!(run_mode.as_deref(), Some("bot" | "console")) {
         // build api app and serve via axum::serve
-        let app = handler::api::routes(ctx.clone());
+        let app = handler::api::routes(ctx.clone(), shutdown.clone());
         let listener = tokio::net::TcpListener::bind("127.0.0.1:9004")
             .await
             .unwrap();
-        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        let signal = shutdown.clone();
+        tasks.push(tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(signal.recv_owned())
+                .await
+                .unwrap()
+        }));
+    }
+    if let Some(addr) = handler::api::metrics_addr() {
+        let listener = tokio::net::TcpListener::bind(&addr)
+            .await
+            .unwrap_or_else(|e| panic!("bind metrics listener {addr}: {e}"));
+        let app = handler::api::metrics_routes();
+        let signal = shutdown.clone();
+        tasks.push(tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(signal.recv_owned())
+                .await
+                .unwrap()
+        }));
+    }
+
+    // the console is for trying out commands: nothing worth backing up, and
+    // nothing that should reach webhooks
+    if !console {
+        tasks.push(tokio::spawn(ctx.clone().run_backup_schedule(
+            service::backup::BackupConfig::from_env(),
+            shutdown.clone(),
+        )));
+        tasks.push(tokio::spawn(
+            ctx.clone().run_webhook_worker(shutdown.clone()),
+        ));
     }
 
     // spawn bot in background
-    if run_mode.as_deref() != Some("web") {
+    if !matches!(run_mode.as_deref(), Some("web" | "console")) {
         let bot = ctx.clone();
-        tokio::spawn(async move { handler::qbot::run(bot).await });
+        ctx.set_bot_state(service::status::BotState::Starting, None);
+        let signal = shutdown.clone();
+        // `BOT_PLATFORM` selects the chat adapter: `mania` (default) or `onebot`
+        let onebot = match env::var("BOT_PLATFORM").as_deref() {
+            Err(_) | Ok("mania") => false,
+            Ok("onebot") => true,
+            Ok(other) => panic!("unknown BOT_PLATFORM {other:?}, expected `mania` or `onebot`"),
+        };
+        tasks.push(tokio::spawn(async move {
+            if onebot {
+                handler::onebot::run(bot, signal).await
+            } else {
+                handler::qbot::run(bot, signal).await
+            }
+        }));
+    }
+
+    // the console runs until stdin is closed, then the process exits
+    let mut console_task = console.then(|| {
+        ctx.set_bot_state(service::status::BotState::Starting, None);
+        tokio::spawn(handler::console::run(ctx.clone(), shutdown.clone()))
+    });
+    let console_closed = async {
+        match console_task.as_mut() {
+            Some(task) => {
+                let _ = task.await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = shutdown::wait_for_signal() => {}
+        _ = console_closed => tracing::info!("Console input closed"),
+    }
+    tasks.extend(console_task.filter(|task| !task.is_finished()));
+    tracing::info!("Shutting down...");
+    let _ = shutdown_tx.send(true);
+    let timeout = shutdown::timeout();
+    if tokio::time::timeout(timeout, futures::future::join_all(tasks))
+        .await
+        .is_err()
+    {
+        tracing::warn!("In-flight work did not finish within {:?}", timeout);
     }
-    tokio::signal::ctrl_c().await.unwrap();
+    ctx.checkpoint_wal();
+    tracing::info!("Shutdown complete");
 }
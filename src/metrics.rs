@@ -0,0 +1,136 @@
+//! Prometheus metrics, exported in the text format on `/metrics`.
+
+use std::sync::LazyLock;
+
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use rusqlite::trace::TraceEvent;
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY
+        .register(Box::new(metric.clone()))
+        .expect("metric registered twice");
+    metric
+}
+
+/// Bot commands handled, by command name.
+pub static COMMANDS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("callcal_commands_total", "Bot commands handled"),
+            &["command"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Check-in changes from every channel: `created`, `cancelled` or `restored`.
+pub static CHECKINS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("callcal_checkins_total", "Check-in changes"),
+            &["action"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Web logins, by `success` / `failure`.
+pub static LOGINS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("callcal_logins_total", "Web login attempts"),
+            &["result"],
+        )
+        .unwrap(),
+    )
+});
+
+pub static MESSAGES_SENT: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(IntCounter::new("callcal_messages_sent_total", "Bot messages sent").unwrap())
+});
+
+pub static MESSAGE_SEND_FAILURES: LazyLock<IntCounter> = LazyLock::new(|| {
+    register(
+        IntCounter::new(
+            "callcal_message_send_failures_total",
+            "Bot messages that failed to send",
+        )
+        .unwrap(),
+    )
+});
+
+/// Duration of every SQL statement, by kind and first table (e.g. `select bot_daka`).
+pub static DB_QUERY_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new("callcal_db_query_duration_seconds", "SQL statement latency")
+                .buckets(vec![
+                    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+                    1.0,
+                ]),
+            &["statement"],
+        )
+        .unwrap(),
+    )
+});
+
+/// 1 while the bot is logged in and online.
+pub static BOT_ONLINE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new("callcal_bot_online", "Whether the bot is online").unwrap())
+});
+
+/// Label for a statement: its first keyword and the first table it names.
+/// Statements are fixed strings in the code, so this keeps the label set small.
+fn statement_label(sql: &str) -> String {
+    let kind = sql
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let mut words = sql.split_whitespace();
+    let table = words
+        .by_ref()
+        .find(|w| matches!(w.to_ascii_uppercase().as_str(), "FROM" | "INTO" | "UPDATE"))
+        .and_then(|_| words.next())
+        .map(|t| t.trim_matches(|c: char| c == '`' || c == '"' || c == '(' || c == ','))
+        .unwrap_or_default();
+    if table.is_empty() {
+        kind
+    } else {
+        format!("{} {}", kind, table)
+    }
+}
+
+/// SQLite profile callback, installed with `Connection::trace_v2`.
+pub fn observe_statement(event: TraceEvent<'_>) {
+    if let TraceEvent::Profile(stmt, duration) = event {
+        DB_QUERY_SECONDS
+            .with_label_values(&[&statement_label(&stmt.sql())])
+            .observe(duration.as_secs_f64());
+    }
+}
+
+/// Make every metric show up on the first scrape, even before it is touched.
+pub fn init() {
+    LazyLock::force(&COMMANDS);
+    LazyLock::force(&CHECKINS);
+    LazyLock::force(&LOGINS);
+    LazyLock::force(&MESSAGES_SENT);
+    LazyLock::force(&MESSAGE_SEND_FAILURES);
+    LazyLock::force(&DB_QUERY_SECONDS);
+    LazyLock::force(&BOT_ONLINE);
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut buf = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buf) {
+        tracing::error!("Failed to encode metrics: {:?}", e);
+    }
+    String::from_utf8(buf).unwrap_or_default()
+}
@@ -1,23 +1,60 @@
+pub mod audit;
+pub mod backup;
+pub mod calendar;
+pub mod clock;
 pub mod daka;
+pub mod events;
+pub mod goal;
+pub mod group_config;
+pub mod habit;
+pub mod import;
+pub mod leaderboard;
+pub mod leave;
 pub mod models;
+pub mod rest_day;
+pub mod stats;
+pub mod status;
 pub mod user;
+pub mod webhook;
 
-use std::sync::{Arc, Mutex};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
 
+use chrono::{DateTime, Utc};
 use rusqlite::Connection;
+use tokio::sync::broadcast;
+
+use clock::Clock;
+use events::{EVENT_BUS_CAPACITY, ServiceEvent};
+use status::BotStatus;
 
 #[derive(Clone)]
 pub struct Service {
     conn: Arc<Mutex<Connection>>,
+    events: broadcast::Sender<ServiceEvent>,
+    bot_status: Arc<RwLock<BotStatus>>,
+    clock: Clock,
+    /// QQ uins allowed to use admin commands and endpoints
+    admin_uins: Arc<RwLock<HashSet<u32>>>,
 }
 
 impl Service {
-    pub(super) fn new(conn: Connection) -> Self {
+    pub(super) fn new(conn: Connection, clock: Clock, admin_uins: HashSet<u32>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUS_CAPACITY);
         Self {
             conn: Arc::new(Mutex::new(conn)),
+            events,
+            bot_status: Arc::new(RwLock::new(BotStatus::default())),
+            clock,
+            admin_uins: Arc::new(RwLock::new(admin_uins)),
         }
     }
 
+    /// The current time of the service clock, for schedules outside it.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
     // Get password hash by member id
     pub fn get_password_by_id(&self, member_id: i64) -> Option<String> {
         let conn_guard = self.conn.lock().unwrap();
@@ -50,12 +87,54 @@ impl Service {
             Ok(())
         }
     }
+
+    /// Fold the write-ahead log back into the database file, so a stopped
+    /// service leaves a single self-contained file behind.
+    pub fn checkpoint_wal(&self) {
+        let conn_guard = self.conn.lock().unwrap();
+        let res = conn_guard.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |r| {
+            r.get::<_, i64>(0)
+        });
+        drop(conn_guard);
+        match res {
+            Ok(0) => tracing::info!("Database checkpointed"),
+            Ok(_) => tracing::warn!("Database checkpoint was blocked"),
+            Err(e) => tracing::error!("Failed to checkpoint database: {:?}", e),
+        }
+    }
 }
 
+pub const DB_PATH: &str = "call-cal-bot.db";
+
 pub fn init_service() -> Service {
-    let mut conn = Connection::open("call-cal-bot.db").expect("Failed to open database");
+    open_service(DB_PATH)
+}
+
+/// A service on the database at `path`, created and migrated as needed.
+pub fn open_service(path: &str) -> Service {
+    let mut conn = Connection::open(path).expect("Failed to open database");
+    // readers do not block the writer, and shutdown folds the log back in
+    let journal_mode: String = conn
+        .pragma_update_and_check(None, "journal_mode", "WAL", |r| r.get(0))
+        .expect("Failed to enable WAL");
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        tracing::warn!("Database journal mode is {} instead of WAL", journal_mode);
+    }
+    crate::migrations::runner()
+        .run(&mut conn)
+        .expect("db migration");
+    conn.trace_v2(
+        rusqlite::trace::TraceEventCodes::SQLITE_TRACE_PROFILE,
+        Some(crate::metrics::observe_statement),
+    );
+    Service::new(conn, Clock::default(), user::admin_uins())
+}
+
+/// A service on a fresh in-memory database, for scenario and console runs.
+pub fn init_in_memory(clock: Clock, admin_uins: HashSet<u32>) -> Service {
+    let mut conn = Connection::open_in_memory().expect("Failed to open database");
     crate::migrations::runner()
         .run(&mut conn)
         .expect("db migration");
-    Service::new(conn)
+    Service::new(conn, clock, admin_uins)
 }
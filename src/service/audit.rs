@@ -0,0 +1,136 @@
+use chrono::prelude::*;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use serde_json::Value;
+
+use super::daka::BOT_TZ;
+use super::models::{Channel, ServiceResponse};
+
+/// One change to record. Written by mutating service methods while they still
+/// hold the connection lock, so the entry matches what was changed.
+pub(super) struct AuditEntry<'a> {
+    pub actor_id: Option<i64>,
+    pub channel: Channel,
+    pub action: &'a str,
+    pub target_id: Option<i64>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+impl AuditEntry<'_> {
+    pub fn write(&self, conn: &Connection) {
+        let res = conn
+            .prepare_cached(
+                "INSERT INTO `audit_log` (`actor_id`, `channel`, `action`, `target_id`, `before`, `after`)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    self.actor_id,
+                    self.channel.as_str(),
+                    self.action,
+                    self.target_id,
+                    self.before,
+                    self.after
+                ])
+            });
+        // a failed audit write must not undo the change it describes
+        if let Err(e) = res {
+            tracing::error!("Failed to write audit log for {}: {:?}", self.action, e);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub id: i64,
+    /// UTC+8 time, `YYYY-MM-DD HH:MM:SS`
+    pub created_at: String,
+    pub actor_id: Option<i64>,
+    pub actor_name: Option<String>,
+    pub channel: String,
+    pub action: String,
+    pub target_id: Option<i64>,
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+impl super::Service {
+    /// Query the audit log, newest first. `action` filters by prefix
+    /// (e.g. `daka.` for all check-in changes).
+    pub fn query_audit_log(
+        &self,
+        limit: u32,
+        action: Option<&str>,
+        actor_id: Option<i64>,
+    ) -> Result<Vec<AuditRecord>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT A.`id`, A.`created_at`, A.`actor_id`, M.`group_nickname`, A.`channel`,
+                    A.`action`, A.`target_id`, A.`before`, A.`after`
+                FROM `audit_log` A
+                LEFT JOIN `bot_group_member` M ON M.`id` = A.`actor_id`
+                WHERE (?1 IS NULL OR A.`action` LIKE ?1 || '%')
+                AND (?2 IS NULL OR A.`actor_id` = ?2)
+                ORDER BY A.`id` DESC LIMIT ?3",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map(params![action, actor_id, limit], |row| {
+                let created_at: DateTime<Utc> = row.get(1)?;
+                Ok(AuditRecord {
+                    id: row.get(0)?,
+                    created_at: created_at
+                        .with_timezone(&BOT_TZ)
+                        .format("%Y-%m-%d %H:%M:%S")
+                        .to_string(),
+                    actor_id: row.get(2)?,
+                    actor_name: row.get(3)?,
+                    channel: row.get(4)?,
+                    action: row.get(5)?,
+                    target_id: row.get(6)?,
+                    before: row.get(7)?,
+                    after: row.get(8)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(rows)
+    }
+
+    /// Admin command: show the latest audit log entries, optionally only those
+    /// made by `actor_id`. `args` is an optional count.
+    pub fn handle_日志(&self, args: &str, actor_id: Option<i64>) -> ServiceResponse {
+        let limit = args
+            .split_whitespace()
+            .find_map(|a| a.parse::<u32>().ok())
+            .unwrap_or(10)
+            .clamp(1, 50);
+        match self.query_audit_log(limit, None, actor_id) {
+            Ok(records) if records.is_empty() => ServiceResponse::ok("暂无日志"),
+            Ok(records) => {
+                let lines = records
+                    .iter()
+                    .map(|r| {
+                        format!(
+                            "{} [{}] {} {}{}",
+                            &r.created_at[5..16],
+                            r.channel,
+                            r.actor_name.as_deref().unwrap_or("-"),
+                            r.action,
+                            r.target_id.map(|id| format!(" #{id}")).unwrap_or_default()
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                ServiceResponse::ok(lines.join("\n"))
+            }
+            Err(e) => {
+                tracing::error!("Failed to query audit log: {}", e);
+                ServiceResponse::err("日志查询失败：数据库错误")
+            }
+        }
+    }
+}
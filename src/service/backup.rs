@@ -0,0 +1,272 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{Connection, OpenFlags};
+
+use super::daka::BOT_TZ;
+use super::models::ServiceResponse;
+use crate::shutdown::ShutdownSignal;
+
+const BACKUP_PREFIX: &str = "call-cal-bot-";
+const BACKUP_SUFFIX: &str = ".db";
+const BACKUP_TIME_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+/// Names of backups taken before they had milliseconds.
+const LEGACY_BACKUP_TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+/// Files SQLite keeps next to a database in WAL mode.
+const WAL_SUFFIXES: [&str; 2] = ["-wal", "-shm"];
+
+/// Backup settings, read from the environment:
+/// - `BACKUP_DIR`: where copies are written (default `backups`)
+/// - `BACKUP_INTERVAL_HOURS`: schedule interval, 0 disables scheduled backups (default 24)
+/// - `BACKUP_KEEP`: number of latest backups always kept (default 7)
+/// - `BACKUP_KEEP_DAILY`: number of days for which the newest backup of the day is kept (default 30)
+#[derive(Debug, Clone)]
+pub struct BackupConfig {
+    pub dir: PathBuf,
+    pub interval: Option<Duration>,
+    pub keep_last: usize,
+    pub keep_daily: usize,
+}
+
+impl BackupConfig {
+    pub fn from_env() -> Self {
+        fn env_num(key: &str, default: u64) -> u64 {
+            std::env::var(key)
+                .ok()
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(default)
+        }
+        let hours = env_num("BACKUP_INTERVAL_HOURS", 24);
+        Self {
+            dir: std::env::var("BACKUP_DIR")
+                .unwrap_or_else(|_| "backups".to_string())
+                .into(),
+            interval: (hours > 0).then(|| Duration::from_secs(hours * 3600)),
+            keep_last: env_num("BACKUP_KEEP", 7) as usize,
+            keep_daily: env_num("BACKUP_KEEP_DAILY", 30) as usize,
+        }
+    }
+}
+
+/// Parse the time a backup was taken from its file name.
+fn backup_time_of(path: &Path) -> Option<NaiveDateTime> {
+    let name = path.file_name()?.to_str()?;
+    let ts = name
+        .strip_prefix(BACKUP_PREFIX)?
+        .strip_suffix(BACKUP_SUFFIX)?;
+    NaiveDateTime::parse_from_str(ts, BACKUP_TIME_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(ts, LEGACY_BACKUP_TIME_FORMAT))
+        .ok()
+}
+
+/// List backups in `dir`, newest first.
+pub fn list_backups(dir: &Path) -> Result<Vec<(PathBuf, NaiveDateTime)>, String> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("read backup dir failed: {:?}", e)),
+    };
+    let mut backups: Vec<_> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let path = e.path();
+            backup_time_of(&path).map(|t| (path, t))
+        })
+        .collect();
+    backups.sort_by_key(|(_, taken_at)| std::cmp::Reverse(*taken_at));
+    Ok(backups)
+}
+
+/// Delete backups not covered by the keep-N/keep-daily policy. Returns deleted paths.
+pub fn prune_backups(config: &BackupConfig) -> Result<Vec<PathBuf>, String> {
+    let backups = list_backups(&config.dir)?;
+    let mut days = HashSet::new();
+    let mut deleted = Vec::new();
+    for (index, (path, taken_at)) in backups.into_iter().enumerate() {
+        // backups are sorted newest first, so the first one seen for a day is its newest
+        let newest_of_day = days.insert(taken_at.date()) && days.len() <= config.keep_daily;
+        if index < config.keep_last || newest_of_day {
+            continue;
+        }
+        std::fs::remove_file(&path)
+            .map_err(|e| format!("remove {} failed: {:?}", path.display(), e))?;
+        deleted.push(path);
+    }
+    Ok(deleted)
+}
+
+/// Highest migration version recorded in a database, if it has been migrated.
+pub(super) fn schema_version(conn: &Connection) -> Option<i64> {
+    conn.query_row(
+        "SELECT MAX(`version`) FROM `refinery_schema_history`",
+        [],
+        |r| r.get(0),
+    )
+    .ok()
+    .flatten()
+}
+
+/// Replace the database at `db_path` with the backup at `backup_path`.
+///
+/// The backup is checked for integrity and must not be newer than the
+/// migrations embedded in this binary; older backups are migrated on the next
+/// start. The current database is kept next to it as `<db>.pre-restore-<time>`,
+/// together with its `-wal` and `-shm` files, which hold changes not yet
+/// checkpointed into it. Must not be run while the bot is running.
+pub fn restore_backup(backup_path: &Path, db_path: &Path) -> Result<String, String> {
+    let backup = Connection::open_with_flags(backup_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("open backup failed: {:?}", e))?;
+    let integrity: String = backup
+        .query_row("PRAGMA integrity_check", [], |r| r.get(0))
+        .map_err(|e| format!("integrity check failed: {:?}", e))?;
+    if integrity != "ok" {
+        return Err(format!("backup is corrupted: {}", integrity));
+    }
+    let backup_version = schema_version(&backup).ok_or("backup has no migration history")?;
+    let current_version = crate::migrations::runner()
+        .get_migrations()
+        .iter()
+        .map(|m| m.version() as i64)
+        .max()
+        .unwrap_or_default();
+    if backup_version > current_version {
+        return Err(format!(
+            "backup schema version {} is newer than supported version {}",
+            backup_version, current_version
+        ));
+    }
+    drop(backup);
+
+    let now = BOT_TZ.from_utc_datetime(&Utc::now().naive_utc());
+    let tmp_path = db_path.with_extension("db.restoring");
+    std::fs::copy(backup_path, &tmp_path).map_err(|e| format!("copy backup failed: {:?}", e))?;
+    let mut msg = String::new();
+    if db_path.exists() {
+        let keep_path = PathBuf::from(format!(
+            "{}.pre-restore-{}",
+            db_path.display(),
+            now.format(BACKUP_TIME_FORMAT)
+        ));
+        std::fs::rename(db_path, &keep_path)
+            .map_err(|e| format!("move current database failed: {:?}", e))?;
+        msg = format!("previous database moved to {}, ", keep_path.display());
+    }
+    // the log of the old database must not be applied to the restored one
+    for suffix in WAL_SUFFIXES {
+        let wal_path = PathBuf::from(format!("{}{}", db_path.display(), suffix));
+        if !wal_path.exists() {
+            continue;
+        }
+        let keep_path = PathBuf::from(format!(
+            "{}.pre-restore-{}{}",
+            db_path.display(),
+            now.format(BACKUP_TIME_FORMAT),
+            suffix
+        ));
+        std::fs::rename(&wal_path, &keep_path)
+            .map_err(|e| format!("move {} failed: {:?}", wal_path.display(), e))?;
+    }
+    std::fs::rename(&tmp_path, db_path).map_err(|e| format!("swap database failed: {:?}", e))?;
+    Ok(format!(
+        "{}restored {} (schema version {}, current {})",
+        msg,
+        backup_path.display(),
+        backup_version,
+        current_version
+    ))
+}
+
+impl super::Service {
+    /// Write a consistent copy of the live database into the backup directory
+    /// using SQLite's online backup API. Returns the path of the new backup.
+    pub fn backup_now(&self, config: &BackupConfig) -> Result<PathBuf, String> {
+        std::fs::create_dir_all(&config.dir)
+            .map_err(|e| format!("create backup dir failed: {:?}", e))?;
+        let now = BOT_TZ.from_utc_datetime(&Utc::now().naive_utc());
+        let path = config.dir.join(format!(
+            "{}{}{}",
+            BACKUP_PREFIX,
+            now.format(BACKUP_TIME_FORMAT),
+            BACKUP_SUFFIX
+        ));
+        // a scheduled backup and /备份 can start at the same time
+        if path.exists() {
+            return Err(format!("backup {} already exists", path.display()));
+        }
+        // write under a name list_backups ignores until the copy is complete
+        let partial_path = path.with_extension("db.partial");
+        let mut dst =
+            Connection::open(&partial_path).map_err(|e| format!("open target failed: {:?}", e))?;
+
+        let conn_guard = self.conn.lock().unwrap();
+        // copy all pages in one step so concurrent writes cannot restart the backup
+        let res = Backup::new(&conn_guard, &mut dst).and_then(|backup| backup.step(-1));
+        drop(conn_guard);
+        drop(dst);
+        match res {
+            Ok(StepResult::Done) => {}
+            res => {
+                let _ = std::fs::remove_file(&partial_path);
+                return Err(format!("backup failed: {:?}", res));
+            }
+        }
+        std::fs::rename(&partial_path, &path)
+            .map_err(|e| format!("rename backup failed: {:?}", e))?;
+        Ok(path)
+    }
+
+    /// Take a backup and prune old ones according to the policy.
+    pub fn backup_and_prune(&self, config: &BackupConfig) -> Result<PathBuf, String> {
+        let path = self.backup_now(config)?;
+        match prune_backups(config) {
+            Ok(deleted) if !deleted.is_empty() => {
+                tracing::info!("Pruned {} old backups", deleted.len())
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to prune backups: {}", e),
+        }
+        Ok(path)
+    }
+
+    /// Admin command: take a backup right away.
+    pub fn handle_备份(&self) -> ServiceResponse {
+        match self.backup_and_prune(&BackupConfig::from_env()) {
+            Ok(path) => ServiceResponse::ok(format!(
+                "备份完成：{}",
+                path.file_name().unwrap_or_default().to_string_lossy()
+            )),
+            Err(e) => {
+                tracing::error!("Backup failed: {}", e);
+                ServiceResponse::err("备份失败")
+            }
+        }
+    }
+
+    /// Take backups periodically according to `config.interval`, until
+    /// shutdown. A backup that is being written is finished first.
+    pub async fn run_backup_schedule(self, config: BackupConfig, mut shutdown: ShutdownSignal) {
+        let Some(interval) = config.interval else {
+            tracing::info!("Scheduled backups disabled");
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick completes immediately; wait a full interval before the first backup
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown.recv() => return,
+            }
+            let svc = self.clone();
+            let config = config.clone();
+            match tokio::task::spawn_blocking(move || svc.backup_and_prune(&config)).await {
+                Ok(Ok(path)) => tracing::info!("Backup written to {}", path.display()),
+                Ok(Err(e)) => tracing::error!("Scheduled backup failed: {}", e),
+                Err(e) => tracing::error!("Scheduled backup task panicked: {:?}", e),
+            }
+        }
+    }
+}
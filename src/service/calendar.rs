@@ -0,0 +1,253 @@
+use std::collections::{BTreeMap, HashSet};
+
+use chrono::prelude::*;
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use rusqlite::params;
+
+use super::audit::AuditEntry;
+use super::daka::{BOT_TZ, checkpoint_date_of};
+use super::goal::format_amount;
+use super::models::Channel;
+
+const CAL_TOKEN_LEN: usize = 32;
+
+fn generate_cal_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(CAL_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Escape a TEXT value as described in RFC 5545 section 3.3.11.
+fn escape_ics_text(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            ';' => out.push_str("\\;"),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Append a content line, folding it at 75 octets without splitting UTF-8 characters.
+fn push_ics_line(out: &mut String, line: &str) {
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            out.push_str("\r\n ");
+            // the leading space of the continuation line counts towards its length
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+    out.push_str("\r\n");
+}
+
+fn push_all_day_event(
+    out: &mut String,
+    uid: &str,
+    stamp: DateTime<Utc>,
+    date: NaiveDate,
+    summary: &str,
+    description: &str,
+) {
+    let next = date.succ_opt().expect("Valid next date");
+    push_ics_line(out, "BEGIN:VEVENT");
+    push_ics_line(out, &format!("UID:{uid}"));
+    push_ics_line(out, &format!("DTSTAMP:{}", stamp.format("%Y%m%dT%H%M%SZ")));
+    push_ics_line(
+        out,
+        &format!("DTSTART;VALUE=DATE:{}", date.format("%Y%m%d")),
+    );
+    push_ics_line(out, &format!("DTEND;VALUE=DATE:{}", next.format("%Y%m%d")));
+    push_ics_line(out, &format!("SUMMARY:{}", escape_ics_text(summary)));
+    if !description.is_empty() {
+        push_ics_line(
+            out,
+            &format!("DESCRIPTION:{}", escape_ics_text(description)),
+        );
+    }
+    push_ics_line(out, "TRANSP:TRANSPARENT");
+    push_ics_line(out, "END:VEVENT");
+}
+
+fn begin_calendar(out: &mut String, name: &str) {
+    push_ics_line(out, "BEGIN:VCALENDAR");
+    push_ics_line(out, "VERSION:2.0");
+    push_ics_line(out, "PRODID:-//call-cal-bot//daka//ZH");
+    push_ics_line(out, "CALSCALE:GREGORIAN");
+    push_ics_line(out, "METHOD:PUBLISH");
+    push_ics_line(out, &format!("X-WR-CALNAME:{}", escape_ics_text(name)));
+    push_ics_line(out, "X-WR-TIMEZONE:Asia/Shanghai");
+}
+
+impl super::Service {
+    /// Get the calendar subscription token of a member, generating one on first use.
+    pub fn get_or_create_cal_token(
+        &self,
+        member_id: i64,
+        channel: Channel,
+    ) -> Result<String, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached("SELECT `cal_token` FROM `bot_group_member` WHERE `id` = ?1")
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let token: String = stmt
+            .query_row([member_id], |r| r.get(0))
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        if !token.is_empty() {
+            return Ok(token);
+        }
+        self.regenerate_cal_token(member_id, channel)
+    }
+
+    /// Replace the calendar token of a member, invalidating previously shared URLs.
+    pub fn regenerate_cal_token(&self, member_id: i64, channel: Channel) -> Result<String, String> {
+        let token = generate_cal_token();
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached("UPDATE `bot_group_member` SET `cal_token` = ?1 WHERE `id` = ?2")
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let res = stmt
+            .execute(params![token, member_id])
+            .map_err(|e| format!("execute failed: {:?}", e))?;
+        drop(stmt);
+        if res == 0 {
+            return Err("no rows updated".to_string());
+        }
+        // the token is a secret, only record that it changed
+        AuditEntry {
+            actor_id: Some(member_id),
+            channel,
+            action: "member.cal_token",
+            target_id: Some(member_id),
+            before: None,
+            after: None,
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(token)
+    }
+
+    /// Find member id and group nickname by calendar token.
+    pub fn find_member_by_cal_token(&self, token: &str) -> Option<(i64, String)> {
+        if token.is_empty() {
+            return None;
+        }
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `group_nickname` FROM `bot_group_member` WHERE `cal_token` = ?1",
+            )
+            .ok()?;
+        let res = stmt.query_row([token], |r| Ok((r.get(0)?, r.get(1)?)));
+        drop(stmt);
+        drop(conn_guard);
+        res.ok()
+    }
+
+    /// Build an iCalendar feed with one all-day event per check-in day of a member.
+    pub fn build_member_ics(&self, member_id: i64, nickname: &str) -> Result<String, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `created_at`, `note`, `amount`, `unit` FROM `bot_daka`
+                WHERE `user_id` = ?1 AND `deleted_at` IS NULL
+                    AND `habit_id` = (SELECT `id` FROM `bot_habit` WHERE `is_default` = 1)
+                ORDER BY `created_at` ASC",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map([member_id], |row| {
+                let id: i64 = row.get(0)?;
+                let created_at: DateTime<Utc> = row.get(1)?;
+                let note: String = row.get(2)?;
+                let amount: Option<f64> = row.get(3)?;
+                let unit: Option<String> = row.get(4)?;
+                Ok((id, created_at, note, amount.zip(unit)))
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+
+        let mut out = String::new();
+        begin_calendar(&mut out, &format!("{nickname} 打卡"));
+        for (id, created_at, note, amount) in rows {
+            let time = created_at.with_timezone(&BOT_TZ).format("%H:%M");
+            let amount = amount
+                .map(|(amount, unit)| format!(" {}{}", format_amount(amount), unit))
+                .unwrap_or_default();
+            push_all_day_event(
+                &mut out,
+                &format!("daka-{id}@call-cal-bot"),
+                created_at,
+                checkpoint_date_of(created_at),
+                &format!("✅ 打卡 {time}{amount}"),
+                &note,
+            );
+        }
+        push_ics_line(&mut out, "END:VCALENDAR");
+        Ok(out)
+    }
+
+    /// Build an iCalendar feed with one all-day event per day showing how many
+    /// members checked in.
+    pub fn build_group_ics(&self) -> Result<String, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let total: i64 = conn_guard
+            .query_row("SELECT COUNT(*) FROM `bot_group_member`", [], |r| r.get(0))
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `user_id`, `created_at` FROM `bot_daka` WHERE `deleted_at` IS NULL
+                    AND `habit_id` = (SELECT `id` FROM `bot_habit` WHERE `is_default` = 1)",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map([], |row| {
+                let user_id: i64 = row.get(0)?;
+                let created_at: DateTime<Utc> = row.get(1)?;
+                Ok((user_id, created_at))
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+
+        // distinct members and latest record time per bot day
+        let mut days: BTreeMap<NaiveDate, (HashSet<i64>, DateTime<Utc>)> = BTreeMap::new();
+        for (user_id, created_at) in rows {
+            let entry = days
+                .entry(checkpoint_date_of(created_at))
+                .or_insert_with(|| (HashSet::new(), created_at));
+            entry.0.insert(user_id);
+            entry.1 = entry.1.max(created_at);
+        }
+
+        let mut out = String::new();
+        begin_calendar(&mut out, "群打卡");
+        for (date, (members, stamp)) in days {
+            push_all_day_event(
+                &mut out,
+                &format!("group-{}@call-cal-bot", date.format("%Y%m%d")),
+                stamp,
+                date,
+                &format!("打卡 {}/{}", members.len(), total),
+                "",
+            );
+        }
+        push_ics_line(&mut out, "END:VCALENDAR");
+        Ok(out)
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::prelude::*;
+
+use super::daka::BOT_TZ;
+
+/// Source of the current time for the service. The system clock normally;
+/// scenario runs use a manual clock and move it forward themselves.
+#[derive(Clone, Default)]
+pub struct Clock(Option<Arc<Mutex<DateTime<Utc>>>>);
+
+impl Clock {
+    pub fn manual(start: DateTime<Utc>) -> Self {
+        Self(Some(Arc::new(Mutex::new(start))))
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        match &self.0 {
+            Some(time) => *time.lock().unwrap(),
+            None => Utc::now(),
+        }
+    }
+
+    /// Set a manual clock. Has no effect on the system clock.
+    pub fn set(&self, to: DateTime<Utc>) {
+        if let Some(time) = &self.0 {
+            *time.lock().unwrap() = to;
+        }
+    }
+}
+
+/// Format a time the way the `strftime('%Y-%m-%d %H:%M:%f', 'now')` column
+/// defaults do, so values written from Rust compare correctly with them.
+pub(super) fn db_time(dt: DateTime<Utc>) -> String {
+    dt.naive_utc().format("%Y-%m-%d %H:%M:%S%.3f").to_string()
+}
+
+/// Format a time for API responses: UTC+8, `YYYY-MM-DD HH:MM:SS`.
+pub(super) fn local_time(dt: Option<DateTime<Utc>>) -> Option<String> {
+    dt.map(|dt| {
+        dt.with_timezone(&BOT_TZ)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    })
+}
@@ -1,16 +1,26 @@
-use crate::service::models::{GroupMember, ServiceResponse};
+use crate::service::audit::AuditEntry;
+use crate::service::clock::db_time;
+use crate::service::events::{ServiceEvent, member_name};
+use crate::service::goal::{Amount, format_amount, goal_for};
+use crate::service::group_config::GuTier;
+use crate::service::habit::Habit;
+use crate::service::models::{Channel, GroupMember, ServiceResponse};
 use chrono::prelude::*;
-use rusqlite::params;
+use rusqlite::{OptionalExtension, params};
+use serde::Serialize;
 use tracing::error;
 
-const BOT_TZ: FixedOffset = FixedOffset::east_opt(8 * 3600).expect("UTC+8 offset");
-const BOT_CHECKPOINT: NaiveTime =
+pub const BOT_TZ: FixedOffset = FixedOffset::east_opt(8 * 3600).expect("UTC+8 offset");
+pub(super) const BOT_CHECKPOINT: NaiveTime =
     NaiveTime::from_hms_opt(4, 0, 0).expect("Valid time for bot checkpoint");
+const DAKA_USAGE: &str = "用法：/打卡 [习惯] [数量单位 | 备注]，如 /打卡 30分钟";
+/// How long after a check-in or cancellation `/撤销` can still revert it.
+const UNDO_WINDOW: chrono::Duration = chrono::Duration::minutes(30);
 
-/// Get the datetime at 4 AM of the current day if the current time is after 4 AM,
-/// otherwise get the datetime at 4 AM of the previous day. Use UTC+8 time zone.
-fn get_checkpoint() -> DateTime<FixedOffset> {
-    let now = BOT_TZ.from_utc_datetime(&Utc::now().naive_utc());
+/// Get the datetime at 4 AM of the day of `now` if it is after 4 AM, otherwise
+/// get the datetime at 4 AM of the previous day. Use UTC+8 time zone.
+fn get_checkpoint(now: DateTime<Utc>) -> DateTime<FixedOffset> {
+    let now = now.with_timezone(&BOT_TZ);
     let checkpoint_date = if now.time() >= BOT_CHECKPOINT {
         now.date_naive()
     } else {
@@ -23,151 +33,325 @@ fn get_checkpoint() -> DateTime<FixedOffset> {
         .expect("Valid checkpoint datetime")
 }
 
-impl super::Service {
-    pub fn build_daily_report(&self) -> String {
-        let checkpoint_start = get_checkpoint();
-        let checkpoint_end = checkpoint_start + chrono::Duration::days(1);
+/// Get the bot day a record belongs to, i.e. the date of the latest checkpoint
+/// at or before `dt` in UTC+8.
+pub(super) fn checkpoint_date_of(dt: DateTime<Utc>) -> NaiveDate {
+    let local = dt.with_timezone(&BOT_TZ);
+    if local.time() >= BOT_CHECKPOINT {
+        local.date_naive()
+    } else {
+        local.date_naive().pred_opt().expect("Valid prev date")
+    }
+}
 
-        // Lock connection for this query
-        let conn_guard = self.conn.lock().unwrap();
-        let mut stmt = match conn_guard.prepare_cached(
-            // Get all member nicknames and their daka time (if exists) within
-            // the two checkpoints
-            "SELECT `bot_group_member`.`group_nickname`, D.`created_at` FROM `bot_group_member`
-            LEFT JOIN (
-                SELECT `created_at`, `user_id` FROM `bot_daka` WHERE `bot_daka`.`created_at` >= ?1 AND `bot_daka`.`created_at` < ?2
-            ) D ON D.`user_id` = `bot_group_member`.`id`
-            ORDER BY D.`created_at` ASC, `bot_group_member`.`sort_key` ASC, `bot_group_member`.`id` ASC",
-        ) {
-            Ok(s) => s,
+/// Start of bot day `date`, i.e. 04:00 UTC+8 on that date.
+pub(super) fn checkpoint_of(date: NaiveDate) -> DateTime<Utc> {
+    BOT_TZ
+        .from_local_datetime(&NaiveDateTime::new(date, BOT_CHECKPOINT))
+        .single()
+        .expect("Valid checkpoint datetime")
+        .with_timezone(&Utc)
+}
+
+/// Check-ins of one member on one bot day, added up.
+struct DayRow {
+    member_id: i64,
+    name: String,
+    first_at: Option<DateTime<Utc>>,
+    /// Sum of the amounts, `None` for plain check-ins
+    total: Option<Amount>,
+}
+
+/// Check-ins of one member on one bot day, for the web API.
+#[derive(Debug, Clone, Serialize)]
+pub struct DayRecord {
+    pub name: String,
+    /// Earliest check-in, `HH:MM`, or `None` if there was none
+    pub time: Option<String>,
+    /// Sum of the amounts checked in
+    pub amount: Option<f64>,
+    pub unit: Option<String>,
+    /// Daily goal the member follows
+    pub goal: Option<Amount>,
+    /// Whether the member checked in, and reached the goal if there is one
+    pub goal_met: bool,
+}
+
+/// How long a member has gone without a check-in, for `/咕`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberActivity {
+    pub member_id: i64,
+    pub qq_uin: u32,
+    pub name: String,
+    /// Bot day of the last check-in, `YYYY-MM-DD`
+    pub last_checkin: Option<String>,
+    /// Full bot days without a check-in since the last one, or since joining
+    /// for members who never checked in. Today, days on leave and rest days do
+    /// not count.
+    pub inactive_days: i64,
+    pub checked_in_today: bool,
+    /// Last day of the leave the member is on today, `YYYY-MM-DD`
+    pub on_leave_until: Option<String>,
+    #[serde(skip)]
+    joined: NaiveDate,
+}
+
+impl super::Service {
+    /// Bot command: `/今日 [习惯]`.
+    pub fn handle_今日(&self, args: &str) -> ServiceResponse {
+        match self.habit_from_args(args) {
+            Ok((habit, "")) => ServiceResponse::ok(self.build_daily_report(&habit)),
+            Ok((_, name)) => self.unknown_habit(name),
             Err(e) => {
-                error!("Prepare statement failed: {:?}", e);
-                return "打卡日报查询失败".to_string();
+                error!("Failed to query habit: {:?}", e);
+                ServiceResponse::err("打卡日报查询失败")
             }
-        };
+        }
+    }
 
-        let rows = match stmt.query_map(
-            params![checkpoint_start.naive_utc(), checkpoint_end.naive_utc()],
-            |row| {
-                let nickname: String = row.get(0)?;
-                let created_at: Option<String> = row.get(1)?;
-                let has_record = created_at.is_some();
-                Ok((nickname, has_record))
-            },
-        ) {
-            Ok(rows) => rows,
+    /// Who checked in today for `habit`. Non-default habits are named in the
+    /// first line. Members with a daily goal are done once their amounts add
+    /// up to it and are listed with ⏳ until then.
+    pub fn build_daily_report(&self, habit: &Habit) -> String {
+        let checkpoint_start = get_checkpoint(self.clock.now());
+        let today = checkpoint_start.date_naive();
+        let (leave, rest, goals) = match self
+            .leave_calendar()
+            .and_then(|l| Ok((l, self.rest_calendar()?, self.goals(habit.id)?)))
+        {
+            Ok(calendars) => calendars,
             Err(e) => {
-                error!("Failed to query daily report: {:?}", e);
+                error!("Failed to query days off and goals: {:?}", e);
                 return "打卡日报查询失败".to_string();
             }
         };
-        let rows: Result<Vec<_>, _> = rows.collect();
-        let rows = match rows {
+        let rows = match self.query_day(today, habit.id) {
             Ok(rows) => rows,
             Err(e) => {
-                error!("Failed to collect daily report rows: {:?}", e);
+                error!("Failed to query daily report: {:?}", e);
                 return "打卡日报查询失败".to_string();
             }
         };
-        // drop the prepared statement before releasing the connection lock
-        drop(stmt);
-        drop(conn_guard);
 
-        let (rows_has_record, rows_wo_record): (Vec<_>, _) =
-            rows.into_iter().partition(|(_, has_record)| *has_record);
-        if rows_has_record.is_empty() {
-            return "今日无人打卡".to_string();
+        let title = if habit.is_default {
+            String::new()
+        } else {
+            format!("{} ", habit.name)
+        };
+        let join_names = |names: &[String]| names.join("\u{3000}");
+        let mut done = Vec::new();
+        let mut in_progress = Vec::new();
+        let mut on_leave = Vec::new();
+        let mut missing = Vec::new();
+        for row in &rows {
+            let goal = goals.for_member(row.member_id);
+            if row.first_at.is_none() {
+                if leave.on_leave(row.member_id, today) {
+                    on_leave.push(row.name.clone());
+                } else {
+                    missing.push(row.name.clone());
+                }
+                continue;
+            }
+            match (goal, &row.total) {
+                (Some(goal), total) => {
+                    let amount = total.as_ref().map_or(0.0, |t| t.amount);
+                    let text = format!("{} {}/{}", row.name, format_amount(amount), goal);
+                    if amount >= goal.amount {
+                        done.push(text);
+                    } else {
+                        in_progress.push(text);
+                    }
+                }
+                (None, Some(total)) => done.push(format!("{} {}", row.name, total)),
+                (None, None) => done.push(row.name.clone()),
+            }
         }
 
-        let rows_has_record = rows_has_record
-            .iter()
-            .map(|(row_text, _)| &**row_text)
-            .collect::<Vec<_>>()
-            .join("\u{3000}");
-        let rows_wo_record = rows_wo_record
-            .iter()
-            .map(|(row_text, _)| &**row_text)
-            .collect::<Vec<_>>()
-            .join("\u{3000}");
-        format!(
-            "{}/{}\n{rows_has_record} ✅\n{rows_wo_record} ❌",
-            checkpoint_start.month(),
-            checkpoint_start.day()
-        )
+        if rest.is_rest_day(today) {
+            if done.is_empty() && in_progress.is_empty() {
+                return "今天是休息日".to_string();
+            }
+            let mut report = format!("{}{}/{} 今天是休息日", title, today.month(), today.day());
+            if !done.is_empty() {
+                report += &format!("\n{} ✅", join_names(&done));
+            }
+            if !in_progress.is_empty() {
+                report += &format!("\n{} ⏳", join_names(&in_progress));
+            }
+            return report;
+        }
+        if done.is_empty() && in_progress.is_empty() {
+            let mut report = format!("{}今日无人打卡", title);
+            if !on_leave.is_empty() {
+                report += &format!("\n{} 🏖", join_names(&on_leave));
+            }
+            return report;
+        }
+        let mut report = format!("{}{}/{}", title, today.month(), today.day());
+        if !done.is_empty() {
+            report += &format!("\n{} ✅", join_names(&done));
+        }
+        if !in_progress.is_empty() {
+            report += &format!("\n{} ⏳", join_names(&in_progress));
+        }
+        report += &format!("\n{} ❌", join_names(&missing));
+        if !on_leave.is_empty() {
+            report += &format!("\n{} 🏖", join_names(&on_leave));
+        }
+        report
     }
 
-    /// Query records for a specific checkpoint start (UTC+8 04:00 of the provided date).
-    /// If `date_str` is None, uses get_checkpoint() (today by bot rules).
-    /// Returns a Vec of (nickname, Option<HH:MM string>) where None means no record.
-    pub fn query_records_for_date(
-        &self,
-        date_str: Option<&str>,
-    ) -> Result<Vec<(String, Option<String>)>, String> {
-        let checkpoint_start = match date_str {
-            Some(s) => match chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
-                Ok(d) => BOT_TZ
-                    .from_local_datetime(&NaiveDateTime::new(d, BOT_CHECKPOINT))
-                    .single()
-                    .ok_or_else(|| "invalid date/time".to_string())?,
-                Err(e) => return Err(format!("invalid date: {:?}", e)),
-            },
-            None => get_checkpoint(),
-        };
-
+    /// Every member taking part in a habit with their check-ins on bot day
+    /// `date` added up, the earliest first and members without one last. All
+    /// members take part in the default habit, only those who checked in for
+    /// it by the end of `date` in the others.
+    fn query_day(&self, date: NaiveDate, habit_id: i64) -> Result<Vec<DayRow>, String> {
+        let checkpoint_start = checkpoint_of(date);
         let checkpoint_end = checkpoint_start + chrono::Duration::days(1);
 
         let conn_guard = self.conn.lock().unwrap();
-        // Order by presence of created_at (not null first) then by created_at asc, then by sort_key and id
         let mut stmt = conn_guard.prepare_cached(
-            "SELECT `bot_group_member`.`group_nickname`, D.`created_at` FROM `bot_group_member`
+            "SELECT `bot_group_member`.`id`, `bot_group_member`.`group_nickname`, D.`first_at`, D.`amount`, D.`unit`
+            FROM `bot_group_member`
             LEFT JOIN (
-                SELECT `created_at`, `user_id` FROM `bot_daka` WHERE `bot_daka`.`created_at` >= ?1 AND `bot_daka`.`created_at` < ?2
+                SELECT `user_id`, MIN(`created_at`) AS `first_at`, SUM(`amount`) AS `amount`, MAX(`unit`) AS `unit`
+                FROM `bot_daka` WHERE `bot_daka`.`created_at` >= ?1 AND `bot_daka`.`created_at` < ?2
+                AND `bot_daka`.`deleted_at` IS NULL AND `bot_daka`.`habit_id` = ?3
+                GROUP BY `user_id`
             ) D ON D.`user_id` = `bot_group_member`.`id`
-            ORDER BY (D.`created_at` IS NULL), D.`created_at` ASC, `bot_group_member`.`sort_key` ASC, `bot_group_member`.`id` ASC",
+            JOIN `bot_habit` ON `bot_habit`.`id` = ?3
+            WHERE `bot_habit`.`is_default` OR EXISTS (
+                SELECT 1 FROM `bot_daka` E WHERE E.`user_id` = `bot_group_member`.`id`
+                AND E.`habit_id` = ?3 AND E.`deleted_at` IS NULL AND E.`created_at` < ?2
+            )
+            ORDER BY (D.`first_at` IS NULL), D.`first_at` ASC, `bot_group_member`.`sort_key` ASC, `bot_group_member`.`id` ASC",
         )
         .map_err(|e| format!("prepare failed: {:?}", e))?;
-
         let rows = stmt
             .query_map(
-                params![checkpoint_start.naive_utc(), checkpoint_end.naive_utc()],
+                params![
+                    checkpoint_start.naive_utc(),
+                    checkpoint_end.naive_utc(),
+                    habit_id
+                ],
                 |row| {
-                    let nickname: String = row.get(0)?;
-                    // read as UTC datetime and convert to BOT_TZ when present
-                    let created_at: Option<chrono::DateTime<chrono::Utc>> = row.get(1)?;
-                    let time_str =
-                        created_at.map(|dt| dt.with_timezone(&BOT_TZ).format("%H:%M").to_string());
-                    Ok((nickname, time_str))
+                    let amount: Option<f64> = row.get(3)?;
+                    let unit: Option<String> = row.get(4)?;
+                    Ok(DayRow {
+                        member_id: row.get(0)?,
+                        name: row.get(1)?,
+                        first_at: row.get(2)?,
+                        total: amount
+                            .zip(unit)
+                            .map(|(amount, unit)| Amount { amount, unit }),
+                    })
                 },
             )
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
             .map_err(|e| format!("query failed: {:?}", e))?;
-
-        let mut out: Vec<(String, Option<String>)> = Vec::new();
-        for r in rows {
-            match r {
-                Ok((n, t)) => out.push((n, t)),
-                Err(e) => return Err(format!("row error: {:?}", e)),
-            }
-        }
         drop(stmt);
         drop(conn_guard);
-        Ok(out)
+        Ok(rows)
+    }
+
+    /// Check-ins of a habit on a bot day (`YYYY-MM-DD`), today if
+    /// `date_str` is None. Every member is listed, those without a check-in
+    /// last.
+    pub fn query_records_for_date(
+        &self,
+        date_str: Option<&str>,
+        habit_id: i64,
+    ) -> Result<Vec<DayRecord>, String> {
+        let date = match date_str {
+            Some(s) => chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d")
+                .map_err(|e| format!("invalid date: {:?}", e))?,
+            None => checkpoint_date_of(self.clock.now()),
+        };
+        let goals = self.goals(habit_id)?;
+        let rows = self.query_day(date, habit_id)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let goal = goals.for_member(row.member_id).cloned();
+                let goal_met = match (&goal, &row.total) {
+                    (Some(goal), total) => {
+                        row.first_at.is_some()
+                            && total.as_ref().map_or(0.0, |t| t.amount) >= goal.amount
+                    }
+                    (None, _) => row.first_at.is_some(),
+                };
+                DayRecord {
+                    name: row.name,
+                    time: row
+                        .first_at
+                        .map(|dt| dt.with_timezone(&BOT_TZ).format("%H:%M").to_string()),
+                    amount: row.total.as_ref().map(|t| t.amount),
+                    unit: row.total.map(|t| t.unit),
+                    goal,
+                    goal_met,
+                }
+            })
+            .collect())
     }
 
     /// Ensure the member record exists and update nickname/group_nickname.
     /// Returns the `id` of the bot_group_member on success or a ServiceResponse error.
-    pub fn upsert_member(&self, group_member: &GroupMember) -> Result<i64, ServiceResponse> {
+    pub fn upsert_member(
+        &self,
+        group_member: &GroupMember,
+        channel: Channel,
+    ) -> Result<i64, ServiceResponse> {
         let uid = &group_member.uid;
         let nickname = group_member.member_name.as_deref().unwrap_or_default();
         let group_nickname = group_member.member_card.as_deref().unwrap_or(nickname);
 
         const UPSERT_RECORD_SQL: &str =
-            "INSERT INTO `bot_group_member` (`qq_uid`, `qq_uin`, `nickname`, `group_nickname`)
-            VALUES (?1, ?2, ?3, ?4)
+            "INSERT INTO `bot_group_member` (`qq_uid`, `qq_uin`, `nickname`, `group_nickname`, `created_at`)
+            VALUES (?1, ?2, ?3, ?4, ?5)
             ON CONFLICT (`qq_uid`)
                 DO UPDATE SET `nickname` = excluded.nickname, `group_nickname` = excluded.group_nickname
             RETURNING `id`";
+        // Placeholder members created by a CSV import or a OneBot adapter are
+        // keyed by uin only, so let the real member claim it on first contact.
+        const CLAIM_IMPORTED_SQL: &str = "UPDATE `bot_group_member` SET `qq_uid` = ?1
+            WHERE `qq_uin` = ?2 AND `qq_uid` != ?1
+                AND (`qq_uid` LIKE 'import:%' OR `qq_uid` LIKE 'onebot:%')
+            RETURNING `id`, `qq_uid`";
+        const GET_NAMES_SQL: &str =
+            "SELECT `nickname`, `group_nickname` FROM `bot_group_member` WHERE `qq_uid` = ?1";
 
         let conn_guard = self.conn.lock().unwrap();
+        let claimed: Option<(i64, String)> = match conn_guard
+            .prepare_cached(CLAIM_IMPORTED_SQL)
+            .and_then(|mut stmt| {
+                stmt.query_row(params![uid, group_member.uin], |r| {
+                    Ok((r.get(0)?, r.get(1)?))
+                })
+                .optional()
+            }) {
+            Ok(claimed) => claimed,
+            Err(e) => {
+                return Err(ServiceResponse::err(format!(
+                    "Failed to claim imported member: {:?}",
+                    e
+                )));
+            }
+        };
+        let previous: Option<(String, String)> = match conn_guard
+            .prepare_cached(GET_NAMES_SQL)
+            .and_then(|mut stmt| {
+                stmt.query_row([uid], |r| Ok((r.get(0)?, r.get(1)?)))
+                    .optional()
+            }) {
+            Ok(previous) => previous,
+            Err(e) => {
+                return Err(ServiceResponse::err(format!(
+                    "Failed to query member: {:?}",
+                    e
+                )));
+            }
+        };
         let mut stmt = match conn_guard.prepare_cached(UPSERT_RECORD_SQL) {
             Ok(s) => s,
             Err(e) => {
@@ -178,7 +362,13 @@ impl super::Service {
             }
         };
         let id: i64 = match stmt.query_row(
-            params![uid, group_member.uin, nickname, group_nickname],
+            params![
+                uid,
+                group_member.uin,
+                nickname,
+                group_nickname,
+                db_time(self.clock.now())
+            ],
             |row| row.get(0),
         ) {
             Ok(id) => id,
@@ -190,24 +380,131 @@ impl super::Service {
             }
         };
         drop(stmt);
+
+        let names = serde_json::json!({"nickname": nickname, "group_nickname": group_nickname});
+        if let Some((claimed_id, _)) = claimed {
+            AuditEntry {
+                actor_id: Some(claimed_id),
+                channel,
+                action: "member.claim",
+                target_id: Some(claimed_id),
+                before: Some(serde_json::json!({"qq_uin": group_member.uin})),
+                after: Some(serde_json::json!({"qq_uid": uid})),
+            }
+            .write(&conn_guard);
+        }
+        match previous {
+            None => {
+                AuditEntry {
+                    actor_id: Some(id),
+                    channel,
+                    action: "member.create",
+                    target_id: Some(id),
+                    before: None,
+                    after: Some(names),
+                }
+                .write(&conn_guard);
+                self.publish(
+                    &conn_guard,
+                    ServiceEvent::Member {
+                        member_id: id,
+                        name: group_nickname.to_string(),
+                        previous_name: None,
+                    },
+                );
+            }
+            Some((old_nickname, old_group_nickname))
+                if old_nickname != nickname || old_group_nickname != group_nickname =>
+            {
+                AuditEntry {
+                    actor_id: Some(id),
+                    channel,
+                    action: "member.rename",
+                    target_id: Some(id),
+                    before: Some(serde_json::json!({
+                        "nickname": old_nickname,
+                        "group_nickname": old_group_nickname
+                    })),
+                    after: Some(names),
+                }
+                .write(&conn_guard);
+                self.publish(
+                    &conn_guard,
+                    ServiceEvent::Member {
+                        member_id: id,
+                        name: group_nickname.to_string(),
+                        previous_name: Some(old_group_nickname),
+                    },
+                );
+            }
+            Some(_) => {}
+        }
         drop(conn_guard);
         Ok(id)
     }
 
-    pub fn handle_我没打卡(&self, user_id: i64, _args: &str) -> ServiceResponse {
-        let checkpoint = get_checkpoint();
+    pub fn handle_我没打卡(
+        &self,
+        user_id: i64,
+        args: &str,
+        channel: Channel,
+    ) -> ServiceResponse {
+        let habit = match self.habit_from_args(args) {
+            Ok((habit, "")) => habit,
+            Ok((_, name)) => return self.unknown_habit(name),
+            Err(e) => {
+                tracing::error!("Failed to query habit: {:?}", e);
+                return ServiceResponse::err("我没打卡失败：数据库错误");
+            }
+        };
+        let now = self.clock.now();
+        let checkpoint = get_checkpoint(now);
 
         let conn_guard = self.conn.lock().unwrap();
 
         let mut 我没打卡_stmt = conn_guard
-            .prepare_cached("DELETE FROM `bot_daka` WHERE `user_id` = ?1 AND `created_at` >= ?2")
+            .prepare_cached(
+                "UPDATE `bot_daka` SET `deleted_at` = ?3
+                WHERE `user_id` = ?1 AND `created_at` >= ?2 AND `deleted_at` IS NULL AND `habit_id` = ?4
+                RETURNING `id`, `created_at`, `note`",
+            )
             .expect("Prepare statement failed");
 
-        let res = 我没打卡_stmt.execute(params![user_id, checkpoint.naive_utc()]);
+        let res = 我没打卡_stmt
+            .query_map(
+                params![user_id, checkpoint.naive_utc(), db_time(now), habit.id],
+                |row| {
+                    let id: i64 = row.get(0)?;
+                    let created_at: String = row.get(1)?;
+                    let note: String = row.get(2)?;
+                    Ok(serde_json::json!({"id": id, "created_at": created_at, "note": note}))
+                },
+            )
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>());
         drop(我没打卡_stmt);
+        if let Ok(deleted) = &res
+            && !deleted.is_empty()
+        {
+            AuditEntry {
+                actor_id: Some(user_id),
+                channel,
+                action: "daka.delete",
+                target_id: Some(user_id),
+                before: Some(serde_json::Value::Array(deleted.clone())),
+                after: None,
+            }
+            .write(&conn_guard);
+            self.publish(
+                &conn_guard,
+                ServiceEvent::Cancel {
+                    member_id: user_id,
+                    name: member_name(&conn_guard, user_id),
+                },
+            );
+        }
         drop(conn_guard);
         let msg = match res {
-            Ok(0) => ServiceResponse::ok("确实"),
+            Ok(deleted) if deleted.is_empty() => ServiceResponse::ok("确实"),
             Ok(_) => ServiceResponse::ok("行吧"),
             Err(e) => {
                 tracing::error!("Failed to insert record: {:?}", e);
@@ -218,28 +515,164 @@ impl super::Service {
         msg
     }
 
-    pub fn handle_打卡(&self, user_id: i64, _args: &str) -> ServiceResponse {
-        let checkpoint = get_checkpoint();
+    /// `/打卡 [习惯] [数量单位 | 备注]`, the default habit if none is given. A
+    /// plain check-in counts once a day, check-ins with an amount such as
+    /// `30分钟` add up. Other text, `/打卡 今天背了50个单词`, is kept as the
+    /// note of a plain check-in.
+    pub fn handle_打卡(&self, user_id: i64, args: &str, channel: Channel) -> ServiceResponse {
+        // anything after the habit that is not an amount is kept as a note
+        let (habit, amount, note) = match self.habit_from_args(args) {
+            Ok((habit, "")) => (habit, None, ""),
+            Ok((habit, rest)) => match Amount::parse(rest) {
+                Some(amount) => (habit, Some(amount), ""),
+                None if rest.starts_with(|c: char| c.is_ascii_digit()) => {
+                    return ServiceResponse::err(DAKA_USAGE);
+                }
+                None => (habit, None, rest),
+            },
+            Err(e) => {
+                tracing::error!("Failed to query habit: {:?}", e);
+                return ServiceResponse::err("打卡失败：数据库错误");
+            }
+        };
+        self.daka(user_id, &habit, amount, note, channel)
+    }
+
+    /// Check in for `habit`, with an amount or else a note, and reply with
+    /// the daily report.
+    pub fn daka(
+        &self,
+        user_id: i64,
+        habit: &Habit,
+        amount: Option<Amount>,
+        note: &str,
+        channel: Channel,
+    ) -> ServiceResponse {
+        let now = self.clock.now();
+        let checkpoint = get_checkpoint(now);
 
         let conn_guard = self.conn.lock().unwrap();
 
-        let mut 打卡_stmt = conn_guard
-            .prepare_cached(
-                "INSERT INTO `bot_daka` (`user_id`) SELECT ?1 WHERE NOT EXISTS (
-            SELECT 1 FROM `bot_daka` WHERE `user_id` = ?1 AND `created_at` >= ?2
-        )",
-            )
-            .expect("Prepare statement failed");
+        // amounts of a day only add up in one unit, the goal's if there is one
+        if let Some(amount) = &amount {
+            let unit = goal_for(&conn_guard, habit.id, user_id).and_then(|goal| match goal {
+                Some(goal) => Ok(Some(goal.unit)),
+                None => conn_guard
+                    .prepare_cached(
+                        "SELECT `unit` FROM `bot_daka` WHERE `user_id` = ?1 AND `created_at` >= ?2
+                        AND `deleted_at` IS NULL AND `habit_id` = ?3 AND `unit` IS NOT NULL LIMIT 1",
+                    )?
+                    .query_row(
+                        params![user_id, checkpoint.naive_utc(), habit.id],
+                        |r| r.get(0),
+                    )
+                    .optional(),
+            });
+            match unit {
+                Ok(Some(unit)) if unit != amount.unit => {
+                    return ServiceResponse::err(format!("单位应为「{}」", unit));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!("Failed to query unit: {:?}", e);
+                    return ServiceResponse::err("打卡失败：数据库错误");
+                }
+            }
+        }
 
-        let res = 打卡_stmt.execute(params![user_id, checkpoint.naive_utc()]);
-        drop(打卡_stmt);
+        let created = |row: &rusqlite::Row| {
+            let id: i64 = row.get(0)?;
+            let created_at: String = row.get(1)?;
+            let created_time: DateTime<Utc> = row.get(1)?;
+            let amount: Option<f64> = row.get(2)?;
+            let unit: Option<String> = row.get(3)?;
+            let note: String = row.get(4)?;
+            Ok((
+                serde_json::json!({
+                    "id": id,
+                    "created_at": created_at,
+                    "habit_id": habit.id,
+                    "amount": amount,
+                    "unit": unit,
+                    "note": note,
+                }),
+                created_time,
+            ))
+        };
+        let res = match &amount {
+            None => conn_guard
+                .prepare_cached(
+                    "INSERT INTO `bot_daka` (`user_id`, `created_at`, `habit_id`, `note`) SELECT ?1, ?3, ?4, ?5 WHERE NOT EXISTS (
+                SELECT 1 FROM `bot_daka` WHERE `user_id` = ?1 AND `created_at` >= ?2 AND `deleted_at` IS NULL
+                    AND `habit_id` = ?4
+            ) RETURNING `id`, `created_at`, `amount`, `unit`, `note`",
+                )
+                .expect("Prepare statement failed")
+                .query_row(
+                    params![user_id, checkpoint.naive_utc(), db_time(now), habit.id, note],
+                    created,
+                )
+                .optional(),
+            Some(amount) => conn_guard
+                .prepare_cached(
+                    "INSERT INTO `bot_daka` (`user_id`, `created_at`, `habit_id`, `amount`, `unit`)
+                    VALUES (?1, ?2, ?3, ?4, ?5) RETURNING `id`, `created_at`, `amount`, `unit`, `note`",
+                )
+                .expect("Prepare statement failed")
+                .query_row(
+                    params![user_id, db_time(now), habit.id, amount.amount, amount.unit],
+                    created,
+                )
+                .map(Some),
+        };
+        if let Ok(Some((created, created_time))) = &res {
+            AuditEntry {
+                actor_id: Some(user_id),
+                channel,
+                action: "daka.create",
+                target_id: Some(user_id),
+                before: None,
+                after: Some(created.clone()),
+            }
+            .write(&conn_guard);
+            self.publish(
+                &conn_guard,
+                ServiceEvent::Daka {
+                    member_id: user_id,
+                    name: member_name(&conn_guard, user_id),
+                    time: created_time
+                        .with_timezone(&BOT_TZ)
+                        .format("%H:%M")
+                        .to_string(),
+                },
+            );
+        }
+        // a check-in without an amount adds nothing towards a goal
+        let goal = match (&amount, &res) {
+            (None, Ok(Some(_))) => goal_for(&conn_guard, habit.id, user_id).unwrap_or_else(|e| {
+                tracing::error!("Failed to query goal: {:?}", e);
+                None
+            }),
+            _ => None,
+        };
         drop(conn_guard);
 
         let mut _daily_report = String::new();
         let msg = match res {
-            Ok(0) => ServiceResponse::ok("您今天已经打过卡莉"),
-            Ok(_) => {
-                _daily_report = self.build_daily_report();
+            Ok(None) => ServiceResponse::ok("您今天已经打过卡莉"),
+            Ok(Some(_)) => {
+                _daily_report = self.build_daily_report(habit);
+                if let Some(goal) = goal {
+                    let command = if habit.is_default {
+                        "/打卡".to_string()
+                    } else {
+                        format!("/打卡 {}", habit.name)
+                    };
+                    _daily_report += &format!(
+                        "\n目标是每天{}，打卡时带上数量才算进度，如 {} {}",
+                        goal, command, goal
+                    );
+                }
                 ServiceResponse::ok(_daily_report.clone())
             }
             Err(e) => {
@@ -251,101 +684,382 @@ impl super::Service {
         msg
     }
 
-    pub fn handle_咕(
+    /// Revert the most recent check-in or cancellation of a member, if it
+    /// happened within `UNDO_WINDOW`.
+    pub fn handle_撤销(&self, user_id: i64, _args: &str, channel: Channel) -> ServiceResponse {
+        let window_start = self.clock.now() - UNDO_WINDOW;
+
+        let conn_guard = self.conn.lock().unwrap();
+        // keep the raw text of deleted_at to match all records cancelled together
+        let last_cancel: Result<Option<String>, _> = conn_guard
+            .prepare_cached(
+                "SELECT MAX(`deleted_at`) FROM `bot_daka` WHERE `user_id` = ?1 AND `deleted_at` >= ?2",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(params![user_id, window_start.naive_utc()], |r| r.get(0))
+            });
+        let last_daka: Result<Option<(i64, DateTime<Utc>)>, _> = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `created_at` FROM `bot_daka`
+                WHERE `user_id` = ?1 AND `created_at` >= ?2 AND `deleted_at` IS NULL
+                ORDER BY `created_at` DESC LIMIT 1",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(params![user_id, window_start.naive_utc()], |r| {
+                    Ok((r.get(0)?, r.get(1)?))
+                })
+                .optional()
+            });
+        let (last_cancel, last_daka) = match (last_cancel, last_daka) {
+            (Ok(c), Ok(d)) => (c, d),
+            (Err(e), _) | (_, Err(e)) => {
+                tracing::error!("Failed to query undo candidates: {:?}", e);
+                return ServiceResponse::err("撤销失败：数据库错误");
+            }
+        };
+
+        // whichever happened last is reverted
+        let cancel_is_latest = match (&last_cancel, &last_daka) {
+            (Some(deleted_at), Some((_, created_at))) => {
+                NaiveDateTime::parse_from_str(deleted_at, "%Y-%m-%d %H:%M:%S%.f")
+                    .is_ok_and(|deleted_at| deleted_at > created_at.naive_utc())
+            }
+            (Some(_), None) => true,
+            _ => false,
+        };
+        let res = if cancel_is_latest {
+            let deleted_at = last_cancel.unwrap_or_default();
+            self.restore_cancelled(&conn_guard, user_id, &deleted_at, channel)
+        } else if let Some((id, created_at)) = last_daka {
+            self.cancel_daka(&conn_guard, user_id, id, created_at, channel)
+        } else {
+            Ok(ServiceResponse::ok("没有可以撤销的操作"))
+        };
+        drop(conn_guard);
+
+        match res {
+            Ok(msg) => msg,
+            Err(e) => {
+                tracing::error!("Failed to undo: {:?}", e);
+                ServiceResponse::err("撤销失败：数据库错误")
+            }
+        }
+    }
+
+    /// Bring back the records of a member cancelled at `deleted_at`.
+    fn restore_cancelled(
         &self,
-        _group_uin: u32,
-        _group_member: &GroupMember,
-        _args: &str,
-    ) -> ServiceResponse {
-        // reuse the query logic to obtain lists and format the message
-        match self.query_missed_and_warning() {
-            Ok((missed, warn)) => {
-                if missed.is_empty() && warn.is_empty() {
-                    ServiceResponse::ok("没有人咕咕".to_string())
-                } else {
-                    let failed_msg = if missed.is_empty() {
-                        "".to_string()
-                    } else {
-                        format!("💢 10天没打卡：\n{}", missed.join("\u{3000}"))
-                    };
-                    let warning_msg = if warn.is_empty() {
-                        "".to_string()
-                    } else {
-                        format!("⚠️ 7天没打卡：\n{}", warn.join("\u{3000}"))
-                    };
-                    ServiceResponse::ok(format!("{}\n{}", failed_msg, warning_msg))
+        conn: &rusqlite::Connection,
+        user_id: i64,
+        deleted_at: &str,
+        channel: Channel,
+    ) -> rusqlite::Result<ServiceResponse> {
+        let mut stmt = conn.prepare_cached(
+            "UPDATE `bot_daka` SET `deleted_at` = NULL
+            WHERE `user_id` = ?1 AND `deleted_at` = ?2
+            RETURNING `id`, `created_at`",
+        )?;
+        let restored = stmt
+            .query_map(params![user_id, deleted_at], |row| {
+                let id: i64 = row.get(0)?;
+                let created_at: DateTime<Utc> = row.get(1)?;
+                Ok((id, created_at))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        let ids = restored.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        AuditEntry {
+            actor_id: Some(user_id),
+            channel,
+            action: "daka.restore",
+            target_id: Some(user_id),
+            before: Some(serde_json::json!({"ids": ids, "deleted_at": deleted_at})),
+            after: Some(serde_json::json!({"ids": ids})),
+        }
+        .write(conn);
+        Ok(
+            match restored.iter().map(|(_, created_at)| created_at).max() {
+                Some(created_at) => {
+                    let time = created_at
+                        .with_timezone(&BOT_TZ)
+                        .format("%H:%M")
+                        .to_string();
+                    self.publish(
+                        conn,
+                        ServiceEvent::Restore {
+                            member_id: user_id,
+                            name: member_name(conn, user_id),
+                            time: time.clone(),
+                        },
+                    );
+                    ServiceResponse::ok(format!("已恢复打卡（{}）", time))
                 }
+                None => ServiceResponse::ok("没有可以撤销的操作"),
+            },
+        )
+    }
+
+    /// Cancel a single check-in record of a member.
+    fn cancel_daka(
+        &self,
+        conn: &rusqlite::Connection,
+        user_id: i64,
+        id: i64,
+        created_at: DateTime<Utc>,
+        channel: Channel,
+    ) -> rusqlite::Result<ServiceResponse> {
+        conn.prepare_cached("UPDATE `bot_daka` SET `deleted_at` = ?2 WHERE `id` = ?1")?
+            .execute(params![id, db_time(self.clock.now())])?;
+        AuditEntry {
+            actor_id: Some(user_id),
+            channel,
+            action: "daka.undo",
+            target_id: Some(user_id),
+            before: Some(serde_json::json!({
+                "id": id,
+                "created_at": created_at.naive_utc().to_string()
+            })),
+            after: None,
+        }
+        .write(conn);
+        self.publish(
+            conn,
+            ServiceEvent::Cancel {
+                member_id: user_id,
+                name: member_name(conn, user_id),
+            },
+        );
+        Ok(ServiceResponse::ok("已撤销打卡"))
+    }
+
+    /// `/咕 [习惯]` report of a group: each tier with the members in it.
+    pub fn handle_咕(&self, group_uin: u32, args: &str) -> ServiceResponse {
+        let habit = match self.habit_from_args(args) {
+            Ok((habit, "")) => habit,
+            Ok((_, name)) => return self.unknown_habit(name),
+            Err(e) => {
+                error!("Failed to query habit: {:?}", e);
+                return ServiceResponse::err("咕咕查询失败：数据库错误");
+            }
+        };
+        let report = match self.gu_report(Some(group_uin), habit.id) {
+            Ok(report) => report,
+            Err(e) => {
+                error!("Failed to query records for 咕: {:?}", e);
+                return ServiceResponse::err("咕咕查询失败：数据库错误");
+            }
+        };
+        if report.is_empty() {
+            return ServiceResponse::ok("没有人咕咕".to_string());
+        }
+        let sections = report
+            .iter()
+            .map(|(tier, members)| {
+                let names = members
+                    .iter()
+                    .map(|m| match m.last_checkin {
+                        Some(_) => format!("{}({}天)", m.name, m.inactive_days),
+                        None => format!("{}(从未打卡)", m.name),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\u{3000}");
+                format!("{} {}：\n{}", tier.emoji, tier.label, names)
+            })
+            .collect::<Vec<_>>();
+        ServiceResponse::ok(sections.join("\n"))
+    }
+
+    /// `/咕 [习惯] @someone`: how long one member has gone without a check-in.
+    pub fn handle_咕_member(&self, group_uin: u32, qq_uin: u32, args: &str) -> ServiceResponse {
+        let habit = match self.habit_from_args(args) {
+            Ok((habit, "")) => habit,
+            Ok((_, name)) => return self.unknown_habit(name),
+            Err(e) => {
+                error!("Failed to query habit: {:?}", e);
+                return ServiceResponse::err("咕咕查询失败：数据库错误");
             }
+        };
+        let members = match self.query_member_activity(habit.id) {
+            Ok(members) => members,
             Err(e) => {
                 error!("Failed to query records for 咕: {:?}", e);
-                ServiceResponse::err("咕咕查询失败：数据库错误")
+                return ServiceResponse::err("咕咕查询失败：数据库错误");
+            }
+        };
+        let Some(member) = members.iter().find(|m| m.qq_uin == qq_uin) else {
+            return ServiceResponse::ok("没有找到该成员");
+        };
+        if member.checked_in_today {
+            return ServiceResponse::ok(format!("{} 今天已经打卡了", member.name));
+        }
+        if let Some(until) = &member.on_leave_until {
+            return ServiceResponse::ok(format!("🏖 {} 请假中（到 {}）", member.name, until));
+        }
+        let today = checkpoint_date_of(self.clock.now());
+        let yesterday = (today - chrono::Duration::days(1)).to_string();
+        let text = match &member.last_checkin {
+            None => format!("{} 还没有打过卡", member.name),
+            Some(last) if *last == yesterday => format!("{} 昨天打过卡", member.name),
+            // the days since were all rest days or leave
+            Some(last) if member.inactive_days == 0 => {
+                format!("{} 上次打卡是 {}，之后都是休息日或假期", member.name, last)
+            }
+            Some(last) => format!(
+                "{} 已经{}天没打卡（上次 {}）",
+                member.name, member.inactive_days, last
+            ),
+        };
+        // like `gu_report`, members in their grace period are not called out
+        let config = self.gu_config(Some(group_uin));
+        if (today - member.joined).num_days() < config.grace_days {
+            return ServiceResponse::ok(text);
+        }
+        ServiceResponse::ok(match config.tier_for(member.inactive_days) {
+            Some(tier) => format!("{} {}", tier.emoji, text),
+            None => text,
+        })
+    }
+
+    /// Tiers of the `/咕` settings of a group with the members in them,
+    /// leaving out empty tiers, members in their grace period and members on leave.
+    pub fn gu_report(
+        &self,
+        group_uin: Option<u32>,
+        habit_id: i64,
+    ) -> Result<Vec<(GuTier, Vec<MemberActivity>)>, String> {
+        let config = self.gu_config(group_uin);
+        let today = checkpoint_date_of(self.clock.now());
+        let mut report: Vec<(GuTier, Vec<MemberActivity>)> = config
+            .tiers
+            .iter()
+            .map(|tier| (tier.clone(), Vec::new()))
+            .collect();
+        for member in self.query_member_activity(habit_id)? {
+            // on leave today, however long they were gone before it
+            if (today - member.joined).num_days() < config.grace_days
+                || member.on_leave_until.is_some()
+            {
+                continue;
+            }
+            if let Some(index) = config
+                .tiers
+                .iter()
+                .position(|t| member.inactive_days >= t.days)
+            {
+                report[index].1.push(member);
             }
         }
+        report.retain(|(_, members)| !members.is_empty());
+        Ok(report)
     }
 
-    /// Return two lists: missed in last 10 days (never daka in window) and warning list (last daka older than 7 days)
-    pub fn query_missed_and_warning(&self) -> Result<(Vec<String>, Vec<String>), String> {
-        let checkpoint_end = get_checkpoint();
-        let checkpoint_start = checkpoint_end - chrono::Duration::days(10);
+    /// Every member taking part in a habit with the time since their last
+    /// check-in of it. Only members who checked in for a habit other than the
+    /// default one take part in it, from their first check-in on.
+    pub fn query_member_activity(&self, habit_id: i64) -> Result<Vec<MemberActivity>, String> {
+        let today = checkpoint_date_of(self.clock.now());
+        let leave = self.leave_calendar()?;
+        let rest = self.rest_calendar()?;
 
         let conn_guard = self.conn.lock().unwrap();
-        let mut get_records_10day_stmt = conn_guard
+        let mut stmt = conn_guard
             .prepare_cached(
-                "SELECT
-                `bot_group_member`.`group_nickname`,
-                (
-                    SELECT `created_at` FROM `bot_daka`
-                    WHERE `bot_daka`.`user_id` = `bot_group_member`.`id`
-                    AND `bot_daka`.`created_at` >= ?1 AND `bot_daka`.`created_at` < ?2
-                    ORDER BY `bot_daka`.`id` DESC LIMIT 1
-                ) AS `last_daka_at`
-            FROM `bot_group_member`
-            ORDER BY `bot_group_member`.`sort_key` ASC, `bot_group_member`.`id` ASC",
+                "SELECT `bot_group_member`.`id`, `bot_group_member`.`qq_uin`,
+                    `bot_group_member`.`group_nickname`, `bot_group_member`.`created_at`,
+                    MIN(`bot_daka`.`created_at`), MAX(`bot_daka`.`created_at`), `bot_habit`.`is_default`
+                FROM `bot_group_member`
+                JOIN `bot_habit` ON `bot_habit`.`id` = ?1
+                LEFT JOIN `bot_daka` ON `bot_daka`.`user_id` = `bot_group_member`.`id`
+                    AND `bot_daka`.`deleted_at` IS NULL AND `bot_daka`.`habit_id` = ?1
+                GROUP BY `bot_group_member`.`id`
+                HAVING `bot_habit`.`is_default` OR COUNT(`bot_daka`.`id`) > 0
+                ORDER BY `bot_group_member`.`sort_key` ASC, `bot_group_member`.`id` ASC",
             )
             .map_err(|e| format!("prepare failed: {:?}", e))?;
-
-        #[derive(Debug, Clone)]
-        struct DakaRecord {
-            group_nickname: String,
-            last_daka_at: Option<DateTime<FixedOffset>>,
-        }
-        let res = get_records_10day_stmt
-            .query_map(
-                params![checkpoint_start.naive_utc(), checkpoint_end.naive_utc()],
-                |row| {
-                    let group_nickname: String = row.get(0)?;
-                    let created_at: Option<DateTime<Utc>> = row.get(1)?;
-                    Ok(DakaRecord {
-                        group_nickname,
-                        last_daka_at: created_at.map(|dt| dt.with_timezone(&BOT_TZ)),
-                    })
-                },
-            )
+        let res = stmt
+            .query_map([habit_id], |row| {
+                let member_id: i64 = row.get(0)?;
+                let created_at: DateTime<Utc> = row.get(3)?;
+                let first_daka_at: Option<DateTime<Utc>> = row.get(4)?;
+                let last_daka_at: Option<DateTime<Utc>> = row.get(5)?;
+                let is_default: bool = row.get(6)?;
+                // imported history can predate the member record
+                let joined = checkpoint_date_of(match first_daka_at {
+                    Some(first) if is_default => first.min(created_at),
+                    Some(first) => first,
+                    None => created_at,
+                });
+                let last_day = last_daka_at.map(checkpoint_date_of);
+                // full days missed, not counting today which is not over yet,
+                // days on leave and rest days
+                let inactive_days = last_day
+                    .map_or(joined, |last| last + chrono::Duration::days(1))
+                    .iter_days()
+                    .take_while(|d| *d < today)
+                    .filter(|d| !leave.on_leave(member_id, *d) && !rest.is_rest_day(*d))
+                    .count();
+                Ok(MemberActivity {
+                    member_id,
+                    qq_uin: row.get(1)?,
+                    name: row.get(2)?,
+                    last_checkin: last_day.map(|d| d.to_string()),
+                    inactive_days: inactive_days as i64,
+                    checked_in_today: last_day == Some(today),
+                    on_leave_until: leave.leave_end(member_id, today).map(|d| d.to_string()),
+                    joined,
+                })
+            })
             .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
             .map_err(|e| format!("query failed: {:?}", e))?;
-        drop(get_records_10day_stmt);
+        drop(stmt);
         drop(conn_guard);
+        Ok(res)
+    }
+}
 
-        let failed_group_members = res
-            .iter()
-            .filter(|record| {
-                if let Some(last_daka_at) = record.last_daka_at {
-                    last_daka_at < checkpoint_start
-                } else {
-                    true
-                }
-            })
-            .map(|record| record.group_nickname.clone())
-            .collect::<Vec<_>>();
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
 
-        let warning_checkpoint = checkpoint_end - chrono::Duration::days(7);
-        let warning_group_members = res
-            .iter()
-            .filter_map(|record| {
-                (record.last_daka_at? < warning_checkpoint).then_some(record.group_nickname.clone())
-            })
-            .collect::<Vec<_>>();
+    use super::*;
+    use crate::service::clock::Clock;
+    use crate::service::init_in_memory;
+
+    fn at(day: u32) -> DateTime<Utc> {
+        BOT_TZ
+            .with_ymd_and_hms(2024, 3, day, 8, 0, 0)
+            .unwrap()
+            .to_utc()
+    }
+
+    #[test]
+    fn gu_member_leaves_out_the_tier_during_the_grace_period() {
+        let clock = Clock::manual(at(1));
+        let svc = init_in_memory(clock.clone(), HashSet::new());
+        let tiers = [GuTier {
+            days: 3,
+            label: "3天没打卡".to_string(),
+            emoji: "⚠️".to_string(),
+        }];
+        svc.set_gu_config(1, Some(&tiers), Some(7), None, Channel::Web)
+            .unwrap();
+        let alice = GroupMember {
+            uid: "u_10001".to_string(),
+            uin: 10001,
+            member_name: Some("Alice".to_string()),
+            member_card: None,
+        };
+        let member_id = svc.upsert_member(&alice, Channel::Bot).unwrap();
+        let habit = svc.find_habit(None).unwrap().unwrap();
+        assert!(svc.daka(member_id, &habit, None, "", Channel::Bot).ok);
+
+        clock.set(at(6));
+        let text = svc.handle_咕_member(1, 10001, "").message;
+        assert_eq!(text, "Alice 已经4天没打卡（上次 2024-03-01）");
 
-        Ok((failed_group_members, warning_group_members))
+        clock.set(at(9));
+        let text = svc.handle_咕_member(1, 10001, "").message;
+        assert_eq!(text, "⚠️ Alice 已经7天没打卡（上次 2024-03-01）");
     }
 }
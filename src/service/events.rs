@@ -0,0 +1,81 @@
+use rusqlite::Connection;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Capacity of the in-process event bus. Slow subscribers that fall further
+/// behind than this miss events and are told how many they lost.
+pub(super) const EVENT_BUS_CAPACITY: usize = 64;
+
+/// Something that happened to the check-in data, whatever the channel it came from.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServiceEvent {
+    /// `time` is the UTC+8 check-in time, `HH:MM`
+    Daka {
+        member_id: i64,
+        name: String,
+        time: String,
+    },
+    Cancel {
+        member_id: i64,
+        name: String,
+    },
+    Restore {
+        member_id: i64,
+        name: String,
+        time: String,
+    },
+    /// A member joined or changed their group nickname.
+    Member {
+        member_id: i64,
+        name: String,
+        previous_name: Option<String>,
+    },
+    /// The bot session expired and an admin has to scan a QR code.
+    LoginRequired {
+        reason: String,
+    },
+}
+
+impl ServiceEvent {
+    /// Name of the event, same as the `type` field of its JSON form.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServiceEvent::Daka { .. } => "daka",
+            ServiceEvent::Cancel { .. } => "cancel",
+            ServiceEvent::Restore { .. } => "restore",
+            ServiceEvent::Member { .. } => "member",
+            ServiceEvent::LoginRequired { .. } => "login_required",
+        }
+    }
+}
+
+/// Group nickname of a member for event payloads, empty if unknown.
+pub(super) fn member_name(conn: &Connection, member_id: i64) -> String {
+    conn.prepare_cached("SELECT `group_nickname` FROM `bot_group_member` WHERE `id` = ?1")
+        .and_then(|mut stmt| stmt.query_row([member_id], |r| r.get(0)))
+        .unwrap_or_default()
+}
+
+impl super::Service {
+    pub fn subscribe(&self) -> broadcast::Receiver<ServiceEvent> {
+        self.events.subscribe()
+    }
+
+    /// Queue webhook deliveries for the event on the connection the change was
+    /// made on, then announce it to in-process subscribers.
+    pub(super) fn publish(&self, conn: &Connection, event: ServiceEvent) {
+        super::webhook::enqueue_deliveries(conn, &event);
+        let action = match event {
+            ServiceEvent::Daka { .. } => Some("created"),
+            ServiceEvent::Cancel { .. } => Some("cancelled"),
+            ServiceEvent::Restore { .. } => Some("restored"),
+            ServiceEvent::Member { .. } | ServiceEvent::LoginRequired { .. } => None,
+        };
+        if let Some(action) = action {
+            crate::metrics::CHECKINS.with_label_values(&[action]).inc();
+        }
+        // sending only fails when nobody is listening
+        let _ = self.events.send(event);
+    }
+}
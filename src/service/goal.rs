@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::NaiveDateTime;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+
+use super::audit::AuditEntry;
+use super::clock::db_time;
+use super::daka::{checkpoint_date_of, checkpoint_of};
+use super::habit::Habit;
+use super::models::{Channel, ServiceResponse};
+
+const MAX_UNIT_CHARS: usize = 10;
+/// Largest amount of a single check-in or goal.
+const MAX_AMOUNT: f64 = 1_000_000.0;
+const GOAL_USAGE: &str = "用法：/目标 [习惯] [全员] [<数量单位> | 清除]，如 /目标 60分钟";
+
+/// How much of something, e.g. 30分钟 or 2.5公里.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Amount {
+    pub amount: f64,
+    pub unit: String,
+}
+
+impl Amount {
+    pub fn new(amount: f64, unit: &str) -> Result<Self, String> {
+        if !amount.is_finite() || amount <= 0.0 || amount > MAX_AMOUNT {
+            return Err(format!(
+                "amount must be positive and at most {}",
+                MAX_AMOUNT
+            ));
+        }
+        let unit = unit.trim();
+        if unit.is_empty() || unit.chars().count() > MAX_UNIT_CHARS {
+            return Err(format!("unit must be 1 to {} characters", MAX_UNIT_CHARS));
+        }
+        if unit.contains(char::is_whitespace) || unit.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err("unit must not contain spaces or start with a digit".to_string());
+        }
+        Ok(Self {
+            amount,
+            unit: unit.to_string(),
+        })
+    }
+
+    /// Parse the bot command form: a number followed by a unit, `30分钟`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let split = s.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+        let (amount, unit) = s.split_at(split);
+        Self::new(amount.parse().ok()?, unit).ok()
+    }
+}
+
+/// An amount without float noise, `45` rather than `45.0`.
+pub fn format_amount(amount: f64) -> String {
+    format!("{}", (amount * 100.0).round() / 100.0)
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", format_amount(self.amount), self.unit)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Goal {
+    pub habit_id: i64,
+    /// `None` for the goal of the whole group
+    pub member_id: Option<i64>,
+    #[serde(flatten)]
+    pub target: Amount,
+}
+
+/// Daily goals of one habit, to look up by member.
+#[derive(Debug, Clone, Default)]
+pub struct Goals {
+    group: Option<Amount>,
+    members: HashMap<i64, Amount>,
+}
+
+impl Goals {
+    /// The goal of a member, falling back to the group goal.
+    pub fn for_member(&self, member_id: i64) -> Option<&Amount> {
+        self.members.get(&member_id).or(self.group.as_ref())
+    }
+}
+
+fn goal_from_row(row: &rusqlite::Row) -> rusqlite::Result<Goal> {
+    Ok(Goal {
+        habit_id: row.get(0)?,
+        member_id: row.get(1)?,
+        target: Amount {
+            amount: row.get(2)?,
+            unit: row.get(3)?,
+        },
+    })
+}
+
+fn get_goal(
+    conn: &Connection,
+    habit_id: i64,
+    member_id: Option<i64>,
+) -> rusqlite::Result<Option<Goal>> {
+    conn.prepare_cached(
+        "SELECT `habit_id`, `member_id`, `amount`, `unit` FROM `bot_goal`
+        WHERE `habit_id` = ?1 AND `member_id` IS ?2",
+    )?
+    .query_row(params![habit_id, member_id], goal_from_row)
+    .optional()
+}
+
+/// The goal a member of habit `habit_id` follows, their own or the group's.
+pub(super) fn goal_for(
+    conn: &Connection,
+    habit_id: i64,
+    member_id: i64,
+) -> rusqlite::Result<Option<Amount>> {
+    conn.prepare_cached(
+        "SELECT `amount`, `unit` FROM `bot_goal`
+        WHERE `habit_id` = ?1 AND (`member_id` = ?2 OR `member_id` IS NULL)
+        ORDER BY `member_id` IS NULL LIMIT 1",
+    )?
+    .query_row(params![habit_id, member_id], |row| {
+        Ok(Amount {
+            amount: row.get(0)?,
+            unit: row.get(1)?,
+        })
+    })
+    .optional()
+}
+
+/// A unit other than `unit` that members following the goal of `member_id`
+/// (`None` for the group's) checked in with today. Amounts of a day add up in
+/// one unit, so a goal in another one cannot be set until tomorrow.
+fn unit_conflict(
+    conn: &Connection,
+    habit_id: i64,
+    member_id: Option<i64>,
+    unit: &str,
+    today_start: NaiveDateTime,
+) -> rusqlite::Result<Option<String>> {
+    conn.prepare_cached(
+        "SELECT `unit` FROM `bot_daka`
+        WHERE `habit_id` = ?1 AND `created_at` >= ?2 AND `deleted_at` IS NULL
+            AND `unit` IS NOT NULL AND `unit` != ?3
+            AND (`user_id` = ?4 OR (?4 IS NULL AND `user_id` NOT IN (
+                SELECT `member_id` FROM `bot_goal` WHERE `habit_id` = ?1 AND `member_id` IS NOT NULL
+            )))
+        LIMIT 1",
+    )?
+    .query_row(params![habit_id, today_start, unit, member_id], |r| {
+        r.get(0)
+    })
+    .optional()
+}
+
+impl super::Service {
+    /// See [`unit_conflict`].
+    pub fn goal_unit_conflict(
+        &self,
+        habit_id: i64,
+        member_id: Option<i64>,
+        unit: &str,
+    ) -> Result<Option<String>, String> {
+        let today_start = checkpoint_of(checkpoint_date_of(self.clock.now())).naive_utc();
+        let conn_guard = self.conn.lock().unwrap();
+        let res = unit_conflict(&conn_guard, habit_id, member_id, unit, today_start)
+            .map_err(|e| format!("query failed: {:?}", e));
+        drop(conn_guard);
+        res
+    }
+
+    pub fn goals(&self, habit_id: i64) -> Result<Goals, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `habit_id`, `member_id`, `amount`, `unit` FROM `bot_goal` WHERE `habit_id` = ?1",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map([habit_id], goal_from_row)
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+
+        let mut goals = Goals::default();
+        for goal in rows {
+            match goal.member_id {
+                Some(member_id) => {
+                    goals.members.insert(member_id, goal.target);
+                }
+                None => goals.group = Some(goal.target),
+            }
+        }
+        Ok(goals)
+    }
+
+    /// The goal of the group (`member_id` `None`) or of one member only, not
+    /// falling back to the group goal.
+    pub fn get_goal(&self, habit_id: i64, member_id: Option<i64>) -> Result<Option<Goal>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let res = get_goal(&conn_guard, habit_id, member_id)
+            .map_err(|e| format!("query failed: {:?}", e));
+        drop(conn_guard);
+        res
+    }
+
+    /// Set the daily goal of the group (`member_id` `None`) or of one member,
+    /// replacing the one there was. Fails if today's check-ins it applies to
+    /// are in another unit, see [`Self::goal_unit_conflict`].
+    pub fn set_goal(
+        &self,
+        habit_id: i64,
+        member_id: Option<i64>,
+        target: Amount,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<Goal, String> {
+        let mut conn_guard = self.conn.lock().unwrap();
+        let tx = conn_guard
+            .transaction()
+            .map_err(|e| format!("begin failed: {:?}", e))?;
+        let today_start = checkpoint_of(checkpoint_date_of(self.clock.now())).naive_utc();
+        if let Some(unit) = unit_conflict(&tx, habit_id, member_id, &target.unit, today_start)
+            .map_err(|e| format!("query failed: {:?}", e))?
+        {
+            return Err(format!("checked in with unit {} today", unit));
+        }
+        let previous =
+            get_goal(&tx, habit_id, member_id).map_err(|e| format!("query failed: {:?}", e))?;
+        tx.execute(
+            "DELETE FROM `bot_goal` WHERE `habit_id` = ?1 AND `member_id` IS ?2",
+            params![habit_id, member_id],
+        )
+        .and_then(|_| {
+            tx.execute(
+                "INSERT INTO `bot_goal` (`created_at`, `habit_id`, `member_id`, `amount`, `unit`)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    db_time(self.clock.now()),
+                    habit_id,
+                    member_id,
+                    target.amount,
+                    target.unit
+                ],
+            )
+        })
+        .map_err(|e| format!("update failed: {:?}", e))?;
+        let goal = Goal {
+            habit_id,
+            member_id,
+            target,
+        };
+        AuditEntry {
+            actor_id,
+            channel,
+            action: "goal.set",
+            target_id: member_id,
+            before: previous.map(|g| serde_json::json!(g)),
+            after: Some(serde_json::json!(goal)),
+        }
+        .write(&tx);
+        tx.commit().map_err(|e| format!("commit failed: {:?}", e))?;
+        drop(conn_guard);
+        Ok(goal)
+    }
+
+    /// Returns the goal there was, `None` if there was none.
+    pub fn clear_goal(
+        &self,
+        habit_id: i64,
+        member_id: Option<i64>,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<Option<Goal>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let Some(goal) = get_goal(&conn_guard, habit_id, member_id)
+            .map_err(|e| format!("query failed: {:?}", e))?
+        else {
+            return Ok(None);
+        };
+        conn_guard
+            .prepare_cached("DELETE FROM `bot_goal` WHERE `habit_id` = ?1 AND `member_id` IS ?2")
+            .and_then(|mut stmt| stmt.execute(params![habit_id, member_id]))
+            .map_err(|e| format!("delete failed: {:?}", e))?;
+        AuditEntry {
+            actor_id,
+            channel,
+            action: "goal.clear",
+            target_id: member_id,
+            before: Some(serde_json::json!(goal)),
+            after: None,
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(Some(goal))
+    }
+
+    /// Bot command: `/目标 [习惯]` shows the daily goals, `/目标 [习惯]
+    /// <数量单位>` or `清除` sets or clears the member's own, and admins do the
+    /// same for the whole group with `全员`.
+    pub fn handle_目标(&self, user_id: i64, args: &str, is_admin: bool) -> ServiceResponse {
+        let (habit, rest) = match self.habit_from_args(args) {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::error!("Failed to query habit: {}", e);
+                return ServiceResponse::err("目标查询失败：数据库错误");
+            }
+        };
+        let (member_id, rest) = match rest.strip_prefix("全员") {
+            Some(_) if !is_admin => return ServiceResponse::err("仅管理员可用"),
+            Some(rest) => (None, rest.trim()),
+            None => (Some(user_id), rest),
+        };
+        let scope = if member_id.is_none() { "全员" } else { "" };
+        let habit_name = habit_prefix(&habit);
+
+        match rest {
+            "" => {}
+            "清除" => {
+                return match self.clear_goal(habit.id, member_id, Some(user_id), Channel::Bot) {
+                    Ok(Some(_)) => {
+                        ServiceResponse::ok(format!("已清除{}{}目标", habit_name, scope))
+                    }
+                    Ok(None) => ServiceResponse::err(format!("没有{}{}目标", habit_name, scope)),
+                    Err(e) => {
+                        tracing::error!("Failed to clear goal: {}", e);
+                        ServiceResponse::err("清除目标失败：数据库错误")
+                    }
+                };
+            }
+            _ => {
+                let Some(target) = Amount::parse(rest) else {
+                    // a word that is not an amount is taken for a habit name
+                    if member_id.is_some()
+                        && !rest.contains(' ')
+                        && !rest.starts_with(|c: char| c.is_ascii_digit())
+                    {
+                        return self.unknown_habit(rest);
+                    }
+                    return ServiceResponse::err(GOAL_USAGE);
+                };
+                match self.goal_unit_conflict(habit.id, member_id, &target.unit) {
+                    Ok(Some(unit)) => {
+                        return ServiceResponse::err(format!(
+                            "今天已有按「{}」的打卡，明天再改用「{}」吧",
+                            unit, target.unit
+                        ));
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        tracing::error!("Failed to query goal unit: {}", e);
+                        return ServiceResponse::err("设置目标失败：数据库错误");
+                    }
+                }
+                return match self.set_goal(habit.id, member_id, target, Some(user_id), Channel::Bot)
+                {
+                    Ok(goal) => ServiceResponse::ok(format!(
+                        "已设置{}{}目标：每天{}",
+                        habit_name, scope, goal.target
+                    )),
+                    Err(e) => {
+                        tracing::error!("Failed to set goal: {}", e);
+                        ServiceResponse::err("设置目标失败：数据库错误")
+                    }
+                };
+            }
+        }
+
+        let res = self
+            .get_goal(habit.id, None)
+            .and_then(|group| Ok((group, self.get_goal(habit.id, Some(user_id))?)));
+        match res {
+            Ok((None, None)) => ServiceResponse::ok(format!("没有{}目标", habit_name)),
+            Ok((group, own)) => {
+                let mut lines = Vec::new();
+                if let Some(group) = group {
+                    lines.push(format!("{}全员目标：每天{}", habit_name, group.target));
+                }
+                if let Some(own) = own {
+                    lines.push(format!("{}你的目标：每天{}", habit_name, own.target));
+                }
+                ServiceResponse::ok(lines.join("\n"))
+            }
+            Err(e) => {
+                tracing::error!("Failed to query goal: {}", e);
+                ServiceResponse::err("目标查询失败：数据库错误")
+            }
+        }
+    }
+}
+
+/// Name of a habit to put before a reply, empty for the default habit.
+fn habit_prefix(habit: &Habit) -> String {
+    if habit.is_default {
+        String::new()
+    } else {
+        format!("{} ", habit.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_amounts() {
+        let amount = Amount::parse("30分钟").unwrap();
+        assert_eq!((amount.amount, amount.unit.as_str()), (30.0, "分钟"));
+        let amount = Amount::parse(" 2.5km ").unwrap();
+        assert_eq!((amount.amount, amount.unit.as_str()), (2.5, "km"));
+        assert_eq!(amount.to_string(), "2.5km");
+    }
+
+    #[test]
+    fn parse_rejects_invalid_amounts() {
+        for s in [
+            "分钟",
+            "30",
+            "0分钟",
+            "1.2.3分钟",
+            "2000000分钟",
+            "30一二三四五六七八九十百",
+        ] {
+            assert_eq!(Amount::parse(s), None, "{}", s);
+        }
+    }
+}
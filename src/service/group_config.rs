@@ -0,0 +1,208 @@
+use rusqlite::{OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+use super::audit::AuditEntry;
+use super::models::Channel;
+
+/// Members who joined fewer days ago than this are left out of `/咕` unless
+/// the group sets its own value.
+const DEFAULT_GU_GRACE_DAYS: i64 = 7;
+
+/// One level of the `/咕` report: members who have gone at least `days` full
+/// bot days without a check-in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GuTier {
+    pub days: i64,
+    pub label: String,
+    pub emoji: String,
+}
+
+fn default_gu_tiers() -> Vec<GuTier> {
+    vec![
+        GuTier {
+            days: 10,
+            label: "10天没打卡".to_string(),
+            emoji: "💢".to_string(),
+        },
+        GuTier {
+            days: 7,
+            label: "7天没打卡".to_string(),
+            emoji: "⚠️".to_string(),
+        },
+    ]
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GuConfig {
+    /// Highest `days` first
+    pub tiers: Vec<GuTier>,
+    pub grace_days: i64,
+}
+
+impl Default for GuConfig {
+    fn default() -> Self {
+        Self {
+            tiers: default_gu_tiers(),
+            grace_days: DEFAULT_GU_GRACE_DAYS,
+        }
+    }
+}
+
+impl GuConfig {
+    /// Check a configuration given by an admin; `None` keeps the default.
+    pub fn new(tiers: Option<Vec<GuTier>>, grace_days: Option<i64>) -> Result<Self, String> {
+        let mut config = Self::default();
+        if let Some(mut tiers) = tiers {
+            if tiers.is_empty() {
+                return Err("at least one tier is required".to_string());
+            }
+            if tiers.iter().any(|t| t.days <= 0) {
+                return Err("tier days must be positive".to_string());
+            }
+            if tiers.iter().any(|t| t.label.trim().is_empty()) {
+                return Err("tier label must not be empty".to_string());
+            }
+            tiers.sort_by_key(|t| std::cmp::Reverse(t.days));
+            if tiers.windows(2).any(|w| w[0].days == w[1].days) {
+                return Err("tier days must be distinct".to_string());
+            }
+            config.tiers = tiers;
+        }
+        if let Some(grace_days) = grace_days {
+            if grace_days < 0 {
+                return Err("grace_days must not be negative".to_string());
+            }
+            config.grace_days = grace_days;
+        }
+        Ok(config)
+    }
+
+    /// The highest tier reached after `inactive_days` days without a check-in.
+    pub fn tier_for(&self, inactive_days: i64) -> Option<&GuTier> {
+        self.tiers.iter().find(|t| inactive_days >= t.days)
+    }
+}
+
+impl super::Service {
+    /// `/咕` settings of a group, the defaults for `None` or a group without
+    /// its own settings.
+    pub fn gu_config(&self, group_uin: Option<u32>) -> GuConfig {
+        let Some(group_uin) = group_uin else {
+            return GuConfig::default();
+        };
+        let conn_guard = self.conn.lock().unwrap();
+        let res: Result<Option<(Option<String>, Option<i64>)>, _> = conn_guard
+            .prepare_cached(
+                "SELECT `gu_tiers`, `gu_grace_days` FROM `bot_group_config` WHERE `group_uin` = ?1",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row([group_uin], |r| Ok((r.get(0)?, r.get(1)?)))
+                    .optional()
+            });
+        drop(conn_guard);
+
+        let (tiers, grace_days) = match res {
+            Ok(Some(row)) => row,
+            Ok(None) => return GuConfig::default(),
+            Err(e) => {
+                tracing::error!("Failed to load config of group {}: {:?}", group_uin, e);
+                return GuConfig::default();
+            }
+        };
+        let tiers = tiers.and_then(|t| match serde_json::from_str(&t) {
+            Ok(tiers) => Some(tiers),
+            Err(e) => {
+                tracing::error!("Invalid gu_tiers of group {}: {:?}", group_uin, e);
+                None
+            }
+        });
+        GuConfig::new(tiers, grace_days).unwrap_or_else(|e| {
+            tracing::error!("Invalid /咕 config of group {}: {}", group_uin, e);
+            GuConfig::default()
+        })
+    }
+
+    /// Store the `/咕` settings of a group. `None` resets a setting to the default.
+    pub fn set_gu_config(
+        &self,
+        group_uin: u32,
+        tiers: Option<&[GuTier]>,
+        grace_days: Option<i64>,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<(), String> {
+        let before = self.gu_config(Some(group_uin));
+        let tiers_json = tiers
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| format!("serialize failed: {:?}", e))?;
+
+        let conn_guard = self.conn.lock().unwrap();
+        conn_guard
+            .prepare_cached(
+                "INSERT INTO `bot_group_config` (`group_uin`, `gu_tiers`, `gu_grace_days`)
+                VALUES (?1, ?2, ?3)
+                ON CONFLICT (`group_uin`)
+                    DO UPDATE SET `gu_tiers` = excluded.gu_tiers, `gu_grace_days` = excluded.gu_grace_days",
+            )
+            .and_then(|mut stmt| stmt.execute(params![group_uin, tiers_json, grace_days]))
+            .map_err(|e| format!("execute failed: {:?}", e))?;
+        AuditEntry {
+            actor_id,
+            channel,
+            action: "group.gu_config",
+            target_id: None,
+            before: Some(serde_json::json!({"group_uin": group_uin, "gu": before})),
+            after: Some(serde_json::json!({
+                "group_uin": group_uin,
+                "gu": {"tiers": tiers, "grace_days": grace_days}
+            })),
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tier(days: i64, label: &str) -> GuTier {
+        GuTier {
+            days,
+            label: label.to_string(),
+            emoji: String::new(),
+        }
+    }
+
+    #[test]
+    fn new_keeps_defaults_and_sorts_tiers() {
+        let config = GuConfig::new(None, None).unwrap();
+        assert_eq!(config.tiers, default_gu_tiers());
+        assert_eq!(config.grace_days, DEFAULT_GU_GRACE_DAYS);
+
+        let config = GuConfig::new(Some(vec![tier(3, "3天"), tier(14, "两周")]), Some(0)).unwrap();
+        assert_eq!(config.tiers, vec![tier(14, "两周"), tier(3, "3天")]);
+        assert_eq!(config.grace_days, 0);
+        assert_eq!(config.tier_for(5).map(|t| t.days), Some(3));
+        assert_eq!(config.tier_for(2), None);
+    }
+
+    #[test]
+    fn new_rejects_invalid_config() {
+        for tiers in [
+            vec![],
+            vec![tier(0, "今天")],
+            vec![tier(3, " ")],
+            vec![tier(3, "3天"), tier(3, "三天")],
+        ] {
+            assert!(
+                GuConfig::new(Some(tiers.clone()), None).is_err(),
+                "{:?}",
+                tiers
+            );
+        }
+        assert!(GuConfig::new(None, Some(-1)).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+
+use super::audit::AuditEntry;
+use super::clock::db_time;
+use super::models::{Channel, ServiceResponse};
+
+const MAX_HABIT_NAME_CHARS: usize = 20;
+const HABIT_USAGE: &str = "用法：/习惯 [添加 <名称> | 默认 <名称>]";
+/// Arguments of other commands that a habit name must not be mistaken for.
+const RESERVED_NAMES: [&str; 11] = [
+    "周", "月", "总", "week", "month", "all", "全员", "清除", "添加", "默认", "删除",
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Habit {
+    pub id: i64,
+    pub name: String,
+    /// The habit of plain `/打卡`
+    pub is_default: bool,
+}
+
+fn habit_from_row(row: &rusqlite::Row) -> rusqlite::Result<Habit> {
+    Ok(Habit {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        is_default: row.get(2)?,
+    })
+}
+
+fn get_habit(conn: &Connection, id: i64) -> rusqlite::Result<Option<Habit>> {
+    conn.prepare_cached("SELECT `id`, `name`, `is_default` FROM `bot_habit` WHERE `id` = ?1")?
+        .query_row([id], habit_from_row)
+        .optional()
+}
+
+/// Id of the habit plain `/打卡` goes to.
+pub(super) fn default_habit_id(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.prepare_cached("SELECT `id` FROM `bot_habit` WHERE `is_default` = 1")?
+        .query_row([], |r| r.get(0))
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.chars().count() > MAX_HABIT_NAME_CHARS {
+        return Err(format!(
+            "name must be 1 to {} characters",
+            MAX_HABIT_NAME_CHARS
+        ));
+    }
+    if name.contains(char::is_whitespace) || name.starts_with(['/', '@']) {
+        return Err("name must not contain spaces or start with / or @".to_string());
+    }
+    // `/打卡 30分钟` is an amount, not a habit
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err("name must not start with a digit".to_string());
+    }
+    if RESERVED_NAMES.contains(&name) {
+        return Err(format!("{} is reserved", name));
+    }
+    Ok(())
+}
+
+impl super::Service {
+    /// All habits, the default one first.
+    pub fn list_habits(&self) -> Result<Vec<Habit>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `name`, `is_default` FROM `bot_habit` ORDER BY `is_default` DESC, `id` ASC",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let res = stmt
+            .query_map([], habit_from_row)
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(res)
+    }
+
+    /// The habit called `name`, or the default habit for `None`. Returns
+    /// `None` if there is no habit with that name.
+    pub fn find_habit(&self, name: Option<&str>) -> Result<Option<Habit>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let res = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `name`, `is_default` FROM `bot_habit`
+                WHERE (?1 IS NULL AND `is_default` = 1) OR `name` = ?1",
+            )
+            .and_then(|mut stmt| stmt.query_row([name], habit_from_row).optional())
+            .map_err(|e| format!("query failed: {:?}", e));
+        drop(conn_guard);
+        res
+    }
+
+    /// Split a habit name off the front of command arguments: the named
+    /// habit and the rest, or the default habit and all of `args` if they do
+    /// not start with a habit name.
+    pub fn habit_from_args<'a>(&self, args: &'a str) -> Result<(Habit, &'a str), String> {
+        let args = args.trim();
+        let (first, rest) = args.split_once(' ').unwrap_or((args, ""));
+        // habits added before a word was reserved keep to the command
+        if !first.is_empty()
+            && !RESERVED_NAMES.contains(&first)
+            && let Some(habit) = self.find_habit(Some(first))?
+        {
+            return Ok((habit, rest.trim()));
+        }
+        let habit = self.find_habit(None)?.ok_or("no default habit")?;
+        Ok((habit, args))
+    }
+
+    /// Reply to a command naming a habit that does not exist.
+    pub fn unknown_habit(&self, name: &str) -> ServiceResponse {
+        match self.list_habits() {
+            Ok(habits) => ServiceResponse::err(format!(
+                "没有习惯「{}」，现有：{}",
+                name,
+                habits
+                    .iter()
+                    .map(|h| h.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join("、")
+            )),
+            Err(e) => {
+                tracing::error!("Failed to list habits: {}", e);
+                ServiceResponse::err(format!("没有习惯「{}」", name))
+            }
+        }
+    }
+
+    pub fn create_habit(
+        &self,
+        name: &str,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<Habit, String> {
+        check_name(name)?;
+        if self.find_habit(Some(name))?.is_some() {
+            return Err(format!("habit {} already exists", name));
+        }
+
+        let conn_guard = self.conn.lock().unwrap();
+        let id: i64 = conn_guard
+            .prepare_cached(
+                "INSERT INTO `bot_habit` (`created_at`, `name`) VALUES (?1, ?2) RETURNING `id`",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(params![db_time(self.clock.now()), name], |r| r.get(0))
+            })
+            .map_err(|e| format!("insert failed: {:?}", e))?;
+        let habit = get_habit(&conn_guard, id)
+            .map_err(|e| format!("query failed: {:?}", e))?
+            .expect("inserted habit exists");
+        AuditEntry {
+            actor_id,
+            channel,
+            action: "habit.create",
+            target_id: None,
+            before: None,
+            after: Some(serde_json::json!(habit)),
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(habit)
+    }
+
+    /// Make habit `id` the one plain `/打卡` goes to. Returns `None` if there
+    /// is no such habit.
+    pub fn set_default_habit(
+        &self,
+        id: i64,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<Option<Habit>, String> {
+        let mut conn_guard = self.conn.lock().unwrap();
+        let tx = conn_guard
+            .transaction()
+            .map_err(|e| format!("begin failed: {:?}", e))?;
+        let Some(habit) = get_habit(&tx, id).map_err(|e| format!("query failed: {:?}", e))? else {
+            return Ok(None);
+        };
+        let previous = default_habit_id(&tx).map_err(|e| format!("query failed: {:?}", e))?;
+        tx.execute(
+            "UPDATE `bot_habit` SET `is_default` = 0 WHERE `is_default` = 1",
+            [],
+        )
+        .and_then(|_| {
+            tx.execute(
+                "UPDATE `bot_habit` SET `is_default` = 1 WHERE `id` = ?1",
+                [id],
+            )
+        })
+        .map_err(|e| format!("update failed: {:?}", e))?;
+        AuditEntry {
+            actor_id,
+            channel,
+            action: "habit.default",
+            target_id: None,
+            before: Some(serde_json::json!({"default_habit_id": previous})),
+            after: Some(serde_json::json!({"default_habit_id": id})),
+        }
+        .write(&tx);
+        tx.commit().map_err(|e| format!("commit failed: {:?}", e))?;
+        drop(conn_guard);
+        Ok(Some(Habit {
+            is_default: true,
+            ..habit
+        }))
+    }
+
+    /// Bot command: `/习惯` lists the habits, admins can `添加` one or make
+    /// one the `默认`.
+    pub fn handle_习惯(
+        &self,
+        args: &str,
+        actor_id: Option<i64>,
+        is_admin: bool,
+    ) -> ServiceResponse {
+        let args = args.trim();
+        let (action, name) = args.split_once(' ').unwrap_or((args, ""));
+        let name = name.trim();
+        match action {
+            "" => {}
+            "添加" | "默认" if !is_admin => return ServiceResponse::err("仅管理员可用"),
+            "添加" => {
+                if RESERVED_NAMES.contains(&name) {
+                    return ServiceResponse::err(format!(
+                        "「{}」是命令用词，不能用作习惯名称",
+                        name
+                    ));
+                }
+                if check_name(name).is_err() {
+                    return ServiceResponse::err(format!(
+                        "习惯名称需为1到{}个字，不能有空格，不能以数字、/ 或 @ 开头",
+                        MAX_HABIT_NAME_CHARS
+                    ));
+                }
+                return match self.find_habit(Some(name)) {
+                    Ok(Some(_)) => ServiceResponse::err(format!("已经有习惯「{}」了", name)),
+                    Ok(None) => match self.create_habit(name, actor_id, Channel::Bot) {
+                        Ok(habit) => ServiceResponse::ok(format!(
+                            "已添加习惯「{}」，用 /打卡 {} 打卡",
+                            habit.name, habit.name
+                        )),
+                        Err(e) => {
+                            tracing::error!("Failed to create habit: {}", e);
+                            ServiceResponse::err("添加习惯失败：数据库错误")
+                        }
+                    },
+                    Err(e) => {
+                        tracing::error!("Failed to query habit: {}", e);
+                        ServiceResponse::err("添加习惯失败：数据库错误")
+                    }
+                };
+            }
+            "默认" => {
+                let habit = match self.find_habit(Some(name)) {
+                    Ok(Some(habit)) => habit,
+                    Ok(None) => return ServiceResponse::err(format!("没有习惯「{}」", name)),
+                    Err(e) => {
+                        tracing::error!("Failed to query habit: {}", e);
+                        return ServiceResponse::err("设置默认习惯失败：数据库错误");
+                    }
+                };
+                return match self.set_default_habit(habit.id, actor_id, Channel::Bot) {
+                    Ok(_) => ServiceResponse::ok(format!("/打卡 现在默认是「{}」", habit.name)),
+                    Err(e) => {
+                        tracing::error!("Failed to set default habit: {}", e);
+                        ServiceResponse::err("设置默认习惯失败：数据库错误")
+                    }
+                };
+            }
+            _ => return ServiceResponse::err(HABIT_USAGE),
+        }
+
+        match self.list_habits() {
+            Ok(habits) => ServiceResponse::ok(
+                habits
+                    .iter()
+                    .map(|h| {
+                        if h.is_default {
+                            format!("{}（默认）", h.name)
+                        } else {
+                            h.name.clone()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            Err(e) => {
+                tracing::error!("Failed to list habits: {}", e);
+                ServiceResponse::err("习惯查询失败：数据库错误")
+            }
+        }
+    }
+}
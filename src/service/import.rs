@@ -0,0 +1,320 @@
+use std::collections::HashSet;
+
+use chrono::prelude::*;
+use rusqlite::{OptionalExtension, params};
+use serde::Serialize;
+
+use super::audit::AuditEntry;
+use super::daka::{BOT_CHECKPOINT, BOT_TZ};
+use super::habit::default_habit_id;
+use super::models::Channel;
+
+/// A problem with a single CSV row. `line` is the 1-based line the row starts on.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportIssue {
+    pub line: usize,
+    pub qq_uin: Option<u32>,
+    pub date: Option<String>,
+    pub reason: String,
+}
+
+/// Outcome of a CSV import. On a dry run nothing is written, but the report
+/// is what a real run would produce.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total_rows: usize,
+    pub imported: usize,
+    pub members_created: Vec<u32>,
+    pub duplicates: Vec<ImportIssue>,
+    pub conflicts: Vec<ImportIssue>,
+    pub errors: Vec<ImportIssue>,
+}
+
+impl ImportReport {
+    pub fn summary(&self) -> String {
+        let mut out = format!(
+            "{}rows: {}, imported: {}, new members: {}, duplicates: {}, conflicts: {}, errors: {}",
+            if self.dry_run { "[dry run] " } else { "" },
+            self.total_rows,
+            self.imported,
+            self.members_created.len(),
+            self.duplicates.len(),
+            self.conflicts.len(),
+            self.errors.len()
+        );
+        for (kind, issues) in [
+            ("duplicate", &self.duplicates),
+            ("conflict", &self.conflicts),
+            ("error", &self.errors),
+        ] {
+            for issue in issues {
+                out.push_str(&format!(
+                    "\nline {}: {}: {}",
+                    issue.line, kind, issue.reason
+                ));
+            }
+        }
+        out
+    }
+}
+
+struct ImportRow {
+    line: usize,
+    qq_uin: u32,
+    day: NaiveDate,
+    created_at: DateTime<Utc>,
+    note: String,
+}
+
+/// Split CSV text into records of fields, keeping the line each record starts on.
+/// Supports quoted fields with `""` escapes and embedded newlines.
+fn split_csv_records(text: &str) -> Vec<(usize, Vec<String>)> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut line = 1;
+    let mut record_line = 1;
+    let mut chars = text.trim_start_matches('\u{feff}').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            '\r' if !in_quotes => {}
+            '\n' => {
+                line += 1;
+                if in_quotes {
+                    field.push('\n');
+                    continue;
+                }
+                fields.push(std::mem::take(&mut field));
+                if fields.iter().any(|f| !f.trim().is_empty()) {
+                    records.push((record_line, std::mem::take(&mut fields)));
+                } else {
+                    fields.clear();
+                }
+                record_line = line;
+            }
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    if fields.iter().any(|f| !f.trim().is_empty()) {
+        records.push((record_line, fields));
+    }
+    records
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    ["%Y-%m-%d", "%Y/%m/%d", "%Y.%m.%d"]
+        .iter()
+        .find_map(|f| NaiveDate::parse_from_str(s, f).ok())
+}
+
+fn parse_time(s: &str) -> Option<NaiveTime> {
+    ["%H:%M:%S", "%H:%M"]
+        .iter()
+        .find_map(|f| NaiveTime::parse_from_str(s, f).ok())
+}
+
+/// Parse one `qq_uin,date,time,note` record. The date is the bot day, so a
+/// time before the checkpoint belongs to the early hours of the next calendar day.
+fn parse_import_row(line: usize, fields: &[String]) -> Result<ImportRow, ImportIssue> {
+    let field = |i: usize| fields.get(i).map(|f| f.trim()).unwrap_or_default();
+    let issue = |qq_uin, reason: String| ImportIssue {
+        line,
+        qq_uin,
+        date: Some(field(1).to_string()).filter(|d| !d.is_empty()),
+        reason,
+    };
+
+    let qq_uin: u32 = field(0)
+        .parse()
+        .map_err(|_| issue(None, format!("invalid qq_uin {:?}", field(0))))?;
+    let day = parse_date(field(1))
+        .ok_or_else(|| issue(Some(qq_uin), format!("invalid date {:?}", field(1))))?;
+    let time = if field(2).is_empty() {
+        BOT_CHECKPOINT
+    } else {
+        parse_time(field(2))
+            .ok_or_else(|| issue(Some(qq_uin), format!("invalid time {:?}", field(2))))?
+    };
+    let local_date = if time < BOT_CHECKPOINT {
+        day.succ_opt().expect("Valid next date")
+    } else {
+        day
+    };
+    let created_at = BOT_TZ
+        .from_local_datetime(&NaiveDateTime::new(local_date, time))
+        .single()
+        .ok_or_else(|| issue(Some(qq_uin), "invalid date/time".to_string()))?
+        .with_timezone(&Utc);
+
+    Ok(ImportRow {
+        line,
+        qq_uin,
+        day,
+        created_at,
+        note: field(3).to_string(),
+    })
+}
+
+impl super::Service {
+    /// Import historical check-ins from CSV rows of `qq_uin,date,time,note`.
+    /// Unknown members are created as placeholders that are claimed once they
+    /// talk to the bot. Rows that duplicate another row of the file, or that hit
+    /// a day already checked in, are skipped and reported. With `dry_run` the
+    /// whole import is rolled back.
+    pub fn import_daka_csv(
+        &self,
+        text: &str,
+        dry_run: bool,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<ImportReport, String> {
+        let mut report = ImportReport {
+            dry_run,
+            ..Default::default()
+        };
+        let mut rows = Vec::new();
+        for (index, (line, fields)) in split_csv_records(text).into_iter().enumerate() {
+            // optional header row
+            if index == 0 && fields[0].trim().eq_ignore_ascii_case("qq_uin") {
+                continue;
+            }
+            report.total_rows += 1;
+            match parse_import_row(line, &fields) {
+                Ok(row) => rows.push(row),
+                Err(issue) => report.errors.push(issue),
+            }
+        }
+
+        let mut conn_guard = self.conn.lock().unwrap();
+        let tx = conn_guard
+            .transaction()
+            .map_err(|e| format!("begin transaction failed: {:?}", e))?;
+        {
+            // imported check-ins go to the default habit
+            let habit_id = default_habit_id(&tx).map_err(|e| format!("query failed: {:?}", e))?;
+            let mut find_member_stmt = tx
+                .prepare_cached("SELECT `id` FROM `bot_group_member` WHERE `qq_uin` = ?1")
+                .map_err(|e| format!("prepare failed: {:?}", e))?;
+            let mut create_member_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO `bot_group_member` (`qq_uid`, `qq_uin`, `nickname`, `group_nickname`)
+                    VALUES (?1, ?2, ?3, ?3) RETURNING `id`",
+                )
+                .map_err(|e| format!("prepare failed: {:?}", e))?;
+            let mut exists_stmt = tx
+                .prepare_cached(
+                    "SELECT 1 FROM `bot_daka` WHERE `user_id` = ?1 AND `created_at` >= ?2 AND `created_at` < ?3
+                    AND `deleted_at` IS NULL AND `habit_id` = ?4",
+                )
+                .map_err(|e| format!("prepare failed: {:?}", e))?;
+            let mut insert_stmt = tx
+                .prepare_cached(
+                    "INSERT INTO `bot_daka` (`user_id`, `created_at`, `note`, `habit_id`)
+                    VALUES (?1, ?2, ?3, ?4)",
+                )
+                .map_err(|e| format!("prepare failed: {:?}", e))?;
+
+            let mut seen = HashSet::new();
+            for row in rows {
+                let issue = |reason: &str| ImportIssue {
+                    line: row.line,
+                    qq_uin: Some(row.qq_uin),
+                    date: Some(row.day.to_string()),
+                    reason: reason.to_string(),
+                };
+                if !seen.insert((row.qq_uin, row.day)) {
+                    report.duplicates.push(issue(
+                        "member already checked in on this day earlier in the file",
+                    ));
+                    continue;
+                }
+
+                let member_id: Option<i64> = find_member_stmt
+                    .query_row([row.qq_uin], |r| r.get(0))
+                    .optional()
+                    .map_err(|e| format!("query failed: {:?}", e))?;
+                let member_id = match member_id {
+                    Some(id) => id,
+                    None => {
+                        let id: i64 = create_member_stmt
+                            .query_row(
+                                params![
+                                    format!("import:{}", row.qq_uin),
+                                    row.qq_uin,
+                                    row.qq_uin.to_string()
+                                ],
+                                |r| r.get(0),
+                            )
+                            .map_err(|e| format!("create member failed: {:?}", e))?;
+                        report.members_created.push(row.qq_uin);
+                        id
+                    }
+                };
+
+                let day_start = BOT_TZ
+                    .from_local_datetime(&NaiveDateTime::new(row.day, BOT_CHECKPOINT))
+                    .single()
+                    .expect("Valid checkpoint datetime");
+                let day_end = day_start + chrono::Duration::days(1);
+                let exists = exists_stmt
+                    .exists(params![
+                        member_id,
+                        day_start.naive_utc(),
+                        day_end.naive_utc(),
+                        habit_id
+                    ])
+                    .map_err(|e| format!("query failed: {:?}", e))?;
+                if exists {
+                    report
+                        .conflicts
+                        .push(issue("member already has a check-in on this day"));
+                    continue;
+                }
+
+                insert_stmt
+                    .execute(params![
+                        member_id,
+                        row.created_at.naive_utc(),
+                        row.note,
+                        habit_id
+                    ])
+                    .map_err(|e| format!("insert failed: {:?}", e))?;
+                report.imported += 1;
+            }
+        }
+        let res = if dry_run {
+            tx.rollback()
+        } else {
+            AuditEntry {
+                actor_id,
+                channel,
+                action: "daka.import",
+                target_id: None,
+                before: None,
+                after: Some(serde_json::json!({
+                    "imported": report.imported,
+                    "members_created": report.members_created,
+                })),
+            }
+            .write(&tx);
+            tx.commit()
+        };
+        res.map_err(|e| format!("finish transaction failed: {:?}", e))?;
+        drop(conn_guard);
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+
+use chrono::prelude::*;
+use rusqlite::params;
+use serde::Serialize;
+
+use super::daka::{BOT_CHECKPOINT, checkpoint_date_of, checkpoint_of};
+use super::habit::Habit;
+use super::models::ServiceResponse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Week,
+    Month,
+    All,
+}
+
+impl Period {
+    /// Accepts the bot command arguments (`周`, `月`, `总`) as well as the
+    /// API values (`week`, `month`, `all`).
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "周" | "week" => Some(Self::Week),
+            "月" | "month" => Some(Self::Month),
+            "总" | "all" => Some(Self::All),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Week => "week",
+            Self::Month => "month",
+            Self::All => "all",
+        }
+    }
+
+    /// First bot day of the period containing `today`, `None` for all time.
+    fn start(&self, today: NaiveDate) -> Option<NaiveDate> {
+        match self {
+            Self::Week => {
+                Some(today - chrono::Duration::days(today.weekday().num_days_from_monday().into()))
+            }
+            Self::Month => today.with_day(1),
+            Self::All => None,
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Week => "本周排行",
+            Self::Month => "本月排行",
+            Self::All => "总排行",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaderboardEntry {
+    /// Members with the same days and average time share a rank
+    pub rank: usize,
+    pub member_id: i64,
+    pub name: String,
+    /// Bot days with a check-in
+    pub days: usize,
+    /// Average check-in time of day, UTC+8 `HH:MM`
+    pub average_time: String,
+    /// Average minutes after the 04:00 checkpoint, used to break ties
+    #[serde(skip)]
+    average_minutes: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Leaderboard {
+    pub title: String,
+    /// First bot day counted, `None` for all time
+    pub start: Option<NaiveDate>,
+    /// Last bot day counted
+    pub end: NaiveDate,
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+impl Leaderboard {
+    pub fn to_text(&self) -> String {
+        if self.entries.is_empty() {
+            return format!("{}：还没有人打卡", self.title);
+        }
+        let range = match self.start {
+            Some(start) => format!(
+                "{}/{}–{}/{}",
+                start.month(),
+                start.day(),
+                self.end.month(),
+                self.end.day()
+            ),
+            None => format!("截至 {}/{}", self.end.month(), self.end.day()),
+        };
+        let lines = self
+            .entries
+            .iter()
+            .map(|e| format!("{}. {} {}天 {}", e.rank, e.name, e.days, e.average_time))
+            .collect::<Vec<_>>()
+            .join("\n");
+        format!("{}（{}）\n{}", self.title, range, lines)
+    }
+}
+
+/// Start of the first bot day of the month after the one `now` falls in.
+pub fn next_month_start(now: DateTime<Utc>) -> DateTime<Utc> {
+    let today = checkpoint_date_of(now);
+    let next = if today.month() == 12 {
+        NaiveDate::from_ymd_opt(today.year() + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd_opt(today.year(), today.month() + 1, 1)
+    }
+    .expect("valid first day of month");
+    checkpoint_of(next)
+}
+
+/// Rank members by check-in days, then by earliest average check-in time.
+fn rank(rows: Vec<(i64, String, DateTime<Utc>)>) -> Vec<LeaderboardEntry> {
+    // earliest check-in of each member per bot day
+    let mut members: BTreeMap<i64, (String, BTreeMap<NaiveDate, DateTime<Utc>>)> = BTreeMap::new();
+    for (member_id, name, created_at) in rows {
+        let days = &mut members
+            .entry(member_id)
+            .or_insert_with(|| (name, BTreeMap::new()))
+            .1;
+        days.entry(checkpoint_date_of(created_at))
+            .and_modify(|t| *t = (*t).min(created_at))
+            .or_insert(created_at);
+    }
+
+    let mut entries = members
+        .into_iter()
+        .map(|(member_id, (name, days))| {
+            let total: i64 = days
+                .iter()
+                .map(|(date, t)| (*t - checkpoint_of(*date)).num_minutes())
+                .sum();
+            let average_minutes = total / days.len() as i64;
+            let average_time = (BOT_CHECKPOINT + chrono::Duration::minutes(average_minutes))
+                .format("%H:%M")
+                .to_string();
+            LeaderboardEntry {
+                rank: 0,
+                member_id,
+                name,
+                days: days.len(),
+                average_time,
+                average_minutes,
+            }
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|e| (std::cmp::Reverse(e.days), e.average_minutes));
+
+    // tied members share the rank of the first of them
+    let mut previous: Option<(usize, i64, usize)> = None;
+    for (index, entry) in entries.iter_mut().enumerate() {
+        entry.rank = match previous {
+            Some((days, minutes, rank))
+                if (days, minutes) == (entry.days, entry.average_minutes) =>
+            {
+                rank
+            }
+            _ => index + 1,
+        };
+        previous = Some((entry.days, entry.average_minutes, entry.rank));
+    }
+    entries
+}
+
+impl super::Service {
+    /// Leaderboard of a habit for the period containing today, up to today.
+    pub fn leaderboard(&self, period: Period, habit: &Habit) -> Result<Leaderboard, String> {
+        let today = checkpoint_date_of(self.clock.now());
+        let title = if habit.is_default {
+            period.title().to_string()
+        } else {
+            format!("{} {}", habit.name, period.title())
+        };
+        self.leaderboard_between(title, period.start(today), today, habit.id)
+    }
+
+    /// Final leaderboard of the default habit for the month before the
+    /// current one.
+    pub fn last_month_leaderboard(&self) -> Result<Leaderboard, String> {
+        let habit = self.find_habit(None)?.ok_or("no default habit")?;
+        let today = checkpoint_date_of(self.clock.now());
+        let end = today
+            .with_day(1)
+            .and_then(|d| d.pred_opt())
+            .expect("valid date");
+        let start = end.with_day(1).expect("valid date");
+        self.leaderboard_between(format!("{}月排行", end.month()), Some(start), end, habit.id)
+    }
+
+    fn leaderboard_between(
+        &self,
+        title: String,
+        start: Option<NaiveDate>,
+        end: NaiveDate,
+        habit_id: i64,
+    ) -> Result<Leaderboard, String> {
+        let from = start.map(checkpoint_of);
+        let until = checkpoint_of(end + chrono::Duration::days(1));
+
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `bot_daka`.`user_id`, `bot_group_member`.`group_nickname`, `bot_daka`.`created_at`
+                FROM `bot_daka` JOIN `bot_group_member` ON `bot_group_member`.`id` = `bot_daka`.`user_id`
+                WHERE (?1 IS NULL OR `bot_daka`.`created_at` >= ?1) AND `bot_daka`.`created_at` < ?2
+                    AND `bot_daka`.`deleted_at` IS NULL AND `bot_daka`.`habit_id` = ?3",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map(
+                params![from.map(|dt| dt.naive_utc()), until.naive_utc(), habit_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+
+        Ok(Leaderboard {
+            title,
+            start,
+            end,
+            entries: rank(rows),
+        })
+    }
+
+    /// Bot command: `/排行 [周|月|总] [习惯]`, this month and the default
+    /// habit by default.
+    pub fn handle_排行(&self, args: &str) -> ServiceResponse {
+        let (habit, args) = match self.habit_from_args(args) {
+            Ok(res) => res,
+            Err(e) => {
+                tracing::error!("Failed to query habit: {}", e);
+                return ServiceResponse::err("排行查询失败：数据库错误");
+            }
+        };
+        let (period, rest) = args.split_once(' ').unwrap_or((args, ""));
+        let period = if period.is_empty() {
+            Period::Month
+        } else {
+            match Period::parse(period) {
+                Some(period) => period,
+                None => return ServiceResponse::err("用法：/排行 [周|月|总] [习惯]"),
+            }
+        };
+        // the habit may also follow the period
+        let habit = match rest.trim() {
+            "" => habit,
+            name => match self.find_habit(Some(name)) {
+                Ok(Some(habit)) => habit,
+                Ok(None) => return self.unknown_habit(name),
+                Err(e) => {
+                    tracing::error!("Failed to query habit: {}", e);
+                    return ServiceResponse::err("排行查询失败：数据库错误");
+                }
+            },
+        };
+        match self.leaderboard(period, &habit) {
+            Ok(board) => ServiceResponse::ok(board.to_text()),
+            Err(e) => {
+                tracing::error!("Failed to build leaderboard: {}", e);
+                ServiceResponse::err("排行查询失败：数据库错误")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::daka::BOT_TZ;
+
+    fn row(member_id: i64, day: u32, hour: u32, minute: u32) -> (i64, String, DateTime<Utc>) {
+        let time = BOT_TZ
+            .with_ymd_and_hms(2024, 3, day, hour, minute, 0)
+            .unwrap();
+        (member_id, format!("m{}", member_id), time.to_utc())
+    }
+
+    #[test]
+    fn rank_shares_ranks_between_ties() {
+        let entries = rank(vec![
+            row(1, 1, 8, 0),
+            row(1, 2, 8, 0),
+            // only the earliest check-in of a day counts
+            row(1, 2, 9, 0),
+            row(2, 1, 7, 0),
+            row(2, 2, 9, 0),
+            row(3, 1, 7, 0),
+            row(4, 1, 7, 0),
+            // before 4:00 is the bot day before
+            row(5, 2, 3, 0),
+        ]);
+        let ranks = entries
+            .iter()
+            .map(|e| (e.member_id, e.rank, e.days, e.average_time.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranks,
+            vec![
+                (1, 1, 2, "08:00"),
+                (2, 1, 2, "08:00"),
+                (3, 3, 1, "07:00"),
+                (4, 3, 1, "07:00"),
+                (5, 5, 1, "03:00"),
+            ]
+        );
+    }
+}
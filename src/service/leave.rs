@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::prelude::*;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+
+use super::audit::AuditEntry;
+use super::clock::db_time;
+use super::daka::checkpoint_date_of;
+use super::models::{Channel, ServiceResponse};
+
+/// Longest leave that can be taken at once, in days.
+const MAX_LEAVE_DAYS: i64 = 90;
+const LEAVE_USAGE: &str = "用法：/请假 <天数|到日期> [原因]";
+
+/// Whether new leave waits for an admin to approve it, set by
+/// `LEAVE_REQUIRES_APPROVAL=1`. Otherwise it counts right away.
+pub fn leave_requires_approval() -> bool {
+    std::env::var("LEAVE_REQUIRES_APPROVAL").is_ok_and(|v| v == "1" || v == "true")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeaveStatus {
+    Pending,
+    Approved,
+    Rejected,
+    Cancelled,
+}
+
+impl LeaveStatus {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "pending" => Some(Self::Pending),
+            "approved" => Some(Self::Approved),
+            "rejected" => Some(Self::Rejected),
+            "cancelled" => Some(Self::Cancelled),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Rejected => "rejected",
+            Self::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Leave {
+    pub id: i64,
+    pub member_id: i64,
+    pub name: String,
+    /// First and last bot day, `YYYY-MM-DD`, both included
+    pub start: String,
+    pub end: String,
+    pub reason: String,
+    pub status: LeaveStatus,
+    #[serde(skip)]
+    start_date: NaiveDate,
+    #[serde(skip)]
+    end_date: NaiveDate,
+}
+
+impl Leave {
+    fn range_text(&self) -> String {
+        format!(
+            "{}/{}–{}/{}",
+            self.start_date.month(),
+            self.start_date.day(),
+            self.end_date.month(),
+            self.end_date.day()
+        )
+    }
+}
+
+/// Approved leave of every member, to look up by member and bot day.
+#[derive(Debug, Clone, Default)]
+pub struct LeaveCalendar(HashMap<i64, Vec<(NaiveDate, NaiveDate)>>);
+
+impl LeaveCalendar {
+    /// First and last day of each leave of a member.
+    pub fn ranges(&self, member_id: i64) -> &[(NaiveDate, NaiveDate)] {
+        self.0.get(&member_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn on_leave(&self, member_id: i64, date: NaiveDate) -> bool {
+        self.leave_end(member_id, date).is_some()
+    }
+
+    /// Last day of the leave `member_id` is on at `date`.
+    pub fn leave_end(&self, member_id: i64, date: NaiveDate) -> Option<NaiveDate> {
+        self.ranges(member_id)
+            .iter()
+            .find(|(s, e)| *s <= date && date <= *e)
+            .map(|(_, e)| *e)
+    }
+}
+
+const LEAVE_COLUMNS: &str =
+    "`bot_leave`.`id`, `bot_leave`.`member_id`, `bot_group_member`.`group_nickname`,
+    `bot_leave`.`start_date`, `bot_leave`.`end_date`, `bot_leave`.`reason`, `bot_leave`.`status`";
+
+fn leave_from_row(row: &rusqlite::Row) -> rusqlite::Result<Leave> {
+    let start_date: NaiveDate = row.get(3)?;
+    let end_date: NaiveDate = row.get(4)?;
+    Ok(Leave {
+        id: row.get(0)?,
+        member_id: row.get(1)?,
+        name: row.get(2)?,
+        start: start_date.to_string(),
+        end: end_date.to_string(),
+        reason: row.get(5)?,
+        // an unknown status never counts as leave
+        status: LeaveStatus::parse(&row.get::<_, String>(6)?).unwrap_or(LeaveStatus::Cancelled),
+        start_date,
+        end_date,
+    })
+}
+
+/// Why `create_leave` did not create a leave.
+#[derive(Debug)]
+pub enum CreateLeaveError {
+    /// The dates do not make a valid leave
+    Invalid(String),
+    /// The member already has pending or approved leave on one of the days
+    Overlaps(Box<Leave>),
+    Db(String),
+}
+
+impl fmt::Display for CreateLeaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid(e) | Self::Db(e) => f.write_str(e),
+            Self::Overlaps(leave) => write!(
+                f,
+                "overlaps leave {} from {} to {}",
+                leave.id, leave.start, leave.end
+            ),
+        }
+    }
+}
+
+fn get_leave(conn: &Connection, id: i64) -> rusqlite::Result<Option<Leave>> {
+    conn.prepare_cached(&format!(
+        "SELECT {LEAVE_COLUMNS} FROM `bot_leave`
+        JOIN `bot_group_member` ON `bot_group_member`.`id` = `bot_leave`.`member_id`
+        WHERE `bot_leave`.`id` = ?1"
+    ))?
+    .query_row([id], leave_from_row)
+    .optional()
+}
+
+/// Parse the `<天数|到日期>` argument of `/请假` into the last day of leave
+/// starting `today`. Dates are `M/D` (the next such day) or `YYYY-MM-DD`.
+fn parse_leave_end(arg: &str, today: NaiveDate) -> Option<NaiveDate> {
+    let arg = arg.trim_end_matches('天');
+    if let Ok(days) = arg.parse::<i64>() {
+        return (days > 0).then(|| today + chrono::Duration::days(days - 1));
+    }
+    let date = arg.strip_prefix('到').unwrap_or(arg);
+    if let Ok(date) = NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return Some(date);
+    }
+    let (month, day) = date.split_once('/')?;
+    let (month, day) = (month.parse().ok()?, day.parse().ok()?);
+    let date = NaiveDate::from_ymd_opt(today.year(), month, day)?;
+    if date < today {
+        NaiveDate::from_ymd_opt(today.year() + 1, month, day)
+    } else {
+        Some(date)
+    }
+}
+
+impl super::Service {
+    /// Approved leave of all members.
+    pub fn leave_calendar(&self) -> Result<LeaveCalendar, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `member_id`, `start_date`, `end_date` FROM `bot_leave` WHERE `status` = 'approved'",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .and_then(|rows| rows.collect::<Result<Vec<(i64, NaiveDate, NaiveDate)>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+
+        let mut calendar = LeaveCalendar::default();
+        for (member_id, start, end) in rows {
+            calendar.0.entry(member_id).or_default().push((start, end));
+        }
+        Ok(calendar)
+    }
+
+    /// Leave records, newest first, optionally of one member or one status.
+    pub fn list_leaves(
+        &self,
+        member_id: Option<i64>,
+        status: Option<LeaveStatus>,
+    ) -> Result<Vec<Leave>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(&format!(
+                "SELECT {LEAVE_COLUMNS} FROM `bot_leave`
+                JOIN `bot_group_member` ON `bot_group_member`.`id` = `bot_leave`.`member_id`
+                WHERE (?1 IS NULL OR `bot_leave`.`member_id` = ?1) AND (?2 IS NULL OR `bot_leave`.`status` = ?2)
+                ORDER BY `bot_leave`.`start_date` DESC, `bot_leave`.`id` DESC"
+            ))
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let res = stmt
+            .query_map(
+                params![member_id, status.map(|s| s.as_str())],
+                leave_from_row,
+            )
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(res)
+    }
+
+    /// Take leave from `start` (today if `None`) to `end`. It is pending if
+    /// leave needs approval.
+    pub fn create_leave(
+        &self,
+        member_id: i64,
+        start: Option<NaiveDate>,
+        end: NaiveDate,
+        reason: &str,
+        channel: Channel,
+    ) -> Result<Leave, CreateLeaveError> {
+        let today = checkpoint_date_of(self.clock.now());
+        let start = start.unwrap_or(today);
+        if start < today {
+            return Err(CreateLeaveError::Invalid(
+                "leave cannot start in the past".to_string(),
+            ));
+        }
+        if end < start {
+            return Err(CreateLeaveError::Invalid(
+                "leave ends before it starts".to_string(),
+            ));
+        }
+        if (end - start).num_days() >= MAX_LEAVE_DAYS {
+            return Err(CreateLeaveError::Invalid(format!(
+                "leave is longer than {} days",
+                MAX_LEAVE_DAYS
+            )));
+        }
+        let status = if leave_requires_approval() {
+            LeaveStatus::Pending
+        } else {
+            LeaveStatus::Approved
+        };
+
+        let conn_guard = self.conn.lock().unwrap();
+        let overlapping: Option<i64> = conn_guard
+            .prepare_cached(
+                "SELECT `id` FROM `bot_leave`
+                WHERE `member_id` = ?1 AND `status` IN ('pending', 'approved')
+                    AND `start_date` <= ?3 AND `end_date` >= ?2
+                LIMIT 1",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(params![member_id, start, end], |r| r.get(0))
+                    .optional()
+            })
+            .map_err(|e| CreateLeaveError::Db(format!("query failed: {:?}", e)))?;
+        if let Some(id) = overlapping {
+            let existing = get_leave(&conn_guard, id)
+                .map_err(|e| CreateLeaveError::Db(format!("query failed: {:?}", e)))?
+                .expect("overlapping leave exists");
+            return Err(CreateLeaveError::Overlaps(Box::new(existing)));
+        }
+        let id: i64 = conn_guard
+            .prepare_cached(
+                "INSERT INTO `bot_leave` (`created_at`, `member_id`, `start_date`, `end_date`, `reason`, `status`)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6) RETURNING `id`",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(
+                    params![
+                        db_time(self.clock.now()),
+                        member_id,
+                        start,
+                        end,
+                        reason,
+                        status.as_str()
+                    ],
+                    |r| r.get(0),
+                )
+            })
+            .map_err(|e| CreateLeaveError::Db(format!("insert failed: {:?}", e)))?;
+        let leave = get_leave(&conn_guard, id)
+            .map_err(|e| CreateLeaveError::Db(format!("query failed: {:?}", e)))?
+            .expect("inserted leave exists");
+        AuditEntry {
+            actor_id: Some(member_id),
+            channel,
+            action: "leave.create",
+            target_id: Some(member_id),
+            before: None,
+            after: Some(serde_json::json!(leave)),
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(leave)
+    }
+
+    /// End a leave of `member_id` early: `id`, or the current or next one if
+    /// `None`. Leave that has not started yet is cancelled, leave in progress
+    /// ends yesterday. Returns `None` if there is no such leave.
+    pub fn end_leave(
+        &self,
+        member_id: i64,
+        id: Option<i64>,
+        channel: Channel,
+    ) -> Result<Option<Leave>, String> {
+        let today = checkpoint_date_of(self.clock.now());
+
+        let conn_guard = self.conn.lock().unwrap();
+        let id: Option<i64> = conn_guard
+            .prepare_cached(
+                "SELECT `id` FROM `bot_leave`
+                WHERE `member_id` = ?1 AND (?2 IS NULL OR `id` = ?2)
+                    AND `status` IN ('pending', 'approved') AND `end_date` >= ?3
+                ORDER BY `start_date` ASC LIMIT 1",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(params![member_id, id, today], |r| r.get(0))
+                    .optional()
+            })
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        let before = get_leave(&conn_guard, id)
+            .map_err(|e| format!("query failed: {:?}", e))?
+            .expect("selected leave exists");
+        let res = if before.start_date >= today || before.status != LeaveStatus::Approved {
+            conn_guard
+                .prepare_cached("UPDATE `bot_leave` SET `status` = 'cancelled' WHERE `id` = ?1")
+                .and_then(|mut stmt| stmt.execute([id]))
+        } else {
+            conn_guard
+                .prepare_cached("UPDATE `bot_leave` SET `end_date` = ?2 WHERE `id` = ?1")
+                .and_then(|mut stmt| stmt.execute(params![id, today.pred_opt()]))
+        };
+        res.map_err(|e| format!("update failed: {:?}", e))?;
+        let after = get_leave(&conn_guard, id)
+            .map_err(|e| format!("query failed: {:?}", e))?
+            .expect("updated leave exists");
+        AuditEntry {
+            actor_id: Some(member_id),
+            channel,
+            action: "leave.end",
+            target_id: Some(member_id),
+            before: Some(serde_json::json!(before)),
+            after: Some(serde_json::json!(after)),
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(Some(after))
+    }
+
+    /// Approve or reject a pending leave. Returns `None` if there is no such
+    /// leave, an error if it is not pending.
+    pub fn review_leave(
+        &self,
+        id: i64,
+        approve: bool,
+        admin_id: i64,
+        channel: Channel,
+    ) -> Result<Option<Leave>, String> {
+        let status = if approve {
+            LeaveStatus::Approved
+        } else {
+            LeaveStatus::Rejected
+        };
+
+        let conn_guard = self.conn.lock().unwrap();
+        let Some(before) =
+            get_leave(&conn_guard, id).map_err(|e| format!("query failed: {:?}", e))?
+        else {
+            return Ok(None);
+        };
+        if before.status != LeaveStatus::Pending {
+            return Err(format!("leave is {}, not pending", before.status.as_str()));
+        }
+        conn_guard
+            .prepare_cached(
+                "UPDATE `bot_leave` SET `status` = ?2, `reviewed_by` = ?3, `reviewed_at` = ?4 WHERE `id` = ?1",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    id,
+                    status.as_str(),
+                    admin_id,
+                    db_time(self.clock.now())
+                ])
+            })
+            .map_err(|e| format!("update failed: {:?}", e))?;
+        let after = get_leave(&conn_guard, id)
+            .map_err(|e| format!("query failed: {:?}", e))?
+            .expect("updated leave exists");
+        AuditEntry {
+            actor_id: Some(admin_id),
+            channel,
+            action: if approve {
+                "leave.approve"
+            } else {
+                "leave.reject"
+            },
+            target_id: Some(before.member_id),
+            before: Some(serde_json::json!(before)),
+            after: Some(serde_json::json!(after)),
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(Some(after))
+    }
+
+    /// Bot command: `/请假 <天数|到日期> [原因]`, starting today.
+    pub fn handle_请假(&self, user_id: i64, args: &str, channel: Channel) -> ServiceResponse {
+        let today = checkpoint_date_of(self.clock.now());
+        let (until, reason) = args.trim().split_once(' ').unwrap_or((args.trim(), ""));
+        let Some(end) = parse_leave_end(until, today) else {
+            return ServiceResponse::err(LEAVE_USAGE);
+        };
+        if end < today {
+            return ServiceResponse::err(LEAVE_USAGE);
+        }
+        if (end - today).num_days() >= MAX_LEAVE_DAYS {
+            return ServiceResponse::err(format!("请假最多{}天", MAX_LEAVE_DAYS));
+        }
+        match self.create_leave(user_id, Some(today), end, reason.trim(), channel) {
+            Ok(leave) if leave.status == LeaveStatus::Pending => {
+                ServiceResponse::ok(format!("已提交请假 {}，等待管理员审批", leave.range_text()))
+            }
+            Ok(leave) => ServiceResponse::ok(format!(
+                "🏖 请假 {}（{}天）",
+                leave.range_text(),
+                (leave.end_date - leave.start_date).num_days() + 1
+            )),
+            Err(CreateLeaveError::Overlaps(leave)) => {
+                ServiceResponse::err(format!("已经请过假了（{}）", leave.range_text()))
+            }
+            Err(e) => {
+                tracing::error!("Failed to create leave: {}", e);
+                ServiceResponse::err("请假失败：数据库错误")
+            }
+        }
+    }
+
+    /// Bot command: `/销假`, ends the current or next leave.
+    pub fn handle_销假(&self, user_id: i64, channel: Channel) -> ServiceResponse {
+        match self.end_leave(user_id, None, channel) {
+            Ok(Some(leave)) if leave.status == LeaveStatus::Cancelled => {
+                ServiceResponse::ok(format!("已取消请假 {}", leave.range_text()))
+            }
+            Ok(Some(_)) => ServiceResponse::ok("已销假，欢迎回来"),
+            Ok(None) => ServiceResponse::ok("没有要销的假"),
+            Err(e) => {
+                tracing::error!("Failed to end leave: {}", e);
+                ServiceResponse::err("销假失败：数据库错误")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn leave_end_from_days() {
+        let today = date(2024, 3, 1);
+        assert_eq!(parse_leave_end("1", today), Some(today));
+        assert_eq!(parse_leave_end("3天", today), Some(date(2024, 3, 3)));
+        assert_eq!(parse_leave_end("0", today), None);
+        assert_eq!(parse_leave_end("-2", today), None);
+    }
+
+    #[test]
+    fn leave_end_from_dates() {
+        let today = date(2024, 12, 30);
+        assert_eq!(parse_leave_end("到12/31", today), Some(date(2024, 12, 31)));
+        assert_eq!(parse_leave_end("12/30", today), Some(today));
+        assert_eq!(parse_leave_end("到1/2", today), Some(date(2025, 1, 2)));
+        assert_eq!(parse_leave_end("2025-01-05", today), Some(date(2025, 1, 5)));
+        assert_eq!(parse_leave_end("明天", today), None);
+    }
+}
@@ -41,3 +41,21 @@ impl ServiceResponse {
         }
     }
 }
+
+/// Where a mutation came from. Recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Bot,
+    Web,
+    Cli,
+}
+
+impl Channel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Channel::Bot => "bot",
+            Channel::Web => "web",
+            Channel::Cli => "cli",
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use chrono::prelude::*;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+
+use super::audit::AuditEntry;
+use super::clock::db_time;
+use super::daka::checkpoint_date_of;
+use super::models::{Channel, ServiceResponse};
+
+/// Longest range of rest days that can be declared at once, in days.
+const MAX_REST_RANGE_DAYS: i64 = 366;
+const REST_DAY_USAGE: &str = "用法：/休息日 [添加 <日期|日期~日期|周几> [原因] | 删除 <编号>]";
+const WEEKDAY_NAMES: [&str; 7] = ["一", "二", "三", "四", "五", "六", "日"];
+
+/// Which days a rest day entry covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestDays {
+    /// First and last bot day, both included
+    Dates(NaiveDate, NaiveDate),
+    Weekly(Weekday),
+}
+
+fn parse_date(s: &str, today: NaiveDate) -> Option<NaiveDate> {
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return Some(date);
+    }
+    let (month, day) = s.split_once('/')?;
+    NaiveDate::from_ymd_opt(today.year(), month.parse().ok()?, day.parse().ok()?)
+}
+
+impl RestDays {
+    /// Parse the bot command form: `3/1`, `2024-03-01`, `3/1~3/3` or `周六`.
+    /// Dates without a year are in the year of `today`, except for the end of
+    /// a range that would be before its start: `12/30~1/2` ends next year.
+    pub fn parse(s: &str, today: NaiveDate) -> Option<Self> {
+        if let Some(name) = s.strip_prefix("星期").or_else(|| s.strip_prefix('周')) {
+            let name = if name == "天" { "日" } else { name };
+            let index = WEEKDAY_NAMES.iter().position(|n| *n == name)?;
+            return Weekday::try_from(index as u8).ok().map(Self::Weekly);
+        }
+        match s.split_once(['~', '到']) {
+            Some((start, end_arg)) => {
+                let start = parse_date(start, today)?;
+                let end = match parse_date(end_arg, today)? {
+                    end if end < start && !end_arg.contains('-') => {
+                        end.with_year(end.year() + 1)?
+                    }
+                    end => end,
+                };
+                Some(Self::Dates(start, end))
+            }
+            None => parse_date(s, today).map(|date| Self::Dates(date, date)),
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if let Self::Dates(start, end) = self {
+            if end < start {
+                return Err("end is before start".to_string());
+            }
+            if (*end - *start).num_days() >= MAX_REST_RANGE_DAYS {
+                return Err(format!("range is longer than {} days", MAX_REST_RANGE_DAYS));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        match self {
+            Self::Dates(start, end) => *start <= date && date <= *end,
+            Self::Weekly(weekday) => date.weekday() == *weekday,
+        }
+    }
+
+    fn describe(&self) -> String {
+        let short = |d: &NaiveDate| format!("{}/{}", d.month(), d.day());
+        match self {
+            Self::Dates(start, end) if start == end => short(start),
+            Self::Dates(start, end) => format!("{}–{}", short(start), short(end)),
+            Self::Weekly(weekday) => format!(
+                "每周{}",
+                WEEKDAY_NAMES[weekday.num_days_from_monday() as usize]
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RestDay {
+    pub id: i64,
+    /// First and last bot day, `YYYY-MM-DD`, for a range of dates
+    pub start: Option<String>,
+    pub end: Option<String>,
+    /// 1 (Monday) to 7 (Sunday) for a weekly rest day
+    pub weekday: Option<u32>,
+    pub reason: String,
+    #[serde(skip)]
+    days: RestDays,
+}
+
+/// All rest days of the group, to look up by bot day.
+#[derive(Debug, Clone, Default)]
+pub struct RestCalendar(Vec<RestDays>);
+
+impl RestCalendar {
+    pub fn is_rest_day(&self, date: NaiveDate) -> bool {
+        self.0.iter().any(|days| days.contains(date))
+    }
+}
+
+fn rest_day_from_row(row: &rusqlite::Row) -> rusqlite::Result<RestDay> {
+    let start: Option<NaiveDate> = row.get(1)?;
+    let end: Option<NaiveDate> = row.get(2)?;
+    let weekday: Option<u32> = row.get(3)?;
+    let days = match (start, end, weekday) {
+        (Some(start), Some(end), _) => Some(RestDays::Dates(start, end)),
+        (_, _, Some(weekday)) => u8::try_from(weekday)
+            .ok()
+            .and_then(|w| w.checked_sub(1))
+            .and_then(|w| Weekday::try_from(w).ok())
+            .map(RestDays::Weekly),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        rusqlite::Error::InvalidColumnType(3, "weekday".to_string(), rusqlite::types::Type::Null)
+    })?;
+    Ok(RestDay {
+        id: row.get(0)?,
+        start: start.map(|d| d.to_string()),
+        end: end.map(|d| d.to_string()),
+        weekday,
+        reason: row.get(4)?,
+        days,
+    })
+}
+
+fn get_rest_day(conn: &Connection, id: i64) -> rusqlite::Result<Option<RestDay>> {
+    conn.prepare_cached(
+        "SELECT `id`, `start_date`, `end_date`, `weekday`, `reason` FROM `bot_rest_day` WHERE `id` = ?1",
+    )?
+    .query_row([id], rest_day_from_row)
+    .optional()
+}
+
+impl super::Service {
+    /// Rest days, ranges of dates by start and then weekly ones by weekday.
+    pub fn list_rest_days(&self) -> Result<Vec<RestDay>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `start_date`, `end_date`, `weekday`, `reason` FROM `bot_rest_day`
+                ORDER BY `weekday` IS NOT NULL, `start_date` ASC, `weekday` ASC",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let res = stmt
+            .query_map([], rest_day_from_row)
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(res)
+    }
+
+    pub fn rest_calendar(&self) -> Result<RestCalendar, String> {
+        Ok(RestCalendar(
+            self.list_rest_days()?.into_iter().map(|r| r.days).collect(),
+        ))
+    }
+
+    pub fn add_rest_day(
+        &self,
+        days: RestDays,
+        reason: &str,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<RestDay, String> {
+        days.check()?;
+        let (start, end, weekday) = match days {
+            RestDays::Dates(start, end) => (Some(start), Some(end), None),
+            RestDays::Weekly(weekday) => (None, None, Some(weekday.number_from_monday())),
+        };
+
+        let conn_guard = self.conn.lock().unwrap();
+        let id: i64 = conn_guard
+            .prepare_cached(
+                "INSERT INTO `bot_rest_day` (`created_at`, `start_date`, `end_date`, `weekday`, `reason`)
+                VALUES (?1, ?2, ?3, ?4, ?5) RETURNING `id`",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_row(
+                    params![db_time(self.clock.now()), start, end, weekday, reason],
+                    |r| r.get(0),
+                )
+            })
+            .map_err(|e| format!("insert failed: {:?}", e))?;
+        let rest_day = get_rest_day(&conn_guard, id)
+            .map_err(|e| format!("query failed: {:?}", e))?
+            .expect("inserted rest day exists");
+        AuditEntry {
+            actor_id,
+            channel,
+            action: "rest_day.create",
+            target_id: None,
+            before: None,
+            after: Some(serde_json::json!(rest_day)),
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(rest_day)
+    }
+
+    /// Returns `None` if there is no such rest day.
+    pub fn delete_rest_day(
+        &self,
+        id: i64,
+        actor_id: Option<i64>,
+        channel: Channel,
+    ) -> Result<Option<RestDay>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let Some(rest_day) =
+            get_rest_day(&conn_guard, id).map_err(|e| format!("query failed: {:?}", e))?
+        else {
+            return Ok(None);
+        };
+        conn_guard
+            .prepare_cached("DELETE FROM `bot_rest_day` WHERE `id` = ?1")
+            .and_then(|mut stmt| stmt.execute([id]))
+            .map_err(|e| format!("delete failed: {:?}", e))?;
+        AuditEntry {
+            actor_id,
+            channel,
+            action: "rest_day.delete",
+            target_id: None,
+            before: Some(serde_json::json!(rest_day)),
+            after: None,
+        }
+        .write(&conn_guard);
+        drop(conn_guard);
+        Ok(Some(rest_day))
+    }
+
+    /// Bot command: `/休息日` lists the rest days, admins can `添加` and `删除`.
+    pub fn handle_休息日(
+        &self,
+        args: &str,
+        actor_id: Option<i64>,
+        is_admin: bool,
+    ) -> ServiceResponse {
+        let args = args.trim();
+        let (action, rest) = args.split_once(' ').unwrap_or((args, ""));
+        match action {
+            "" => {}
+            "添加" | "删除" if !is_admin => return ServiceResponse::err("仅管理员可用"),
+            "添加" => {
+                let today = checkpoint_date_of(self.clock.now());
+                let (spec, reason) = rest.trim().split_once(' ').unwrap_or((rest.trim(), ""));
+                let Some(days) = RestDays::parse(spec, today).filter(|d| d.check().is_ok()) else {
+                    return ServiceResponse::err(REST_DAY_USAGE);
+                };
+                return match self.add_rest_day(days, reason.trim(), actor_id, Channel::Bot) {
+                    Ok(rest_day) => ServiceResponse::ok(format!(
+                        "已添加休息日 #{} {}",
+                        rest_day.id,
+                        rest_day.days.describe()
+                    )),
+                    Err(e) => {
+                        tracing::error!("Failed to add rest day: {}", e);
+                        ServiceResponse::err("添加休息日失败：数据库错误")
+                    }
+                };
+            }
+            "删除" => {
+                let Ok(id) = rest.trim().trim_start_matches('#').parse() else {
+                    return ServiceResponse::err(REST_DAY_USAGE);
+                };
+                return match self.delete_rest_day(id, actor_id, Channel::Bot) {
+                    Ok(Some(rest_day)) => ServiceResponse::ok(format!(
+                        "已删除休息日 #{} {}",
+                        rest_day.id,
+                        rest_day.days.describe()
+                    )),
+                    Ok(None) => ServiceResponse::err("没有这个休息日"),
+                    Err(e) => {
+                        tracing::error!("Failed to delete rest day: {}", e);
+                        ServiceResponse::err("删除休息日失败：数据库错误")
+                    }
+                };
+            }
+            _ => return ServiceResponse::err(REST_DAY_USAGE),
+        }
+
+        match self.list_rest_days() {
+            Ok(rest_days) if rest_days.is_empty() => ServiceResponse::ok("没有休息日"),
+            Ok(rest_days) => ServiceResponse::ok(
+                rest_days
+                    .iter()
+                    .map(|r| {
+                        let mut line = format!("#{} {}", r.id, r.days.describe());
+                        if !r.reason.is_empty() {
+                            line += &format!(" {}", r.reason);
+                        }
+                        line
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            ),
+            Err(e) => {
+                tracing::error!("Failed to list rest days: {}", e);
+                ServiceResponse::err("休息日查询失败：数据库错误")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).unwrap()
+    }
+
+    #[test]
+    fn parse_dates_and_weekdays() {
+        let today = date(2024, 3, 1);
+        assert_eq!(
+            RestDays::parse("3/2", today),
+            Some(RestDays::Dates(date(2024, 3, 2), date(2024, 3, 2)))
+        );
+        assert_eq!(
+            RestDays::parse("2025-01-01到2025-01-03", today),
+            Some(RestDays::Dates(date(2025, 1, 1), date(2025, 1, 3)))
+        );
+        assert_eq!(
+            RestDays::parse("周六", today),
+            Some(RestDays::Weekly(Weekday::Sat))
+        );
+        assert_eq!(
+            RestDays::parse("星期天", today),
+            Some(RestDays::Weekly(Weekday::Sun))
+        );
+        assert_eq!(RestDays::parse("周八", today), None);
+        assert_eq!(RestDays::parse("2/30", today), None);
+    }
+
+    #[test]
+    fn parse_range_rolls_over_the_year() {
+        let today = date(2024, 12, 1);
+        assert_eq!(
+            RestDays::parse("12/30~1/2", today),
+            Some(RestDays::Dates(date(2024, 12, 30), date(2025, 1, 2)))
+        );
+        // a full date is taken as given and left for `check` to reject
+        let days = RestDays::parse("12/30~2024-01-02", today).unwrap();
+        assert_eq!(days, RestDays::Dates(date(2024, 12, 30), date(2024, 1, 2)));
+        assert!(days.check().is_err());
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::BTreeMap;
+
+use chrono::prelude::*;
+use rusqlite::params;
+use serde::Serialize;
+
+use super::daka::{BOT_CHECKPOINT, checkpoint_date_of, checkpoint_of};
+use super::models::ServiceResponse;
+use super::rest_day::RestCalendar;
+
+/// Longest range `/daka/stats` accepts, in days.
+const MAX_STATS_DAYS: i64 = 366;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DayStats {
+    /// Bot day, `YYYY-MM-DD`
+    pub date: String,
+    pub checked_in: usize,
+    /// Members who had joined by that day and were not on leave, 0 on rest days
+    pub expected: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberStats {
+    pub member_id: i64,
+    pub name: String,
+    pub days: usize,
+    /// Days in the range since the member joined, leave and rest days not included
+    pub expected_days: usize,
+    /// `days / expected_days`, 0 to 1
+    pub completion: f64,
+    /// Average and median check-in time of day, UTC+8 `HH:MM`
+    pub average_time: Option<String>,
+    pub median_time: Option<String>,
+    /// Consecutive days with a check-in up to the end of the range. Days on
+    /// leave, rest days and a missing check-in today do not break it.
+    pub current_streak: usize,
+    pub longest_streak: usize,
+}
+
+/// Completion of one Monday-based week within the range, compared with the
+/// same weekdays of the week before.
+#[derive(Debug, Clone, Serialize)]
+pub struct WeekStats {
+    pub start: String,
+    pub end: String,
+    pub completion_rate: Option<f64>,
+    pub previous_rate: Option<f64>,
+    /// Change from `previous_rate`, in percentage points
+    pub change: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupStats {
+    pub from: String,
+    pub to: String,
+    /// Check-ins over expected check-ins across the range, 0 to 1
+    pub completion_rate: Option<f64>,
+    pub average_time: Option<String>,
+    pub median_time: Option<String>,
+    pub days: Vec<DayStats>,
+    pub members: Vec<MemberStats>,
+    pub weeks: Vec<WeekStats>,
+}
+
+struct Member {
+    id: i64,
+    name: String,
+    /// First bot day the member is expected to check in
+    first_day: NaiveDate,
+    /// Minutes after the checkpoint of the earliest check-in of each day
+    checkins: BTreeMap<NaiveDate, i64>,
+    /// Approved leave, first and last day
+    leave: Vec<(NaiveDate, NaiveDate)>,
+    rest: RestCalendar,
+}
+
+impl Member {
+    /// On leave or a rest day of the group.
+    fn day_off(&self, date: NaiveDate) -> bool {
+        self.rest.is_rest_day(date) || self.leave.iter().any(|(s, e)| *s <= date && date <= *e)
+    }
+
+    fn expected_on(&self, date: NaiveDate) -> bool {
+        date >= self.first_day && !self.day_off(date)
+    }
+}
+
+fn format_minutes(minutes: i64) -> String {
+    (BOT_CHECKPOINT + chrono::Duration::minutes(minutes))
+        .format("%H:%M")
+        .to_string()
+}
+
+fn average(minutes: &[i64]) -> Option<String> {
+    (!minutes.is_empty())
+        .then(|| format_minutes(minutes.iter().sum::<i64>() / minutes.len() as i64))
+}
+
+fn median(minutes: &mut [i64]) -> Option<String> {
+    if minutes.is_empty() {
+        return None;
+    }
+    minutes.sort_unstable();
+    let mid = minutes.len() / 2;
+    let median = if minutes.len().is_multiple_of(2) {
+        (minutes[mid - 1] + minutes[mid]) / 2
+    } else {
+        minutes[mid]
+    };
+    Some(format_minutes(median))
+}
+
+fn days_between(from: NaiveDate, to: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+    from.iter_days().take_while(move |d| *d <= to)
+}
+
+/// Check-ins over expected check-ins from `from` to `to`.
+fn completion_rate(members: &[Member], from: NaiveDate, to: NaiveDate) -> Option<f64> {
+    let (mut done, mut expected) = (0, 0);
+    for date in days_between(from, to) {
+        for member in members.iter().filter(|m| m.expected_on(date)) {
+            expected += 1;
+            done += usize::from(member.checkins.contains_key(&date));
+        }
+    }
+    (expected > 0).then(|| done as f64 / expected as f64)
+}
+
+/// Current and longest run of consecutive check-in days up to `to`. Days off
+/// without a check-in are skipped, and so is today since it is not over.
+fn streaks(member: &Member, to: NaiveDate, today: NaiveDate) -> (usize, usize) {
+    let Some(first) = member.checkins.keys().next() else {
+        return (0, 0);
+    };
+    let mut longest = 0;
+    let mut run = 0;
+    for date in days_between(*first, to) {
+        if member.checkins.contains_key(&date) {
+            run += 1;
+            longest = longest.max(run);
+        } else if date != today && !member.day_off(date) {
+            run = 0;
+        }
+    }
+    (run, longest)
+}
+
+fn percent(rate: Option<f64>) -> String {
+    rate.map(|r| format!("{:.0}%", r * 100.0))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+impl super::Service {
+    /// Members taking part in a habit with their check-ins of it up to the end
+    /// of bot day `to`: all members for the default habit, those who checked
+    /// in for it from their first check-in on for the others.
+    fn load_members(&self, to: NaiveDate, habit_id: i64) -> Result<Vec<Member>, String> {
+        let until = checkpoint_of(to + chrono::Duration::days(1));
+        let leave = self.leave_calendar()?;
+        let rest = self.rest_calendar()?;
+
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `bot_group_member`.`id`, `group_nickname`, `bot_group_member`.`created_at`,
+                    `bot_habit`.`is_default`
+                FROM `bot_group_member` JOIN `bot_habit` ON `bot_habit`.`id` = ?2
+                WHERE (`bot_habit`.`is_default` AND `bot_group_member`.`created_at` < ?1)
+                    OR `bot_group_member`.`id` IN (
+                        SELECT `user_id` FROM `bot_daka` WHERE `created_at` < ?1 AND `habit_id` = ?2
+                        AND (`bot_habit`.`is_default` OR `deleted_at` IS NULL)
+                    )
+                ORDER BY `sort_key` ASC, `bot_group_member`.`id` ASC",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let members = stmt
+            .query_map(params![until.naive_utc(), habit_id], |row| {
+                let id: i64 = row.get(0)?;
+                let created_at: DateTime<Utc> = row.get(2)?;
+                let is_default: bool = row.get(3)?;
+                Ok(Member {
+                    id,
+                    name: row.get(1)?,
+                    // moved back to the first check-in below
+                    first_day: if is_default {
+                        checkpoint_date_of(created_at)
+                    } else {
+                        NaiveDate::MAX
+                    },
+                    checkins: BTreeMap::new(),
+                    leave: leave.ranges(id).to_vec(),
+                    rest: rest.clone(),
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `user_id`, `created_at` FROM `bot_daka`
+                WHERE `created_at` < ?1 AND `deleted_at` IS NULL AND `habit_id` = ?2",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let records = stmt
+            .query_map(params![until.naive_utc(), habit_id], |row| {
+                let user_id: i64 = row.get(0)?;
+                let created_at: DateTime<Utc> = row.get(1)?;
+                Ok((user_id, created_at))
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+
+        let mut members = members;
+        for (user_id, created_at) in records {
+            let Some(member) = members.iter_mut().find(|m| m.id == user_id) else {
+                continue;
+            };
+            let date = checkpoint_date_of(created_at);
+            let minutes = (created_at - checkpoint_of(date)).num_minutes();
+            member
+                .checkins
+                .entry(date)
+                .and_modify(|m| *m = (*m).min(minutes))
+                .or_insert(minutes);
+            // imported history can predate the member record
+            member.first_day = member.first_day.min(date);
+        }
+        Ok(members)
+    }
+
+    /// Resolve and check a stats range: `to` defaults to today and `from` to
+    /// six days before `to`.
+    pub fn stats_range(
+        &self,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<(NaiveDate, NaiveDate), String> {
+        let to = to.unwrap_or_else(|| checkpoint_date_of(self.clock.now()));
+        let from = from.unwrap_or(to - chrono::Duration::days(6));
+        if from > to {
+            return Err("from is after to".to_string());
+        }
+        if (to - from).num_days() >= MAX_STATS_DAYS {
+            return Err(format!("range is longer than {} days", MAX_STATS_DAYS));
+        }
+        Ok((from, to))
+    }
+
+    /// Statistics of a habit for the bot days from `from` to `to`, both included.
+    pub fn group_stats(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        habit_id: i64,
+    ) -> Result<GroupStats, String> {
+        let today = checkpoint_date_of(self.clock.now());
+        let members = self.load_members(to, habit_id)?;
+
+        let days = days_between(from, to)
+            .map(|date| {
+                let expected = members.iter().filter(|m| m.expected_on(date));
+                DayStats {
+                    date: date.to_string(),
+                    checked_in: expected
+                        .clone()
+                        .filter(|m| m.checkins.contains_key(&date))
+                        .count(),
+                    expected: expected.count(),
+                }
+            })
+            .collect();
+
+        let mut all_minutes = Vec::new();
+        let member_stats = members
+            .iter()
+            .map(|m| {
+                let mut minutes = m
+                    .checkins
+                    .range(from..=to)
+                    .map(|(_, minutes)| *minutes)
+                    .collect::<Vec<_>>();
+                all_minutes.extend_from_slice(&minutes);
+                let expected_days = days_between(from, to).filter(|d| m.expected_on(*d)).count();
+                let (current_streak, longest_streak) = streaks(m, to, today);
+                MemberStats {
+                    member_id: m.id,
+                    name: m.name.clone(),
+                    days: minutes.len(),
+                    expected_days,
+                    completion: if expected_days > 0 {
+                        minutes.len() as f64 / expected_days as f64
+                    } else {
+                        0.0
+                    },
+                    average_time: average(&minutes),
+                    median_time: median(&mut minutes),
+                    current_streak,
+                    longest_streak,
+                }
+            })
+            .collect();
+
+        let mut weeks = Vec::new();
+        let mut week_start = from;
+        while week_start <= to {
+            let days_to_sunday = 6 - i64::from(week_start.weekday().num_days_from_monday());
+            let week_end = (week_start + chrono::Duration::days(days_to_sunday)).min(to);
+            let rate = completion_rate(&members, week_start, week_end);
+            let previous_rate = completion_rate(
+                &members,
+                week_start - chrono::Duration::days(7),
+                week_end - chrono::Duration::days(7),
+            );
+            weeks.push(WeekStats {
+                start: week_start.to_string(),
+                end: week_end.to_string(),
+                completion_rate: rate,
+                previous_rate,
+                change: rate.zip(previous_rate).map(|(r, p)| (r - p) * 100.0),
+            });
+            week_start = week_end + chrono::Duration::days(1);
+        }
+
+        Ok(GroupStats {
+            from: from.to_string(),
+            to: to.to_string(),
+            completion_rate: completion_rate(&members, from, to),
+            average_time: average(&all_minutes),
+            median_time: median(&mut all_minutes),
+            days,
+            members: member_stats,
+            weeks,
+        })
+    }
+
+    /// Bot command: `/统计 [习惯]`, summary of the current week so far.
+    pub fn handle_统计(&self, args: &str) -> ServiceResponse {
+        let habit = match self.habit_from_args(args) {
+            Ok((habit, "")) => habit,
+            Ok((_, name)) => return self.unknown_habit(name),
+            Err(e) => {
+                tracing::error!("Failed to query habit: {}", e);
+                return ServiceResponse::err("统计查询失败：数据库错误");
+            }
+        };
+        let today = checkpoint_date_of(self.clock.now());
+        let monday = today - chrono::Duration::days(today.weekday().num_days_from_monday().into());
+        let stats = match self.group_stats(monday, today, habit.id) {
+            Ok(stats) => stats,
+            Err(e) => {
+                tracing::error!("Failed to compute stats: {}", e);
+                return ServiceResponse::err("统计查询失败：数据库错误");
+            }
+        };
+
+        let mut lines = vec![format!(
+            "{}本周统计（{}/{}–{}/{}）",
+            if habit.is_default {
+                String::new()
+            } else {
+                format!("{} ", habit.name)
+            },
+            monday.month(),
+            monday.day(),
+            today.month(),
+            today.day()
+        )];
+        let mut rate_line = format!("完成率 {}", percent(stats.completion_rate));
+        if let Some(week) = stats.weeks.first()
+            && let Some(change) = week.change
+        {
+            rate_line += &format!(
+                "（上周同期 {}，{}{:.0}%）",
+                percent(week.previous_rate),
+                if change >= 0.0 { "↑" } else { "↓" },
+                change.abs()
+            );
+        }
+        lines.push(rate_line);
+        if let (Some(average), Some(median)) = (&stats.average_time, &stats.median_time) {
+            lines.push(format!("平均打卡 {}，中位 {}", average, median));
+        }
+        for m in stats.members.iter().filter(|m| m.expected_days > 0) {
+            let mut line = format!("{} {}/{}", m.name, m.days, m.expected_days);
+            if m.current_streak > 1 {
+                line += &format!(" 🔥{}", m.current_streak);
+            }
+            lines.push(line);
+        }
+        ServiceResponse::ok(lines.join("\n"))
+    }
+}
@@ -0,0 +1,254 @@
+use chrono::prelude::*;
+use serde::Serialize;
+
+use super::backup::schema_version;
+use super::clock::local_time;
+use super::events::ServiceEvent;
+
+const ADMIN_NOTIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// URL that admin alerts are POSTed to as `{"title", "text"}` JSON, e.g. a
+/// push service that forwards them to an admin's phone.
+fn admin_notify_url() -> Option<String> {
+    std::env::var("ADMIN_NOTIFY_URL")
+        .ok()
+        .filter(|v| !v.is_empty())
+}
+
+/// Lifecycle of the bot task, as last reported by it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BotState {
+    /// The bot is not run by this process (`RUN_MODE=web`).
+    Disabled,
+    Starting,
+    /// Waiting for the QR code to be scanned.
+    LoggingIn,
+    Online,
+    /// Lost the connection or failed to login; retrying.
+    Offline,
+    /// The bot task gave up; the process needs a restart to get it back.
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QrState {
+    /// Shown and waiting to be scanned and confirmed on the phone.
+    Waiting,
+    Confirmed,
+    /// Not confirmed in time; a new code is fetched right away.
+    Expired,
+    Failed,
+}
+
+/// The QR code of a pending bot login.
+#[derive(Debug, Clone)]
+pub struct QrLogin {
+    pub state: QrState,
+    pub url: String,
+    pub png: Vec<u8>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QrLoginReport {
+    pub bot_state: BotState,
+    pub qr_state: Option<QrState>,
+    pub url: Option<String>,
+    /// Changes with every new code, for cache busting the PNG
+    pub fetched_at: Option<i64>,
+}
+
+/// Bot status shared between the bot task, which updates it, and the health endpoints.
+#[derive(Debug, Clone)]
+pub struct BotStatus {
+    pub state: BotState,
+    pub error: Option<String>,
+    pub online_since: Option<DateTime<Utc>>,
+    pub last_event_at: Option<DateTime<Utc>>,
+    pub keystore_expired: Option<bool>,
+    /// When the session in the key store runs out, if it could be read
+    pub keystore_expires_at: Option<DateTime<Utc>>,
+    pub keystore_saved_at: Option<DateTime<Utc>>,
+    /// Latest QR code, kept until the next login attempt
+    pub qr_login: Option<QrLogin>,
+}
+
+impl Default for BotStatus {
+    fn default() -> Self {
+        Self {
+            state: BotState::Disabled,
+            error: None,
+            online_since: None,
+            last_event_at: None,
+            keystore_expired: None,
+            keystore_expires_at: None,
+            keystore_saved_at: None,
+            qr_login: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub db_ok: bool,
+    pub db_error: Option<String>,
+    /// Latest applied migration
+    pub schema_version: Option<i64>,
+    pub bot_state: BotState,
+    pub bot_error: Option<String>,
+    /// UTC+8 times, `YYYY-MM-DD HH:MM:SS`
+    pub bot_online_since: Option<String>,
+    pub last_event_at: Option<String>,
+    pub keystore_expired: Option<bool>,
+    pub keystore_expires_at: Option<String>,
+    /// Seconds until the key store expires, negative once it has
+    pub keystore_expires_in: Option<i64>,
+    pub keystore_saved_at: Option<String>,
+}
+
+impl HealthReport {
+    /// Alive: the database answers and the bot, if this process runs one, has not given up.
+    pub fn healthy(&self) -> bool {
+        self.db_ok && self.bot_state != BotState::Failed
+    }
+
+    /// Ready: healthy, and the bot, if this process runs one, is online.
+    pub fn ready(&self) -> bool {
+        self.db_ok && matches!(self.bot_state, BotState::Disabled | BotState::Online)
+    }
+}
+
+impl super::Service {
+    pub fn bot_status(&self) -> BotStatus {
+        self.bot_status.read().unwrap().clone()
+    }
+
+    pub fn set_bot_state(&self, state: BotState, error: Option<String>) {
+        let mut status = self.bot_status.write().unwrap();
+        if state == BotState::Online && status.state != BotState::Online {
+            status.online_since = Some(Utc::now());
+        } else if state != BotState::Online {
+            status.online_since = None;
+        }
+        status.state = state;
+        status.error = error;
+        crate::metrics::BOT_ONLINE.set(i64::from(state == BotState::Online));
+    }
+
+    /// Called by the bot for every event it receives.
+    pub fn mark_bot_event(&self) {
+        self.bot_status.write().unwrap().last_event_at = Some(Utc::now());
+    }
+
+    pub fn set_keystore_status(
+        &self,
+        expired: bool,
+        expires_at: Option<DateTime<Utc>>,
+        saved: bool,
+    ) {
+        let mut status = self.bot_status.write().unwrap();
+        status.keystore_expired = Some(expired);
+        status.keystore_expires_at = expires_at;
+        if saved {
+            status.keystore_saved_at = Some(Utc::now());
+        }
+    }
+
+    pub fn set_qr_code(&self, url: String, png: Vec<u8>) {
+        self.bot_status.write().unwrap().qr_login = Some(QrLogin {
+            state: QrState::Waiting,
+            url,
+            png,
+            fetched_at: Utc::now(),
+        });
+    }
+
+    pub fn set_qr_state(&self, state: QrState) {
+        if let Some(qr) = self.bot_status.write().unwrap().qr_login.as_mut() {
+            qr.state = state;
+        }
+    }
+
+    /// PNG of the QR code while it can still be scanned.
+    pub fn pending_qr_png(&self) -> Option<Vec<u8>> {
+        let status = self.bot_status.read().unwrap();
+        status
+            .qr_login
+            .as_ref()
+            .filter(|qr| qr.state == QrState::Waiting)
+            .map(|qr| qr.png.clone())
+    }
+
+    pub fn qr_login_report(&self) -> QrLoginReport {
+        let status = self.bot_status.read().unwrap();
+        let qr = status.qr_login.as_ref();
+        QrLoginReport {
+            bot_state: status.state,
+            qr_state: qr.map(|qr| qr.state),
+            url: qr
+                .filter(|qr| qr.state == QrState::Waiting)
+                .map(|qr| qr.url.clone()),
+            fetched_at: qr.map(|qr| qr.fetched_at.timestamp_millis()),
+        }
+    }
+
+    /// Tell admins that someone has to scan a QR code before the bot can come
+    /// back: through the event bus and its webhooks, and pushed straight to
+    /// `ADMIN_NOTIFY_URL` if set, since the bot itself cannot send messages
+    /// then. Must be called from within the runtime.
+    pub fn notify_login_required(&self, reason: &str) {
+        tracing::warn!("Manual login required: {}", reason);
+        let conn_guard = self.conn.lock().unwrap();
+        self.publish(
+            &conn_guard,
+            ServiceEvent::LoginRequired {
+                reason: reason.to_string(),
+            },
+        );
+        drop(conn_guard);
+
+        let Some(url) = admin_notify_url() else {
+            return;
+        };
+        let text = format!("机器人需要重新登录：{}。请在 /admin/bot 扫码。", reason);
+        tokio::spawn(async move {
+            let res = reqwest::Client::new()
+                .post(&url)
+                .timeout(ADMIN_NOTIFY_TIMEOUT)
+                .json(&serde_json::json!({"title": "机器人需要登录", "text": text}))
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status());
+            match res {
+                Ok(_) => tracing::info!("Admin notified of the required login"),
+                Err(e) => tracing::error!("Failed to notify admin: {:?}", e),
+            }
+        });
+    }
+
+    pub fn health(&self) -> HealthReport {
+        let conn_guard = self.conn.lock().unwrap();
+        let db = conn_guard.query_row("SELECT 1", [], |r| r.get::<_, i64>(0));
+        let version = schema_version(&conn_guard);
+        drop(conn_guard);
+
+        let bot = self.bot_status();
+        HealthReport {
+            db_ok: db.is_ok(),
+            db_error: db.err().map(|e| e.to_string()),
+            schema_version: version,
+            bot_state: bot.state,
+            bot_error: bot.error,
+            bot_online_since: local_time(bot.online_since),
+            last_event_at: local_time(bot.last_event_at),
+            keystore_expired: bot.keystore_expired,
+            keystore_expires_at: local_time(bot.keystore_expires_at),
+            keystore_expires_in: bot
+                .keystore_expires_at
+                .map(|at| (at - Utc::now()).num_seconds()),
+            keystore_saved_at: local_time(bot.keystore_saved_at),
+        }
+    }
+}
@@ -1,6 +1,43 @@
-use crate::service::models::ServiceResponse;
+use std::collections::HashSet;
+
+use crate::service::audit::AuditEntry;
+use crate::service::models::{Channel, ServiceResponse};
+
+/// QQ uins allowed to use admin commands and endpoints, read from the
+/// comma-separated `ADMIN_UINS` environment variable.
+pub fn admin_uins() -> HashSet<u32> {
+    std::env::var("ADMIN_UINS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|s| s.trim().parse().ok())
+        .collect()
+}
 
 impl super::Service {
+    pub fn is_admin_uin(&self, qq_uin: u32) -> bool {
+        self.admin_uins.read().unwrap().contains(&qq_uin)
+    }
+
+    /// Allow `qq_uin` to use admin commands, on top of the ones the service
+    /// was started with.
+    pub fn add_admin_uin(&self, qq_uin: u32) {
+        self.admin_uins.write().unwrap().insert(qq_uin);
+    }
+
+    /// Whether the member with the given id is an admin.
+    pub fn is_admin_member(&self, member_id: i64) -> bool {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt =
+            match conn_guard.prepare_cached("SELECT qq_uin FROM bot_group_member WHERE id = ?1") {
+                Ok(s) => s,
+                Err(_) => return false,
+            };
+        let res: Result<u32, _> = stmt.query_row([member_id], |r| r.get(0));
+        drop(stmt);
+        drop(conn_guard);
+        res.is_ok_and(|uin| self.is_admin_uin(uin))
+    }
+
     /// Find member id and password by qq_uin. Returns (id, password) on success.
     pub fn find_member_by_uin(&self, qq_uin: u32) -> Option<(i64, String)> {
         let conn_guard = self.conn.lock().unwrap();
@@ -20,14 +57,42 @@ impl super::Service {
         res.ok()
     }
 
+    /// The `qq_uid` a member is stored under, for platforms that only know the uin.
+    pub fn find_member_uid_by_uin(&self, qq_uin: u32) -> Option<String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let res = conn_guard
+            .prepare_cached("SELECT qq_uid FROM bot_group_member WHERE qq_uin = ?1")
+            .and_then(|mut stmt| stmt.query_row([qq_uin], |r| r.get(0)));
+        drop(conn_guard);
+        res.ok()
+    }
+
     pub fn set_password_for_member_id(
         &self,
         member_id: i64,
         hashed_password: &str,
+        channel: Channel,
     ) -> Result<(), ServiceResponse> {
+        let was_set = self
+            .get_password_by_id(member_id)
+            .is_some_and(|pw| !pw.trim().is_empty());
         // delegate to service's update_password_by_id
         match self.update_password_by_id(member_id, hashed_password) {
-            Ok(_) => Ok(()),
+            Ok(_) => {
+                // never log the hash itself
+                let conn_guard = self.conn.lock().unwrap();
+                AuditEntry {
+                    actor_id: Some(member_id),
+                    channel,
+                    action: "member.password",
+                    target_id: Some(member_id),
+                    before: Some(serde_json::json!({"password_set": was_set})),
+                    after: Some(serde_json::json!({"password_set": true})),
+                }
+                .write(&conn_guard);
+                drop(conn_guard);
+                Ok(())
+            }
             Err(e) => Err(ServiceResponse::err(e)),
         }
     }
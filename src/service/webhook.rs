@@ -0,0 +1,390 @@
+use std::time::Duration;
+
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use rand::distributions::Alphanumeric;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use sha2::Sha256;
+
+use super::clock::local_time;
+use super::events::ServiceEvent;
+use crate::shutdown::ShutdownSignal;
+
+/// Deliveries are given up after this many failed attempts.
+const MAX_ATTEMPTS: u32 = 10;
+const RETRY_BASE: chrono::Duration = chrono::Duration::seconds(30);
+const RETRY_MAX: chrono::Duration = chrono::Duration::hours(1);
+/// How often the worker looks for deliveries that are due for a retry.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: String,
+    pub enabled: bool,
+}
+
+impl Webhook {
+    fn wants(&self, kind: &str) -> bool {
+        self.enabled
+            && self
+                .events
+                .split(',')
+                .any(|e| e.trim() == "*" || e.trim() == kind)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub event: String,
+    pub status: String,
+    pub attempts: u32,
+    pub last_status: Option<u16>,
+    pub last_error: Option<String>,
+    /// UTC+8 times, `YYYY-MM-DD HH:MM:SS`
+    pub created_at: String,
+    pub next_attempt_at: Option<String>,
+    pub delivered_at: Option<String>,
+}
+
+struct PendingDelivery {
+    id: i64,
+    url: String,
+    secret: String,
+    event: String,
+    payload: String,
+    attempts: u32,
+}
+
+fn generate_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex-encoded HMAC-SHA256 of the payload, sent as `X-Signature-256: sha256=<hex>`.
+pub fn sign_payload(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(payload.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn retry_delay(attempts: u32) -> chrono::Duration {
+    let factor = 1i32 << attempts.saturating_sub(1).min(10);
+    (RETRY_BASE * factor).min(RETRY_MAX)
+}
+
+/// Queue a delivery of `event` for every enabled webhook subscribed to it.
+/// Called with the connection the event's change was made on, so a recorded
+/// change always has its deliveries queued.
+pub(super) fn enqueue_deliveries(conn: &Connection, event: &ServiceEvent) {
+    let res = (|| -> rusqlite::Result<()> {
+        let mut stmt = conn.prepare_cached(
+            "SELECT `id`, `url`, `secret`, `events`, `enabled` FROM `webhook` WHERE `enabled` = 1",
+        )?;
+        let hooks = stmt
+            .query_map([], |row| {
+                Ok(Webhook {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    secret: row.get(2)?,
+                    events: row.get(3)?,
+                    enabled: row.get(4)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(stmt);
+
+        let kind = event.kind();
+        let payload = serde_json::json!({
+            "event": kind,
+            "created_at": Utc::now().to_rfc3339(),
+            "data": event,
+        })
+        .to_string();
+        let mut insert_stmt = conn.prepare_cached(
+            "INSERT INTO `webhook_delivery` (`webhook_id`, `event`, `payload`) VALUES (?1, ?2, ?3)",
+        )?;
+        for hook in hooks.iter().filter(|h| h.wants(kind)) {
+            insert_stmt.execute(params![hook.id, kind, payload])?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = res {
+        tracing::error!("Failed to queue webhook deliveries: {:?}", e);
+    }
+}
+
+impl super::Service {
+    pub fn list_webhooks(&self) -> Result<Vec<Webhook>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `url`, `secret`, `events`, `enabled` FROM `webhook` ORDER BY `id`",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok(Webhook {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    secret: row.get(2)?,
+                    events: row.get(3)?,
+                    enabled: row.get(4)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(rows)
+    }
+
+    /// Register a webhook. A secret is generated when none is given.
+    pub fn create_webhook(
+        &self,
+        url: &str,
+        secret: Option<&str>,
+        events: Option<&str>,
+    ) -> Result<Webhook, String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err("url must be http(s)".to_string());
+        }
+        let secret = secret
+            .filter(|s| !s.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(generate_secret);
+        let events = events.filter(|s| !s.trim().is_empty()).unwrap_or("*");
+
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "INSERT INTO `webhook` (`url`, `secret`, `events`) VALUES (?1, ?2, ?3) RETURNING `id`",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let id: i64 = stmt
+            .query_row(params![url, secret, events], |r| r.get(0))
+            .map_err(|e| format!("insert failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(Webhook {
+            id,
+            url: url.to_string(),
+            secret,
+            events: events.to_string(),
+            enabled: true,
+        })
+    }
+
+    /// Remove a webhook together with its delivery log.
+    pub fn delete_webhook(&self, id: i64) -> Result<bool, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        conn_guard
+            .execute(
+                "DELETE FROM `webhook_delivery` WHERE `webhook_id` = ?1",
+                [id],
+            )
+            .map_err(|e| format!("delete failed: {:?}", e))?;
+        let res = conn_guard
+            .execute("DELETE FROM `webhook` WHERE `id` = ?1", [id])
+            .map_err(|e| format!("delete failed: {:?}", e))?;
+        drop(conn_guard);
+        Ok(res > 0)
+    }
+
+    /// Delivery log, newest first.
+    pub fn query_webhook_deliveries(
+        &self,
+        webhook_id: Option<i64>,
+        limit: u32,
+    ) -> Result<Vec<WebhookDelivery>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT `id`, `webhook_id`, `event`, `status`, `attempts`, `last_status`, `last_error`,
+                    `created_at`, `next_attempt_at`, `delivered_at`
+                FROM `webhook_delivery`
+                WHERE (?1 IS NULL OR `webhook_id` = ?1)
+                ORDER BY `id` DESC LIMIT ?2",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map(params![webhook_id, limit], |row| {
+                let status: String = row.get(3)?;
+                let next_attempt_at: Option<DateTime<Utc>> = row.get(8)?;
+                Ok(WebhookDelivery {
+                    id: row.get(0)?,
+                    webhook_id: row.get(1)?,
+                    event: row.get(2)?,
+                    attempts: row.get(4)?,
+                    last_status: row.get(5)?,
+                    last_error: row.get(6)?,
+                    created_at: local_time(row.get(7)?).unwrap_or_default(),
+                    next_attempt_at: local_time(next_attempt_at.filter(|_| status == "pending")),
+                    delivered_at: local_time(row.get(9)?),
+                    status,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(rows)
+    }
+
+    fn due_webhook_deliveries(&self, limit: u32) -> Result<Vec<PendingDelivery>, String> {
+        let conn_guard = self.conn.lock().unwrap();
+        let mut stmt = conn_guard
+            .prepare_cached(
+                "SELECT D.`id`, W.`url`, W.`secret`, D.`event`, D.`payload`, D.`attempts`
+                FROM `webhook_delivery` D JOIN `webhook` W ON W.`id` = D.`webhook_id`
+                WHERE D.`status` = 'pending' AND D.`next_attempt_at` <= ?1 AND W.`enabled` = 1
+                ORDER BY D.`id` ASC LIMIT ?2",
+            )
+            .map_err(|e| format!("prepare failed: {:?}", e))?;
+        let rows = stmt
+            .query_map(params![Utc::now().naive_utc(), limit], |row| {
+                Ok(PendingDelivery {
+                    id: row.get(0)?,
+                    url: row.get(1)?,
+                    secret: row.get(2)?,
+                    event: row.get(3)?,
+                    payload: row.get(4)?,
+                    attempts: row.get(5)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("query failed: {:?}", e))?;
+        drop(stmt);
+        drop(conn_guard);
+        Ok(rows)
+    }
+
+    /// Record the outcome of one attempt and schedule the next one on failure.
+    fn record_webhook_attempt(
+        &self,
+        delivery: &PendingDelivery,
+        http_status: Option<u16>,
+        error: Option<String>,
+    ) {
+        let attempts = delivery.attempts + 1;
+        let now = Utc::now();
+        let (status, next_attempt_at, delivered_at) = if error.is_none() {
+            ("delivered", now, Some(now.naive_utc()))
+        } else if attempts >= MAX_ATTEMPTS {
+            ("failed", now, None)
+        } else {
+            ("pending", now + retry_delay(attempts), None)
+        };
+        let conn_guard = self.conn.lock().unwrap();
+        let res = conn_guard
+            .prepare_cached(
+                "UPDATE `webhook_delivery` SET `status` = ?1, `attempts` = ?2, `next_attempt_at` = ?3,
+                    `last_status` = ?4, `last_error` = ?5, `delivered_at` = ?6
+                WHERE `id` = ?7",
+            )
+            .and_then(|mut stmt| {
+                stmt.execute(params![
+                    status,
+                    attempts,
+                    next_attempt_at.naive_utc(),
+                    http_status,
+                    error,
+                    delivered_at,
+                    delivery.id
+                ])
+            });
+        drop(conn_guard);
+        if let Err(e) = res {
+            tracing::error!("Failed to record webhook delivery {}: {:?}", delivery.id, e);
+        }
+    }
+
+    async fn deliver_due_webhooks(&self, client: &reqwest::Client) {
+        let due = match self.due_webhook_deliveries(50) {
+            Ok(due) => due,
+            Err(e) => {
+                tracing::error!("Failed to load webhook deliveries: {}", e);
+                return;
+            }
+        };
+        for delivery in due {
+            let res = client
+                .post(&delivery.url)
+                .header("Content-Type", "application/json")
+                .header("User-Agent", "call-cal-bot")
+                .header("X-Webhook-Event", &delivery.event)
+                .header("X-Webhook-Delivery", delivery.id.to_string())
+                .header(
+                    "X-Signature-256",
+                    format!(
+                        "sha256={}",
+                        sign_payload(&delivery.secret, &delivery.payload)
+                    ),
+                )
+                .body(delivery.payload.clone())
+                .send()
+                .await;
+            let (http_status, error) = match res {
+                Ok(resp) if resp.status().is_success() => (Some(resp.status().as_u16()), None),
+                Ok(resp) => (
+                    Some(resp.status().as_u16()),
+                    Some(format!("HTTP {}", resp.status())),
+                ),
+                Err(e) => (None, Some(e.to_string())),
+            };
+            if let Some(e) = &error {
+                tracing::warn!(
+                    "Webhook delivery {} to {} failed: {}",
+                    delivery.id,
+                    delivery.url,
+                    e
+                );
+            }
+            self.record_webhook_attempt(&delivery, http_status, error);
+        }
+    }
+
+    /// Send queued webhook deliveries: right after new events are published,
+    /// and periodically for retries, until shutdown.
+    pub async fn run_webhook_worker(self, mut shutdown: ShutdownSignal) {
+        let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+            Ok(c) => c,
+            Err(e) => {
+                tracing::error!("Failed to build webhook HTTP client: {:?}", e);
+                return;
+            }
+        };
+        let mut events = self.subscribe();
+        let mut ticker = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                // a delivery round that has started is finished first
+                _ = shutdown.recv() => return,
+                _ = ticker.tick() => {}
+                // lagging only means several events arrived at once; they are all in the queue
+                res = events.recv() => {
+                    if let Err(tokio::sync::broadcast::error::RecvError::Closed) = res {
+                        return;
+                    }
+                }
+            }
+            self.deliver_due_webhooks(&client).await;
+        }
+    }
+}
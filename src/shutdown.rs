@@ -0,0 +1,60 @@
+//! Coordinated shutdown: long-running tasks hold a [`ShutdownSignal`] and
+//! wind down once `main` triggers it.
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// Resolves once shutdown has been requested. Cheap to clone.
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub async fn recv(&mut self) {
+        // an error means the sender is gone, which is a shutdown as well
+        let _ = self.0.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Owned version of [`recv`](Self::recv), for APIs that take a future by value.
+    pub async fn recv_owned(mut self) {
+        self.recv().await
+    }
+}
+
+pub fn channel() -> (watch::Sender<bool>, ShutdownSignal) {
+    let (tx, rx) = watch::channel(false);
+    (tx, ShutdownSignal(rx))
+}
+
+/// How long in-flight work may take after shutdown was requested.
+/// `SHUTDOWN_TIMEOUT_SECS`, 15 seconds by default.
+pub fn timeout() -> Duration {
+    let secs = std::env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(15);
+    Duration::from_secs(secs)
+}
+
+/// Wait for SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("install Ctrl-C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("Received SIGINT"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
@@ -110,7 +110,7 @@ async function loadRecords(){
   }
 }
 
-// 咕 button logic: visible only on today and when either array is non-empty
+// 咕 button logic: visible only on today and when any tier has members
 async function checkAndShowGuButton(){
   const guBtn = document.getElementById('gu');
   const today = getCheckpointDateFor(new Date());
@@ -118,7 +118,7 @@ async function checkAndShowGuButton(){
   if(!isToday){ guBtn.style.display = 'none'; return; }
   try{
     const res = await API.call('/daka/gu');
-    const hasAny = (Array.isArray(res.missed_10) && res.missed_10.length>0) || (Array.isArray(res.warning_7) && res.warning_7.length>0);
+    const hasAny = Array.isArray(res.tiers) && res.tiers.length>0;
     if(hasAny){ guBtn.style.display = ''; } else { guBtn.style.display = 'none'; }
     // store last fetched results for modal
     guBtn._last = res;
@@ -127,26 +127,39 @@ async function checkAndShowGuButton(){
 
 function showGuModal(){
   const guBtn = document.getElementById('gu');
-  const res = guBtn._last || { missed_10: [], warning_7: [] };
-  const missList = document.getElementById('gu-missed-list');
-  const warnList = document.getElementById('gu-warning-list');
-  missList.innerHTML = '';
-  warnList.innerHTML = '';
-  const missed = (res.missed_10 || []);
-  const warning = (res.warning_7 || []);
-  if(missed.length>0){
-    document.getElementById('gu-missed').style.display = '';
-    missed.forEach(n => { const li = document.createElement('li'); li.textContent = n; missList.appendChild(li); });
-  } else { document.getElementById('gu-missed').style.display = 'none'; }
-  if(warning.length>0){
-    document.getElementById('gu-warning').style.display = '';
-    warning.forEach(n => { const li = document.createElement('li'); li.textContent = n; warnList.appendChild(li); });
-  } else { document.getElementById('gu-warning').style.display = 'none'; }
+  const res = guBtn._last || { tiers: [] };
+  const content = document.getElementById('gu-content');
+  content.innerHTML = '';
+  (res.tiers || []).forEach(tier => {
+    const h = document.createElement('h4');
+    h.textContent = `${tier.emoji} ${tier.label}`;
+    const ul = document.createElement('ul');
+    tier.members.forEach(m => {
+      const li = document.createElement('li');
+      li.textContent = m.last_checkin ? `${m.name}（${m.inactive_days}天，上次 ${m.last_checkin}）` : `${m.name}（从未打卡）`;
+      ul.appendChild(li);
+    });
+    content.appendChild(h);
+    content.appendChild(ul);
+  });
   document.getElementById('gu-modal').classList.remove('hidden');
 }
 
 function hideGuModal(){ document.getElementById('gu-modal').classList.add('hidden'); }
 
+// calendar subscription: show the personal feed URL, cancel offers to regenerate it
+async function showCalendarLink(){
+  try{
+    let res = await API.call('/cal/token');
+    if(!res || !res.url){ if(res && res.error){ alert(res.error); } return; }
+    const kept = prompt('日历订阅链接（复制到手机日历订阅）\n点“取消”可重新生成链接', location.origin + res.url);
+    if(kept === null && confirm('重新生成链接？旧链接将失效')){
+      res = await API.call('/cal/token', { method:'POST' });
+      if(res && res.url){ prompt('新的日历订阅链接', location.origin + res.url); }
+    }
+  }catch(e){ if(e.unauth){ showAuth(); } else { console.error(e); alert('calendar link failed'); } }
+}
+
 async function doAction(type){
   // type: 'daka' or 'undo'
   try{
@@ -173,6 +186,7 @@ async function doLogin(){
     if(res && res.need_reset){ showReset(); hideAuth(); return; }
     hideAuth();
     await loadRecords();
+    subscribeEvents();
   }catch(e){
     if(e.unauth){
       // if the thrown object includes a message from server, show it
@@ -197,11 +211,26 @@ async function doSetPassword(){
   }catch(e){ if(e.unauth){ showAuth(); } else { alert('set password failed'); } }
 }
 
+// live updates: re-fetch today's records whenever anyone checks in or cancels
+let events = null;
+function subscribeEvents(){
+  if(events || !window.EventSource) return;
+  events = new EventSource('/daka/events');
+  const refresh = ()=>{
+    const today = getCheckpointDateFor(new Date());
+    if(formatDate(today) === formatDate(state.date)){ loadRecords(); }
+  };
+  ['daka','cancel','restore','lagged'].forEach(t => events.addEventListener(t, refresh));
+  // the browser reconnects on its own; give up only when the stream was rejected (e.g. not logged in)
+  events.onerror = ()=>{ if(events.readyState === EventSource.CLOSED){ events = null; } };
+}
+
 // UI wiring
 window.addEventListener('load', ()=>{
   document.getElementById('prev').addEventListener('click', ()=>{ state.date.setDate(state.date.getDate()-1); loadRecords(); });
   document.getElementById('next').addEventListener('click', ()=>{ state.date.setDate(state.date.getDate()+1); loadRecords(); });
   document.getElementById('gu').addEventListener('click', showGuModal);
+  document.getElementById('cal').addEventListener('click', showCalendarLink);
   document.getElementById('daka').addEventListener('click', ()=>doAction('daka'));
   document.getElementById('undo').addEventListener('click', ()=>doAction('undo'));
   document.getElementById('login').addEventListener('click', doLogin);
@@ -209,4 +238,5 @@ window.addEventListener('load', ()=>{
   document.getElementById('gu-close').addEventListener('click', hideGuModal);
   loadRecords();
   checkAndShowGuButton();
+  subscribeEvents();
 });